@@ -0,0 +1,114 @@
+// Floods proxychain with concurrent SOCKS5 connections to a direct target,
+// once with --max-connections (which pre-sizes the connection tables) and
+// once without, printing how long it took until every tunnel was up.
+//
+//     cargo bench --bench accept
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECTIONS: usize = 4000;
+const ROUNDS: usize = 5;
+
+// Accepts and holds every connection so the tunnels stay open.
+fn origin() -> io::Result<(u16, Arc<Mutex<Vec<TcpStream>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let held = Arc::new(Mutex::new(Vec::new()));
+    let streams = held.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            streams.lock().unwrap().push(stream);
+        }
+    });
+    Ok((port, held))
+}
+
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn spawn_proxychain(listen: u16, max_connections: Option<usize>) -> io::Result<Child> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_proxychain"));
+    command
+        .arg("-i")
+        .arg(format!("socks5://127.0.0.1:{}", listen))
+        .arg("-o")
+        .arg("http://127.0.0.1:9")
+        .arg("--direct-loopback")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if let Some(max) = max_connections {
+        command.arg("--max-connections").arg(max.to_string());
+    }
+    let child = command.spawn()?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(("127.0.0.1", listen)).is_err() {
+        if Instant::now() > deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "not listening"));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(child)
+}
+
+// Goes through each step of the handshake on every connection before the
+// next step, so proxychain sees the whole flood at once.
+fn flood(listen: u16, target: u16) -> io::Result<Duration> {
+    let port = target.to_be_bytes();
+    let start = Instant::now();
+    let mut streams = Vec::with_capacity(CONNECTIONS);
+    for _ in 0..CONNECTIONS {
+        let mut stream = TcpStream::connect(("127.0.0.1", listen))?;
+        stream.write_all(&[0x05, 0x01, 0x00])?;
+        streams.push(stream);
+    }
+    let mut reply = [0; 10];
+    for stream in &mut streams {
+        stream.read_exact(&mut reply[..2])?;
+        stream.write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]])?;
+    }
+    for stream in &mut streams {
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0x00 {
+            return Err(io::Error::other("tunnel refused"));
+        }
+    }
+    Ok(start.elapsed())
+}
+
+// Each round gets a fresh process, the tables never shrink once grown.
+fn run(max_connections: Option<usize>) -> io::Result<()> {
+    let (target, held) = origin()?;
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let listen = free_port()?;
+        let mut child = spawn_proxychain(listen, max_connections)?;
+        best = best.min(flood(listen, target)?);
+        child.kill()?;
+        child.wait()?;
+        held.lock().unwrap().clear();
+    }
+
+    println!(
+        "{:<10} {} tunnels up in {:.1}ms (best of {})",
+        match max_connections {
+            Some(_) => "presized",
+            None => "growing",
+        },
+        CONNECTIONS,
+        best.as_secs_f64() * 1000.0,
+        ROUNDS
+    );
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    run(None)?;
+    run(Some(CONNECTIONS * 2))
+}
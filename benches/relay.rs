@@ -0,0 +1,164 @@
+// Relays a sustained, paced download through proxychain and a stub HTTP
+// proxy, printing throughput and the CPU time consumed by proxychain. The
+// origin is paced so the relay stays busy without saturating the sockets,
+// `--unpaced` sends as fast as the relay takes instead.
+//
+//     cargo bench --bench relay [-- --unpaced]
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TOTAL: usize = 128 * 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+const PACE: Duration = Duration::from_millis(1);
+
+fn origin(pace: Option<Duration>) -> io::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let chunk = vec![0x5a; CHUNK];
+                let mut sent = 0;
+                while sent < TOTAL {
+                    if stream.write_all(&chunk).is_err() {
+                        return;
+                    }
+                    sent += CHUNK;
+                    if let Some(pace) = pace {
+                        thread::sleep(pace);
+                    }
+                }
+            });
+        }
+    });
+    Ok(port)
+}
+
+fn http_proxy() -> io::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            thread::spawn(move || tunnel(stream));
+        }
+    });
+    Ok(port)
+}
+
+fn tunnel(mut client: TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut byte = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if client.read(&mut byte)? == 0 {
+            return Ok(());
+        }
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let authority = request.split(' ').nth(1).unwrap_or_default().to_string();
+    let mut origin = TcpStream::connect(authority)?;
+    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+
+    let mut upload = client.try_clone()?;
+    let mut upload_origin = origin.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut upload, &mut upload_origin);
+        let _ = upload_origin.shutdown(Shutdown::Write);
+    });
+    io::copy(&mut origin, &mut client)?;
+    client.shutdown(Shutdown::Write)
+}
+
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn spawn_proxychain(listen: u16, upstream: u16) -> io::Result<Child> {
+    Command::new(env!("CARGO_BIN_EXE_proxychain"))
+        .arg("-i")
+        .arg(format!("socks5://127.0.0.1:{}", listen))
+        .arg("-o")
+        .arg(format!("http://127.0.0.1:{}", upstream))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+fn socks_connect(listen: u16, target: u16) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", listen)) {
+            Ok(s) => break s,
+            Err(err) if Instant::now() > deadline => return Err(err),
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    };
+    let mut reply = [0; 10];
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    stream.read_exact(&mut reply[..2])?;
+    let port = target.to_be_bytes();
+    stream.write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]])?;
+    stream.read_exact(&mut reply)?;
+    Ok(stream)
+}
+
+// utime + stime of a process in seconds, Linux only.
+fn cpu_time(pid: u32) -> Option<f64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) / 100.0)
+}
+
+fn main() -> io::Result<()> {
+    let paced = !std::env::args().any(|arg| arg == "--unpaced");
+    let origin = origin(Some(PACE).filter(|_| paced))?;
+    let upstream = http_proxy()?;
+    let listen = free_port()?;
+    let mut child = spawn_proxychain(listen, upstream)?;
+
+    let mut stream = socks_connect(listen, origin)?;
+    let cpu_before = cpu_time(child.id());
+    let start = Instant::now();
+    let mut buf = vec![0; CHUNK];
+    let mut received = 0;
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received += n,
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let cpu = match (cpu_before, cpu_time(child.id())) {
+        (Some(before), Some(after)) => format!("{:.2}s", after - before),
+        _ => String::from("n/a"),
+    };
+
+    child.kill()?;
+    child.wait()?;
+
+    let mib = received as f64 / (1024.0 * 1024.0);
+    println!(
+        "relayed {:.1} of {} MiB in {:.2}s ({:.1} MiB/s), proxychain cpu {}",
+        mib,
+        TOTAL / (1024 * 1024),
+        elapsed,
+        mib / elapsed,
+        cpu
+    );
+    Ok(())
+}
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proxychain::socks::parse::{
+    parse_auth_request, parse_client_hello_sni, parse_connection_request, parse_http_connect,
+    parse_method_request, parse_socks4_request,
+};
+
+// Any input has to come back as a request or an error, never a panic.
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_method_request(data);
+    let _ = parse_connection_request(data, 255);
+    let _ = parse_auth_request(data);
+    let _ = parse_socks4_request(data, 255);
+    let _ = parse_http_connect(data, 255);
+    let _ = parse_client_hello_sni(data);
+});
@@ -0,0 +1,159 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Text,
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(AccessLogFormat::Text),
+            "json" => Some(AccessLogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// When each phase of a connection started and ended.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Phases {
+    pub resolve: Option<Instant>,
+    pub resolved: Option<Instant>,
+    pub connect: Option<Instant>,
+    pub connected: Option<Instant>,
+    pub answered: Option<Instant>,
+    pub first_byte: Option<Instant>,
+}
+
+impl Phases {
+    pub fn timings(&self) -> PhaseTimings {
+        let span = |from: Option<Instant>, to: Option<Instant>| match (from, to) {
+            (Some(from), Some(to)) => Some(to.saturating_duration_since(from).as_secs_f64()),
+            _ => None,
+        };
+        PhaseTimings {
+            dns: span(self.resolve, self.resolved),
+            connect: span(self.connect, self.connected),
+            handshake: span(self.connected, self.answered),
+            first_byte: span(self.answered, self.first_byte),
+        }
+    }
+}
+
+// Seconds spent in each phase, absent for phases the connection never got
+// through: resolving the target, connecting the upstream, its handshake and
+// waiting for the first byte of the origin.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PhaseTimings {
+    pub dns: Option<f64>,
+    pub connect: Option<f64>,
+    pub handshake: Option<f64>,
+    pub first_byte: Option<f64>,
+}
+
+impl PhaseTimings {
+    fn text(&self) -> String {
+        [
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("handshake", self.handshake),
+            ("first byte", self.first_byte),
+        ]
+        .iter()
+        .filter_map(|(name, secs)| secs.map(|secs| format!(", {} {:.3}s", name, secs)))
+        .collect()
+    }
+}
+
+// One completed connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: String,
+    pub id: usize,
+    pub client: String,
+    pub domain: String,
+    pub port: u16,
+    pub upstream: Option<String>,
+    // Status line of a refused CONNECT.
+    pub upstream_status: Option<String>,
+    pub status: &'static str,
+    // Why it ended, see CloseReason.
+    pub reason: &'static str,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration: f64,
+    pub timings: PhaseTimings,
+}
+
+impl AccessLogEntry {
+    pub fn now() -> String {
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "[#{}] closed: client {}, target {}:{}, {} bytes in, {} bytes out, {:.3}s, {}{}, {}{}",
+            self.id,
+            self.client,
+            self.domain,
+            self.port,
+            self.bytes_in,
+            self.bytes_out,
+            self.duration,
+            self.status,
+            match self.upstream_status.as_deref() {
+                Some(line) => format!(" ({})", line),
+                None => String::new(),
+            },
+            self.reason,
+            self.timings.text()
+        )
+    }
+}
+
+// Writes an entry per connection to the given file, or to the regular log
+// when there is none.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Option<File>,
+}
+
+impl AccessLog {
+    pub fn open(path: Option<&Path>, format: AccessLogFormat) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Self { format, file })
+    }
+
+    pub fn record(&mut self, entry: &AccessLogEntry) {
+        let line = match self.format {
+            AccessLogFormat::Text => entry.text(),
+            AccessLogFormat::Json => match serde_json::to_string(entry) {
+                Ok(line) => line,
+                Err(err) => {
+                    error!("[#{}] Failed to serialize access log: {}", entry.id, err);
+                    return;
+                }
+            },
+        };
+        match self.file.as_mut() {
+            Some(file) => {
+                if let Err(err) = writeln!(file, "{}", line) {
+                    error!("[#{}] Failed to write access log: {}", entry.id, err);
+                }
+            }
+            None => info!("{}", line),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::datatype::Target;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.find('/') {
+            Some(i) => (&value[..i], Some(&value[i + 1..])),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok()?,
+            None => max,
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) if self.addr.is_ipv4() => match v6.to_ipv4() {
+                Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v4),
+                _ => return false,
+            },
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => Cidr::matches(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                Cidr::matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+
+    fn matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+        if prefix == 0 {
+            return true;
+        }
+        let shift = bits - prefix;
+        (net >> shift) == (ip >> shift)
+    }
+}
+
+// Deny rules always win; when no allow rule is given every other peer is accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn allow(&mut self, cidr: Cidr) {
+        self.allow.push(cidr);
+    }
+
+    pub fn deny(&mut self, cidr: Cidr) {
+        self.deny.push(cidr);
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (start, end) = match value.find('-') {
+            Some(i) => (value[..i].parse().ok()?, value[i + 1..].parse().ok()?),
+            None => {
+                let port = value.parse().ok()?;
+                (port, port)
+            }
+        };
+        if start > end {
+            return None;
+        }
+        Some(Self { start, end })
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+// Destinations a client is not allowed to reach, checked once the target is resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DestinationRules {
+    domains: Vec<String>,
+    ports: Vec<PortRange>,
+    cidrs: Vec<Cidr>,
+    internal: bool,
+}
+
+impl DestinationRules {
+    pub fn block_domain(&mut self, suffix: &str) {
+        let suffix = suffix.trim_matches('.').to_ascii_lowercase();
+        if !suffix.is_empty() {
+            self.domains.push(suffix);
+        }
+    }
+
+    pub fn block_port(&mut self, range: PortRange) {
+        self.ports.push(range);
+    }
+
+    pub fn block_cidr(&mut self, cidr: Cidr) {
+        self.cidrs.push(cidr);
+    }
+
+    // Refuses loopback, link-local and private targets. Domains get
+    // resolved here first so one pointing inside is caught as well.
+    pub fn block_internal(&mut self) {
+        self.internal = true;
+    }
+
+    // Whether any rule needs the resolved address of a target.
+    pub fn has_cidrs(&self) -> bool {
+        !self.cidrs.is_empty() || self.internal
+    }
+
+    pub fn blocks(&self, target: &Target) -> bool {
+        self.blocking(target).is_some()
+    }
+
+    // The rule blocking a target, for explaining the decision.
+    pub fn blocking(&self, target: &Target) -> Option<String> {
+        if self.internal && target.is_internal() {
+            return Some(String::from("--block-internal"));
+        }
+        if let Some(suffix) = matching_domain(&self.domains, &target.domain) {
+            return Some(format!("--block-domain {}", suffix));
+        }
+        if let Some(range) = self.ports.iter().find(|range| range.contains(target.port)) {
+            return Some(format!("--block-port {}", range));
+        }
+        self.cidrs
+            .iter()
+            .find(|cidr| cidr.contains(target.addr.ip()))
+            .map(|cidr| format!("--block-cidr {}", cidr))
+    }
+}
+
+// Destinations reached without going through the upstream proxy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectRules {
+    domains: Vec<String>,
+    cidrs: Vec<Cidr>,
+}
+
+impl DirectRules {
+    // Takes either a CIDR or a domain suffix.
+    pub fn add(&mut self, value: &str) {
+        match Cidr::parse(value) {
+            Some(cidr) => self.cidrs.push(cidr),
+            None => {
+                let suffix = value.trim_matches('.').to_ascii_lowercase();
+                if !suffix.is_empty() {
+                    self.domains.push(suffix);
+                }
+            }
+        }
+    }
+
+    pub fn add_loopback(&mut self) {
+        self.add("127.0.0.0/8");
+        self.add("::1/128");
+        self.add("localhost");
+    }
+
+    pub fn matches(&self, target: &Target) -> bool {
+        self.matching(target).is_some()
+    }
+
+    // The domain suffix or CIDR a target matched, for explaining the decision.
+    pub fn matching(&self, target: &Target) -> Option<String> {
+        if let Some(suffix) = matching_domain(&self.domains, &target.domain) {
+            return Some(format!("domain {}", suffix));
+        }
+        self.cidrs
+            .iter()
+            .find(|cidr| cidr.contains(target.addr.ip()))
+            .map(|cidr| format!("CIDR {}", cidr))
+    }
+}
+
+// First suffix the domain is equal to or a subdomain of.
+fn matching_domain<'a>(suffixes: &'a [String], domain: &str) -> Option<&'a String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    suffixes.iter().find(|suffix| {
+        domain == **suffix
+            || (domain.ends_with(suffix.as_str())
+                && domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.')
+    })
+}
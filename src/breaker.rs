@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use fnv::FnvHashMap;
+use log::{info, warn};
+
+use crate::datatype::Target;
+use crate::proxy::Proxy;
+use crate::selector::UpstreamSelector;
+
+// When an upstream stops getting traffic: `failures` failed handshakes
+// within `window` open its circuit for `cooldown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerConfig {
+    pub failures: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    // Skipped until the cooldown is over.
+    Open(Instant),
+    // A single probe connection was let through at this time.
+    HalfOpen(Instant),
+}
+
+// Health of one upstream, driven by the outcome of its handshakes.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: BreakerConfig,
+    state: CircuitState,
+    failures: VecDeque<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: BreakerConfig) -> Self {
+        Self {
+            name: String::from(name),
+            config,
+            state: CircuitState::Closed,
+            failures: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    // Whether a connection may go through now. Past the cooldown this lets
+    // one probe through, another one only if it never reported back.
+    pub fn allows(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open(until) | CircuitState::HalfOpen(until) if now < until => false,
+            _ => {
+                info!("Probing upstream {} after its cooldown", self.name);
+                self.state = CircuitState::HalfOpen(now + self.config.cooldown);
+                true
+            }
+        }
+    }
+
+    pub fn success(&mut self) {
+        if self.state != CircuitState::Closed {
+            info!("Upstream {} recovered, closing its circuit", self.name);
+            self.state = CircuitState::Closed;
+        }
+        self.failures.clear();
+    }
+
+    pub fn failure(&mut self, now: Instant) {
+        match self.state {
+            // Connections handed out before it opened
+            CircuitState::Open(_) => return,
+            CircuitState::HalfOpen(_) => {
+                warn!(
+                    "Upstream {} still failing, skipping it for {}s",
+                    self.name,
+                    self.config.cooldown.as_secs()
+                );
+                self.state = CircuitState::Open(now + self.config.cooldown);
+                return;
+            }
+            CircuitState::Closed => {}
+        }
+        while self
+            .failures
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > self.config.window)
+        {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(now);
+        if self.failures.len() >= self.config.failures {
+            warn!(
+                "Upstream {} failed {} times within {}s, skipping it for {}s",
+                self.name,
+                self.failures.len(),
+                self.config.window.as_secs(),
+                self.config.cooldown.as_secs()
+            );
+            self.failures.clear();
+            self.state = CircuitState::Open(now + self.config.cooldown);
+        }
+    }
+}
+
+// Wraps a selector so upstreams with an open circuit are skipped, falling
+// back to the other proxies of the selector in order. Nothing is selected
+// when every circuit is open, the client fails fast instead.
+pub struct CircuitBreaking {
+    inner: Box<dyn UpstreamSelector>,
+    config: BreakerConfig,
+    circuits: RefCell<FnvHashMap<String, CircuitBreaker>>,
+}
+
+impl CircuitBreaking {
+    pub fn new(inner: Box<dyn UpstreamSelector>, config: BreakerConfig) -> Self {
+        Self {
+            inner,
+            config,
+            circuits: RefCell::new(FnvHashMap::default()),
+        }
+    }
+
+    pub fn state(&self, proxy: &Proxy) -> CircuitState {
+        self.circuits
+            .borrow()
+            .get(proxy.url())
+            .map_or(CircuitState::Closed, |circuit| circuit.state())
+    }
+
+    fn with_circuit<R>(&self, proxy: &Proxy, f: impl FnOnce(&mut CircuitBreaker) -> R) -> R {
+        let mut circuits = self.circuits.borrow_mut();
+        let circuit = circuits
+            .entry(String::from(proxy.url()))
+            .or_insert_with(|| {
+                CircuitBreaker::new(&format!("{}:{}", proxy.host, proxy.port), self.config)
+            });
+        f(circuit)
+    }
+
+    fn allows(&self, proxy: &Proxy) -> bool {
+        let now = Instant::now();
+        self.with_circuit(proxy, |circuit| circuit.allows(now))
+    }
+}
+
+impl UpstreamSelector for CircuitBreaking {
+    fn select(&self, target: &Target, peer: SocketAddr) -> Option<&Proxy> {
+        let picked = self.inner.select(target, peer)?;
+        if self.allows(picked) {
+            return Some(picked);
+        }
+        self.inner
+            .proxies()
+            .into_iter()
+            .find(|proxy| proxy.url() != picked.url() && self.allows(proxy))
+    }
+
+    // Open circuits are checked without letting a probe through.
+    fn explain(&self, target: &Target, peer: SocketAddr) -> Option<(&Proxy, String)> {
+        let now = Instant::now();
+        let open = |proxy: &Proxy| match self.state(proxy) {
+            CircuitState::Open(until) | CircuitState::HalfOpen(until) => now < until,
+            CircuitState::Closed => false,
+        };
+        let (picked, reason) = self.inner.explain(target, peer)?;
+        if !open(picked) {
+            return Some((picked, reason));
+        }
+        self.inner
+            .proxies()
+            .into_iter()
+            .find(|proxy| proxy.url() != picked.url() && !open(proxy))
+            .map(|proxy| {
+                (
+                    proxy,
+                    format!(
+                        "{}, but the circuit of {}:{} is open",
+                        reason, picked.host, picked.port
+                    ),
+                )
+            })
+    }
+
+    fn proxies(&self) -> Vec<&Proxy> {
+        self.inner.proxies()
+    }
+
+    fn report(&self, proxy: &Proxy, ok: bool) {
+        let now = Instant::now();
+        self.with_circuit(proxy, |circuit| {
+            if ok {
+                circuit.success()
+            } else {
+                circuit.failure(now)
+            }
+        });
+        self.inner.report(proxy, ok);
+    }
+
+    // Every circuit of the rebuilt selector starts closed.
+    fn with_proxies(&self, proxies: Vec<Proxy>) -> Option<Box<dyn UpstreamSelector>> {
+        let inner = self.inner.with_proxies(proxies)?;
+        Some(Box::new(CircuitBreaking::new(inner, self.config)))
+    }
+}
@@ -0,0 +1,34 @@
+use bytes::BytesMut;
+use std::cmp;
+use std::io::{self, Read, Write};
+
+// Reads at most `limit` bytes into the spare capacity of `buf`, growing it
+// when full. The spare room is zeroed first, so the reader only ever sees
+// initialized memory, and cut back to what was read.
+pub fn read_buf<R: Read>(reader: &mut R, buf: &mut BytesMut, limit: usize) -> io::Result<usize> {
+    if buf.capacity() == buf.len() {
+        buf.reserve(1024);
+    }
+    let start = buf.len();
+    let len = cmp::min(buf.capacity() - start, limit);
+    buf.resize(start + len, 0);
+    let result = reader.read(&mut buf[start..]);
+    buf.truncate(start + *result.as_ref().unwrap_or(&0));
+    result
+}
+
+// Writes as much of `buf` as the socket takes right now, stopping at
+// WouldBlock. None when the write got interrupted.
+pub fn write_some<W: Write>(writer: &mut W, buf: &[u8]) -> io::Result<Option<usize>> {
+    let mut written = 0;
+    while written < buf.len() {
+        match writer.write(&buf[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => return Ok(None),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Some(written))
+}
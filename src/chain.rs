@@ -0,0 +1,192 @@
+use log::debug;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use bytes::BytesMut;
+use mio::event::Event;
+use mio::{Registry, Token};
+
+use crate::datatype::Target;
+use crate::error::Step;
+use crate::proxy::Proxy;
+use crate::upstream::{Client, UpstreamClient};
+
+// Tunnels through several upstream proxies in order, of any protocol. Only
+// the first hop connects, each established tunnel is handed to the next
+// hop which runs its handshake through it. Everything else goes to the
+// hop currently holding the socket.
+pub struct ChainClient {
+    id: usize,
+    hops: Vec<Client>,
+    current: usize,
+}
+
+impl ChainClient {
+    // Each hop has to target the proxy of the hop after it, the last one
+    // the destination.
+    pub fn new(id: usize, hops: Vec<Client>) -> Self {
+        assert!(!hops.is_empty(), "a chain needs at least one hop");
+        Self {
+            id,
+            hops,
+            current: 0,
+        }
+    }
+
+    // Index of the hop handshaking or relaying right now.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    fn hop(&self) -> &Client {
+        &self.hops[self.current]
+    }
+
+    fn hop_mut(&mut self) -> &mut Client {
+        &mut self.hops[self.current]
+    }
+
+    // Whether the current hop answered its tunnel request successfully.
+    fn tunneled(&self) -> bool {
+        self.hop().status() == Some(200) || self.hop().reply() == Some(0x00)
+    }
+}
+
+// Destination a hop is asked to tunnel to when it is the proxy of the next.
+pub fn hop_target(proxy: &Proxy) -> Target {
+    let mut target = Target::new();
+    target.domain = proxy.host.clone();
+    let ips: Vec<_> = proxy.addrs.iter().map(|addr| addr.ip()).collect();
+    target.set_candidates(&ips, proxy.port, None);
+    target
+}
+
+impl UpstreamClient for ChainClient {
+    fn token(&self) -> Option<Token> {
+        self.hop().token()
+    }
+
+    fn connect(&mut self, token: Token, registry: &Registry) -> io::Result<Step> {
+        self.hops[0].connect(token, registry)
+    }
+
+    fn check_connected(&mut self, registry: &Registry) -> io::Result<bool> {
+        self.hop_mut().check_connected(registry)
+    }
+
+    fn established(&self) -> bool {
+        false
+    }
+
+    // Intermediate answers stay hidden, the client only hears of the last
+    // hop or of the one that failed.
+    fn handle(&mut self, event: &Event, value: Option<&mut BytesMut>) -> io::Result<Step> {
+        let mut step = self.hop_mut().handle(event, value)?;
+        while self.current + 1 < self.hops.len() && self.tunneled() {
+            let token = self.hop().token();
+            let (stream, token) = match (self.hop_mut().take_stream(), token) {
+                (Some(stream), Some(token)) => (stream, token),
+                _ => break,
+            };
+            self.current += 1;
+            debug!(
+                "[#{}] Chain hop {} established, handshaking with hop {}",
+                self.id,
+                self.current,
+                self.current + 1
+            );
+            self.hop_mut().adopt(stream, token);
+            step = self.hop_mut().handle(event, None)?;
+        }
+        Ok(step)
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.hop().status()
+    }
+
+    fn status_line(&self) -> Option<&str> {
+        self.hop().status_line()
+    }
+
+    fn reply(&self) -> Option<u8> {
+        self.hop().reply()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.hop().local_addr()
+    }
+
+    fn last_error(&self) -> Option<io::ErrorKind> {
+        self.hops[0].last_error()
+    }
+
+    fn buffer(&self) -> &BytesMut {
+        self.hop().buffer()
+    }
+
+    fn size(&self) -> usize {
+        self.hop().size()
+    }
+
+    fn read_buffer_up_to(&mut self, limit: usize) -> io::Result<Step> {
+        self.hop_mut().read_buffer_up_to(limit)
+    }
+
+    fn write_buffer(&mut self) -> io::Result<Step> {
+        self.hop_mut().write_buffer()
+    }
+
+    fn pending(&self) -> usize {
+        self.hop().pending()
+    }
+
+    fn flush(&mut self) -> io::Result<Step> {
+        self.hop_mut().flush()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.hop_mut().shutdown_write()
+    }
+
+    fn swap_buffer(&mut self, buffer: &mut BytesMut) {
+        self.hop_mut().swap_buffer(buffer)
+    }
+
+    fn clear_buffer(&mut self) {
+        self.hop_mut().clear_buffer()
+    }
+
+    fn reset_buffer(&mut self) {
+        self.hop_mut().reset_buffer()
+    }
+
+    // Only the first hop reconnects on its own, later ones have no socket
+    // of their own to retry.
+    fn retry_at(&self) -> Option<Instant> {
+        match self.current {
+            0 => self.hops[0].retry_at(),
+            _ => None,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.hop().deadline()
+    }
+
+    fn retry(&mut self, registry: &Registry) -> io::Result<Step> {
+        match self.current {
+            0 => self.hops[0].retry(registry),
+            _ => Ok(Step::Yield),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.hop_mut().reregister(registry)
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        self.hop_mut().deregister(registry)
+    }
+}
@@ -0,0 +1,107 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, Token};
+
+use crate::config::Config;
+use crate::datatype::Target;
+use crate::error::Step;
+use crate::http::client::HttpClient;
+use crate::proxy::{Proxy, ProxyProtocol};
+use crate::socks::client::Socks5Client;
+use crate::upstream::UpstreamClient;
+
+const CHECK: Token = Token(0);
+
+// How an upstream answered the probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    // HTTP status of the CONNECT.
+    Status(u16),
+    // SOCKS5 REP of the CONNECT.
+    Reply(u8),
+    // Credentials were asked for and none were sent, or they were rejected.
+    Unauthorized,
+}
+
+// Connects to the proxy and asks it for a tunnel to its own address, the
+// way a connection through it would: a CONNECT for HTTP proxies, the
+// greeting and a CONNECT request for SOCKS5 ones.
+pub fn check(proxy: &Proxy, config: &Config, timeout: Duration) -> io::Result<Answer> {
+    let mut target = Target::new();
+    target.set_candidates(&[proxy.addr.ip()], proxy.port, None);
+    target.domain = proxy.host.clone();
+
+    match proxy.protocol() {
+        ProxyProtocol::SOCKS5Proxy | ProxyProtocol::SOCKS5hProxy => {
+            let mut client = Socks5Client::new(
+                0,
+                proxy.clone(),
+                target,
+                config.buffer_size,
+                config.max_buffer,
+            );
+            client.set_outbound(config.outbound.clone());
+            probe(&mut client, timeout)?;
+            match client.reply {
+                Some(_) if client.unauthorized => Ok(Answer::Unauthorized),
+                Some(rep) => Ok(Answer::Reply(rep)),
+                None => Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+        _ => {
+            let mut client = HttpClient::new(
+                0,
+                proxy.clone(),
+                target,
+                config.buffer_size,
+                config.max_buffer,
+            );
+            client.set_outbound(config.outbound.clone());
+            client.set_headers(config.connect_headers.clone());
+            client.set_host_style(config.connect_host_style);
+            client.set_http_version(config.upstream_http_version);
+            probe(&mut client, timeout)?;
+            match client.status {
+                Some(407) => Ok(Answer::Unauthorized),
+                Some(status) => Ok(Answer::Status(status)),
+                None => Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+}
+
+// Drives the handshake until the upstream answered the tunnel request or
+// went away.
+fn probe(client: &mut dyn UpstreamClient, timeout: Duration) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(16);
+    let deadline = Instant::now() + timeout;
+
+    if client.connect(CHECK, poll.registry())? == Step::Close {
+        return Err(client.last_error().unwrap_or(io::ErrorKind::Other).into());
+    }
+
+    let mut connected = false;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        poll.poll(&mut events, Some(remaining))?;
+        for event in events.iter() {
+            if !connected {
+                if !event.is_writable() || !client.check_connected(poll.registry())? {
+                    continue;
+                }
+                connected = true;
+                client.handle(event, None)?;
+            } else if event.is_readable() {
+                let closed = client.handle(event, None)? == Step::Close;
+                if closed || client.status().is_some() || client.reply().is_some() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::accesslog::AccessLogFormat;
+use crate::acl::{AccessList, Cidr, DestinationRules, DirectRules, PortRange};
+use crate::breaker::BreakerConfig;
+use crate::datatype::{BndMode, IpFamily};
+use crate::dns::DnsProtocol;
+use crate::http::{HostStyle, HttpVersion};
+use crate::keepalive::Keepalive;
+use crate::outbound::Outbound;
+use crate::proxy::{Proxy, ProxyProtocol};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Protocol spoken to clients, SOCKS5 (along with SOCKS4) or HTTP.
+    pub inbound: ProxyProtocol,
+    pub subproxy: Vec<Proxy>,
+    // Goes through every subproxy in order instead of picking one of them.
+    pub chain: bool,
+    // Bytes per second a tunnel may relay, unlimited when None or 0.
+    pub rate_limit: Option<u64>,
+    pub buffer_size: usize,
+    pub max_buffer: usize,
+    pub acl: AccessList,
+    pub rules: DestinationRules,
+    pub direct: DirectRules,
+    pub prefer: Option<IpFamily>,
+    pub upstream_retries: u32,
+    pub upstream_retry_delay: Duration,
+    // Skips upstreams that keep failing, none when unset.
+    pub breaker: Option<BreakerConfig>,
+    pub dns: Option<SocketAddr>,
+    pub dns_protocol: DnsProtocol,
+    // Resolved domains kept around, none when unset.
+    pub dns_cache: Option<usize>,
+    pub outbound: Option<Outbound>,
+    pub keepalive: Option<Keepalive>,
+    // TCP_NODELAY on accepted connections and on upstream sockets.
+    pub inbound_nodelay: bool,
+    pub outbound_nodelay: bool,
+    pub connect_headers: Vec<(String, String)>,
+    pub connect_host_style: HostStyle,
+    pub upstream_http_version: HttpVersion,
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    // Username and password clients may authenticate with.
+    pub auth: Option<(String, String)>,
+    // Refuses clients that don't authenticate instead of letting them in.
+    pub require_auth: bool,
+    pub idle_timeout: Option<Duration>,
+    // Logs connection duration percentiles this often.
+    pub latency_report: Option<Duration>,
+    // Logs the throughput of every relay at debug level this often.
+    pub throughput_interval: Option<Duration>,
+    pub max_connections: Option<usize>,
+    // Connections a single source IP may hold at once.
+    pub max_conns_per_ip: Option<usize>,
+    // Events taken per poll by `serve`, grown while polls keep filling it.
+    pub event_capacity: usize,
+    pub send_proxy_protocol: bool,
+    // Forwards plain HTTP to port 80 as absolute-form requests instead of
+    // tunneling it through a CONNECT.
+    pub http_absolute_form: bool,
+    pub connect_timeout: Option<Duration>,
+    // Upstream CONNECT handshakes allowed in flight at once.
+    pub max_pending_upstream: Option<usize>,
+    // Longest hostname accepted in a request.
+    pub max_domain_len: usize,
+    // Bytes buffered for a handshake frame before the client is cut off.
+    pub max_request_size: usize,
+    // Closes tunnels whose TLS ClientHello names another host than the
+    // requested one, or that don't start with one at all.
+    pub enforce_sni: bool,
+    // Answers the non-standard RESOLVE command (0xF0).
+    pub enable_resolve: bool,
+    // Reported in BND.ADDR of successful replies, e.g. behind NAT.
+    pub advertise_addr: Option<SocketAddr>,
+    // What BND reports, zeros unless told otherwise.
+    pub bnd_mode: BndMode,
+    // Unix socket taking admin commands.
+    pub admin_socket: Option<PathBuf>,
+    // TCP address answering health checks of load balancers.
+    pub health_addr: Option<SocketAddr>,
+    // Switched to by `serve` once the listener is bound.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    // Rules and upstreams read on top of the flags, again on SIGHUP.
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            inbound: ProxyProtocol::SOCKS5Proxy,
+            subproxy: Vec::new(),
+            chain: false,
+            rate_limit: None,
+            buffer_size: 4096,
+            max_buffer: 1024 * 1024,
+            acl: AccessList::default(),
+            rules: DestinationRules::default(),
+            direct: DirectRules::default(),
+            prefer: None,
+            upstream_retries: 0,
+            upstream_retry_delay: Duration::from_millis(500),
+            breaker: None,
+            dns: None,
+            dns_protocol: DnsProtocol::Udp,
+            dns_cache: None,
+            outbound: None,
+            keepalive: Some(Keepalive::default()),
+            inbound_nodelay: true,
+            outbound_nodelay: true,
+            connect_headers: Vec::new(),
+            connect_host_style: HostStyle::AlwaysPort,
+            upstream_http_version: HttpVersion::Http11,
+            access_log: None,
+            access_log_format: AccessLogFormat::Text,
+            auth: None,
+            require_auth: false,
+            idle_timeout: None,
+            throughput_interval: None,
+            latency_report: None,
+            max_connections: None,
+            max_conns_per_ip: None,
+            event_capacity: 1024,
+            send_proxy_protocol: false,
+            http_absolute_form: false,
+            connect_timeout: None,
+            max_pending_upstream: None,
+            max_domain_len: 255,
+            max_request_size: 64 * 1024,
+            enforce_sni: false,
+            enable_resolve: false,
+            advertise_addr: None,
+            bnd_mode: BndMode::Zero,
+            admin_socket: None,
+            health_addr: None,
+            user: None,
+            group: None,
+            config_file: None,
+        }
+    }
+}
+
+// Routing rules and upstreams of a JSON config file, keyed like the flags,
+// e.g. `{"upstreams": ["http://10.0.0.1:8123"], "block-port": ["25"]}`.
+// Each list given replaces what the flags set, one left out keeps it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub upstreams: Option<Vec<String>>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub block_domain: Option<Vec<String>>,
+    pub block_port: Option<Vec<String>>,
+    pub block_cidr: Option<Vec<String>>,
+    pub block_internal: Option<bool>,
+    pub direct: Option<Vec<String>>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    // Nothing of `config` is touched unless the whole file is valid.
+    pub fn apply(&self, config: &mut Config) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let cidrs = |values: &Option<Vec<String>>| -> io::Result<Vec<Cidr>> {
+            values
+                .iter()
+                .flatten()
+                .map(|value| {
+                    Cidr::parse(value).ok_or_else(|| invalid(format!("invalid CIDR {}", value)))
+                })
+                .collect()
+        };
+
+        let mut subproxy = config.subproxy.clone();
+        if let Some(values) = self.upstreams.as_ref() {
+            if values.is_empty() {
+                return Err(invalid(String::from("no upstreams")));
+            }
+            subproxy = values
+                .iter()
+                .map(|value| match Proxy::try_parse(value) {
+                    Ok(proxy) if proxy.protocol() == &ProxyProtocol::HTTPSProxy => Err(invalid(
+                        format!("{}: https:// upstreams are not supported yet", value),
+                    )),
+                    Ok(proxy) => Ok(proxy),
+                    Err(err) => Err(invalid(err)),
+                })
+                .collect::<io::Result<_>>()?;
+        }
+
+        let mut acl = config.acl.clone();
+        if self.allow.is_some() || self.deny.is_some() {
+            acl = AccessList::default();
+            for cidr in cidrs(&self.allow)? {
+                acl.allow(cidr);
+            }
+            for cidr in cidrs(&self.deny)? {
+                acl.deny(cidr);
+            }
+        }
+
+        let mut rules = config.rules.clone();
+        if self.block_domain.is_some()
+            || self.block_port.is_some()
+            || self.block_cidr.is_some()
+            || self.block_internal.is_some()
+        {
+            rules = DestinationRules::default();
+            for value in self.block_domain.iter().flatten() {
+                rules.block_domain(value);
+            }
+            for value in self.block_port.iter().flatten() {
+                let range = PortRange::parse(value)
+                    .ok_or_else(|| invalid(format!("invalid port range {}", value)))?;
+                rules.block_port(range);
+            }
+            for cidr in cidrs(&self.block_cidr)? {
+                rules.block_cidr(cidr);
+            }
+            if self.block_internal == Some(true) {
+                rules.block_internal();
+            }
+        }
+
+        let mut direct = config.direct.clone();
+        if let Some(values) = self.direct.as_ref() {
+            direct = DirectRules::default();
+            for value in values {
+                direct.add(value);
+            }
+        }
+
+        config.subproxy = subproxy;
+        config.acl = acl;
+        config.rules = rules;
+        config.direct = direct;
+        Ok(())
+    }
+}
+
+// What tells two configs apart in what a config file sets, for the log
+// line of a reload.
+pub fn changes(old: &Config, new: &Config) -> Vec<String> {
+    let urls = |config: &Config| -> Vec<String> {
+        config
+            .subproxy
+            .iter()
+            .map(|proxy| String::from(proxy.url()))
+            .collect()
+    };
+    let (old_urls, new_urls) = (urls(old), urls(new));
+    let mut changes: Vec<String> = new_urls
+        .iter()
+        .filter(|url| !old_urls.contains(url))
+        .map(|url| format!("upstream +{}", url))
+        .chain(
+            old_urls
+                .iter()
+                .filter(|url| !new_urls.contains(url))
+                .map(|url| format!("upstream -{}", url)),
+        )
+        .collect();
+    if changes.is_empty() && old_urls != new_urls {
+        changes.push(String::from("upstreams reordered"));
+    }
+    if old.acl != new.acl {
+        changes.push(String::from("allow/deny rules changed"));
+    }
+    if old.rules != new.rules {
+        changes.push(String::from("blocking rules changed"));
+    }
+    if old.direct != new.direct {
+        changes.push(String::from("direct rules changed"));
+    }
+    changes
+}
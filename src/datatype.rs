@@ -1,20 +1,149 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+// What a successful SOCKS5 reply reports in BND.ADDR and BND.PORT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BndMode {
+    // Zeros, whatever the connection went through.
+    Zero,
+    // Where the upstream connection left from, zeros through a proxy.
+    Local,
+    // The --advertise-addr address.
+    Advertise,
+}
+
+impl BndMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "zero" => Some(BndMode::Zero),
+            "local" => Some(BndMode::Local),
+            "advertise" => Some(BndMode::Advertise),
+            _ => None,
+        }
+    }
+}
+
+// Whether a requested hostname is safe to pass upstream. Anything outside
+// the hostname charset could end up in the CONNECT line, colons are kept
+// for IPv6 literals sent as a domain.
+pub fn valid_hostname(domain: &str, max_len: usize) -> bool {
+    !domain.is_empty()
+        && domain.len() <= max_len
+        && domain
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._:".contains(&b))
+}
+
+// IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) stand for plain IPv4 ones,
+// which is what upstreams expect to see.
+pub fn normalize_addr(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Target {
     pub addr: SocketAddr,
+    pub candidates: Vec<SocketAddr>,
     pub ip: String,
     pub port: u16,
     pub domain: String,
 }
 
+impl Default for Target {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Target {
     pub fn new() -> Self {
         Self {
             addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080),
+            candidates: Vec::new(),
             ip: String::new(),
             port: 8080,
             domain: String::new(),
         }
     }
+
+    // Fills the resolved addresses of the target, the preferred address
+    // family first while keeping the resolver order within each family.
+    // Mapped addresses are normalized, a literal domain along with them.
+    pub fn set_candidates(&mut self, ips: &[IpAddr], port: u16, prefer: Option<IpFamily>) {
+        let mut candidates: Vec<SocketAddr> = ips
+            .iter()
+            .map(|ip| (normalize_addr(*ip), port).into())
+            .collect();
+        if let Ok(ip) = self.domain.parse::<IpAddr>() {
+            self.domain = normalize_addr(ip).to_string();
+        }
+        if let Some(family) = prefer {
+            candidates.sort_by_key(|addr| addr.is_ipv6() != (family == IpFamily::V6));
+        }
+        if let Some(addr) = candidates.first() {
+            self.addr = *addr;
+            self.ip = addr.ip().to_string();
+        }
+        self.port = port;
+        self.candidates = candidates;
+    }
+
+    // Addresses the target may be connected to, the placeholder one while
+    // nothing was resolved yet.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        if self.candidates.is_empty() {
+            vec![self.addr.ip()]
+        } else {
+            self.candidates.iter().map(|addr| addr.ip()).collect()
+        }
+    }
+
+    // The checks below hold when any address of the target is in the range,
+    // a domain may resolve to a public and an internal address at once.
+    pub fn is_loopback(&self) -> bool {
+        self.addresses().iter().any(|ip| ip.is_loopback())
+    }
+
+    // 169.254.0.0/16 and fe80::/10, where cloud metadata services live.
+    pub fn is_link_local(&self) -> bool {
+        self.addresses().iter().any(|ip| match ip {
+            IpAddr::V4(v4) => v4.is_link_local(),
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+        })
+    }
+
+    // RFC 1918 networks and IPv6 unique local addresses (fc00::/7).
+    pub fn is_private(&self) -> bool {
+        self.addresses().iter().any(|ip| match ip {
+            IpAddr::V4(v4) => v4.is_private(),
+            IpAddr::V6(v6) => v6.segments()[0] & 0xfe00 == 0xfc00,
+        })
+    }
+
+    // Any of the above, the unspecified address also reaches this host.
+    pub fn is_internal(&self) -> bool {
+        self.is_loopback()
+            || self.is_link_local()
+            || self.is_private()
+            || self.addresses().iter().any(|ip| ip.is_unspecified())
+    }
+
+    // Host part of an authority, IPv6 literals need brackets there. The
+    // domain is checked directly since clients may also send a literal as
+    // a SOCKS5 DOMAINNAME or SOCKS4a hostname.
+    pub fn host(&self) -> String {
+        if self.domain.parse::<Ipv6Addr>().is_ok() {
+            format!("[{}]", self.domain)
+        } else {
+            self.domain.clone()
+        }
+    }
 }
@@ -0,0 +1,258 @@
+use log::debug;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use mio::event::Event;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+
+use crate::datatype::{IpFamily, Target};
+use crate::error::Step;
+use crate::keepalive::Keepalive;
+use crate::outbound::Outbound;
+use crate::transport::{self, Transport};
+use crate::upstream::UpstreamClient;
+
+// Delay before racing the next address while an attempt is still pending.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Connects straight to the target. Its addresses are raced Happy Eyeballs
+// style (RFC 8305): families alternate and every attempt that hasn't
+// connected within ATTEMPT_DELAY gets the next one started beside it.
+pub struct DirectClient {
+    pub id: usize,
+    pub target: Target,
+    pub(crate) transport: Transport,
+    addrs: Vec<SocketAddr>,
+    attempt: usize,
+    pending: Vec<(SocketAddr, TcpStream)>,
+    race_at: Option<Instant>,
+}
+
+impl DirectClient {
+    pub fn new(id: usize, target: Target, first: IpFamily, buffer_size: usize) -> Self {
+        let addrs = DirectClient::interleave(&target.candidates, first);
+        Self {
+            id,
+            target,
+            transport: Transport::new(id, "target", buffer_size),
+            addrs,
+            attempt: 0,
+            pending: Vec::new(),
+            race_at: None,
+        }
+    }
+
+    pub fn set_outbound(&mut self, outbound: Option<Outbound>) {
+        self.transport.set_outbound(outbound);
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.transport.set_keepalive(keepalive);
+    }
+
+    // Nagle's algorithm stays off unless this is false.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.transport.set_nodelay(nodelay);
+    }
+
+    // Alternates the address families, starting with the given one.
+    fn interleave(candidates: &[SocketAddr], first: IpFamily) -> Vec<SocketAddr> {
+        let (mut primary, mut secondary): (Vec<_>, Vec<_>) = candidates
+            .iter()
+            .partition(|addr| addr.is_ipv6() == (first == IpFamily::V6));
+        primary.reverse();
+        secondary.reverse();
+        let mut addrs = Vec::with_capacity(candidates.len());
+        while let Some(addr) = primary.pop() {
+            addrs.push(addr);
+            if let Some(addr) = secondary.pop() {
+                addrs.push(addr);
+            }
+        }
+        addrs.extend(secondary.into_iter().rev());
+        addrs
+    }
+
+    // Starts the next address that doesn't fail right away. The race timer
+    // is armed as long as another address is left to try.
+    fn start_next(&mut self, registry: &Registry) -> io::Result<()> {
+        let token = self.transport.token.unwrap();
+        self.race_at = None;
+        while let Some(addr) = self.addrs.get(self.attempt).copied() {
+            self.attempt += 1;
+            match self.transport.open(addr) {
+                Ok(mut s) => {
+                    registry.register(&mut s, token, Interest::READABLE.add(Interest::WRITABLE))?;
+                    self.pending.push((addr, s));
+                    if self.attempt < self.addrs.len() {
+                        self.race_at = Some(Instant::now() + ATTEMPT_DELAY);
+                    }
+                    break;
+                }
+                Err(err) => self.transport.failed(addr, &err),
+            }
+        }
+        Ok(())
+    }
+
+    // Whether every address has been tried and none is pending anymore.
+    fn exhausted(&self) -> bool {
+        self.pending.is_empty() && self.attempt >= self.addrs.len()
+    }
+
+    // Connecting goes on until every address failed.
+    fn progress(&self) -> Step {
+        if self.exhausted() {
+            Step::Close
+        } else {
+            Step::Yield
+        }
+    }
+}
+
+impl UpstreamClient for DirectClient {
+    fn token(&self) -> Option<Token> {
+        self.transport.token
+    }
+
+    fn established(&self) -> bool {
+        true
+    }
+
+    fn handle(&mut self, _event: &Event, _value: Option<&mut BytesMut>) -> io::Result<Step> {
+        Ok(Step::Yield)
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.transport
+            .stream
+            .as_ref()
+            .and_then(|stream| stream.local_addr().ok())
+    }
+
+    fn last_error(&self) -> Option<io::ErrorKind> {
+        self.transport.last_error
+    }
+
+    fn buffer(&self) -> &BytesMut {
+        &self.transport.buffer
+    }
+
+    fn size(&self) -> usize {
+        self.transport.size
+    }
+
+    fn read_buffer_up_to(&mut self, limit: usize) -> io::Result<Step> {
+        self.transport.read_up_to(limit)
+    }
+
+    fn write_buffer(&mut self) -> io::Result<Step> {
+        self.transport.write_buffer()
+    }
+
+    fn pending(&self) -> usize {
+        self.transport.pending()
+    }
+
+    fn flush(&mut self) -> io::Result<Step> {
+        self.transport.flush()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.transport.shutdown_write()
+    }
+
+    fn swap_buffer(&mut self, buffer: &mut BytesMut) {
+        self.transport.swap_buffer(buffer);
+    }
+
+    fn clear_buffer(&mut self) {
+        self.transport.clear_buffer();
+    }
+
+    fn reset_buffer(&mut self) {
+        self.transport.reset_buffer();
+    }
+
+    fn connect(&mut self, token: Token, registry: &Registry) -> io::Result<Step> {
+        self.transport.token = Some(token);
+        self.start_next(registry)?;
+        Ok(self.progress())
+    }
+
+    fn check_connected(&mut self, registry: &Registry) -> io::Result<bool> {
+        if self.transport.stream.is_some() {
+            return Ok(true);
+        }
+
+        let mut i = 0;
+        let mut error = None;
+        while i < self.pending.len() {
+            let (addr, stream) = &mut self.pending[i];
+            let addr = *addr;
+            let err = match stream.take_error()? {
+                Some(err) => err,
+                None => match stream.peer_addr() {
+                    Ok(_) => {
+                        let (_, stream) = self.pending.swap_remove(i);
+                        debug!("[#{}] Connected directly to {}", self.id, addr);
+                        self.transport.stream = Some(stream);
+                        self.race_at = None;
+                        for (_, mut other) in self.pending.drain(..) {
+                            registry.deregister(&mut other)?;
+                        }
+                        return Ok(true);
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::NotConnected => {
+                        i += 1;
+                        continue;
+                    }
+                    Err(err) => err,
+                },
+            };
+
+            let (_, mut stream) = self.pending.swap_remove(i);
+            registry.deregister(&mut stream)?;
+            self.transport.failed(addr, &err);
+            error = Some(err);
+        }
+
+        // A failed attempt doesn't wait for the timer to start the next one.
+        if let Some(err) = error {
+            self.start_next(registry)?;
+            if self.exhausted() {
+                return Err(err);
+            }
+        }
+        Ok(false)
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        self.race_at
+    }
+
+    fn retry(&mut self, registry: &Registry) -> io::Result<Step> {
+        match self.race_at {
+            Some(at) if at <= Instant::now() => {
+                debug!("[#{}] Racing the next address of the target", self.id);
+                self.start_next(registry)?;
+                Ok(self.progress())
+            }
+            _ => Ok(Step::Yield),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.transport.reregister(registry)
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        self.transport.deregister(registry);
+        for (_, stream) in self.pending.iter_mut() {
+            transport::deregister(self.id, "target", stream, registry);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use fnv::FnvHashMap;
+use log::{debug, error};
+use mio::{Token, Waker};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::runtime::{Builder, Handle};
+use trust_dns_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::{Resolver, TokioAsyncResolver};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+}
+
+impl DnsProtocol {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "udp" => Some(DnsProtocol::Udp),
+            "tcp" => Some(DnsProtocol::Tcp),
+            _ => None,
+        }
+    }
+}
+
+// Answer of a lookup, keyed by the token of the handler that asked for it
+// along with its id, the token may belong to another one by the time the
+// answer comes in.
+pub type Resolution = (Token, usize, Option<Vec<IpAddr>>);
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+    used: u64,
+}
+
+// Bounded map of resolved domains, each kept until the TTL of its answer
+// runs out. The least recently used entry makes room for a new one.
+pub struct DnsCache {
+    capacity: usize,
+    entries: FnvHashMap<String, CacheEntry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: FnvHashMap::default(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, domain: &str, now: Instant) -> Option<Vec<IpAddr>> {
+        let key = domain.to_ascii_lowercase();
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) if entry.expires > now => {
+                entry.used = self.clock;
+                self.hits += 1;
+                Some(entry.ips.clone())
+            }
+            Some(_) => {
+                self.entries.remove(&key);
+                self.misses += 1;
+                None
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, domain: &str, ips: Vec<IpAddr>, expires: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = domain.to_ascii_lowercase();
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(domain, _)| domain.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        let used = self.clock;
+        self.entries.insert(key, CacheEntry { ips, expires, used });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+// Resolves domains on a background runtime so a slow lookup never blocks
+// the event loop. Answers are queued and the poll is woken up to fetch them.
+pub struct DnsResolver {
+    handle: Handle,
+    resolver: TokioAsyncResolver,
+    sender: Sender<Resolution>,
+    receiver: Receiver<Resolution>,
+    waker: Arc<Waker>,
+    cache: Option<Arc<Mutex<DnsCache>>>,
+}
+
+impl DnsResolver {
+    pub fn new(
+        server: Option<SocketAddr>,
+        protocol: DnsProtocol,
+        cache: Option<usize>,
+        waker: Waker,
+    ) -> io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let handle = runtime.handle().clone();
+        thread::Builder::new()
+            .name(String::from("dns"))
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+
+        let (config, opts) = resolver_config(server, protocol);
+        let resolver = {
+            let _guard = handle.enter();
+            TokioAsyncResolver::tokio(config, opts).map_err(io::Error::other)?
+        };
+        let (sender, receiver) = mpsc::channel();
+
+        Ok(Self {
+            handle,
+            resolver,
+            sender,
+            receiver,
+            waker: Arc::new(waker),
+            cache: cache.map(|size| Arc::new(Mutex::new(DnsCache::new(size)))),
+        })
+    }
+
+    pub fn resolve(&self, id: usize, token: Token, domain: String) {
+        let resolver = self.resolver.clone();
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        let cache = self.cache.clone();
+        if let Some(cache) = cache.as_ref() {
+            if let Some(ips) = lock(cache).get(&domain, Instant::now()) {
+                debug!("[#{}] Resolved {} from the DNS cache", id, domain);
+                answer(&sender, &waker, (token, id, Some(ips)));
+                return;
+            }
+        }
+        self.handle.spawn(async move {
+            let ips = match resolver.lookup_ip(domain.as_str()).await {
+                Ok(response) => {
+                    let ips: Vec<IpAddr> = response.iter().collect();
+                    if ips.is_empty() {
+                        error!("[#{}] No DNS record to requested domain", id);
+                        None
+                    } else {
+                        if let Some(cache) = cache.as_ref() {
+                            lock(cache).insert(&domain, ips.clone(), response.valid_until());
+                        }
+                        Some(ips)
+                    }
+                }
+                Err(err) => {
+                    error!("[#{}] Failed to resolve requested domain: {}", id, err);
+                    None
+                }
+            };
+            answer(&sender, &waker, (token, id, ips));
+        });
+    }
+
+    // Wakes the poll the resolver answers on, for others to share.
+    pub fn waker(&self) -> Arc<Waker> {
+        self.waker.clone()
+    }
+
+    pub fn next(&self) -> Option<Resolution> {
+        self.receiver.try_recv().ok()
+    }
+
+    // Hits and misses of the cache, when there is one.
+    pub fn cache_stats(&self) -> Option<(u64, u64)> {
+        self.cache.as_ref().map(|cache| {
+            let cache = lock(cache);
+            (cache.hits(), cache.misses())
+        })
+    }
+}
+
+fn answer(sender: &Sender<Resolution>, waker: &Waker, resolution: Resolution) {
+    if sender.send(resolution).is_ok() {
+        if let Err(err) = waker.wake() {
+            error!("Failed to wake up the event loop: {}", err);
+        }
+    }
+}
+
+// The cache holds no invariant a panicking holder could break.
+fn lock(cache: &Mutex<DnsCache>) -> MutexGuard<'_, DnsCache> {
+    match cache.lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Lookups are bounded and both families are queried at once, so an
+// unreachable server fails the request within a few seconds.
+fn resolver_config(
+    server: Option<SocketAddr>,
+    protocol: DnsProtocol,
+) -> (ResolverConfig, ResolverOpts) {
+    let config = match server {
+        Some(socket_addr) => {
+            let mut config = ResolverConfig::new();
+            config.add_name_server(NameServerConfig {
+                socket_addr,
+                protocol: match protocol {
+                    DnsProtocol::Udp => Protocol::Udp,
+                    DnsProtocol::Tcp => Protocol::Tcp,
+                },
+                tls_dns_name: None,
+                trust_nx_responses: true,
+            });
+            config
+        }
+        None => ResolverConfig::default(),
+    };
+    let opts = ResolverOpts {
+        timeout: Duration::from_secs(2),
+        attempts: 1,
+        ip_strategy: LookupIpStrategy::Ipv4AndIpv6,
+        ..ResolverOpts::default()
+    };
+    (config, opts)
+}
+
+// One answer of an SRV lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+// Looks up the SRV records of a service name with the system resolver,
+// blocking. Empty when the name has none.
+pub fn lookup_srv(name: &str) -> io::Result<Vec<SrvRecord>> {
+    let resolver = Resolver::from_system_conf()?;
+    match resolver.srv_lookup(name) {
+        Ok(lookup) => Ok(lookup
+            .iter()
+            .map(|srv| SrvRecord {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_utf8().trim_end_matches('.').to_string(),
+            })
+            .collect()),
+        Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+        Err(err) => Err(io::Error::other(err)),
+    }
+}
+
+// Orders records the way RFC 2782 picks them: lowest priority first,
+// records of the same priority drawn at random in proportion to their
+// weight. `random(n)` returns a number from 0 to n inclusive.
+pub fn order_srv(
+    mut records: Vec<SrvRecord>,
+    mut random: impl FnMut(u32) -> u32,
+) -> Vec<SrvRecord> {
+    // Zero weights go first so they still get a small chance
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let end = records
+            .iter()
+            .position(|record| record.priority != priority)
+            .unwrap_or(records.len());
+        let total: u32 = records[..end].iter().map(|r| u32::from(r.weight)).sum();
+        let pick = random(total);
+        let mut sum = 0;
+        let chosen = records[..end]
+            .iter()
+            .position(|record| {
+                sum += u32::from(record.weight);
+                sum >= pick
+            })
+            .unwrap_or(end - 1);
+        ordered.push(records.remove(chosen));
+    }
+    ordered
+}
+
+// Random source for order_srv, the keys of RandomState differ every time.
+pub fn random_up_to(max: u32) -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(max);
+    (hasher.finish() % (u64::from(max) + 1)) as u32
+}
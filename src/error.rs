@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Where a protocol step leaves the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    // Done with this stage, the next one may run right away.
+    Continue,
+    // Nothing more to do until the next event, e.g. a frame is incomplete.
+    Yield,
+    Close,
+}
+
+// A peer resetting or going away under a read or write is an ordinary
+// end of a connection rather than a failure.
+pub fn disconnected(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+// Why a connection ended, reported in its access log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // The client finished sending or went away first.
+    ClientEof,
+    // Reading from or writing to the client failed, e.g. it reset.
+    ClientError,
+    // The upstream or the origin behind it finished sending first.
+    UpstreamEof,
+    IdleTimeout,
+    // Refused by the ruleset, authentication or an operator.
+    PolicyDenied,
+    // The client sent something the protocol doesn't allow.
+    ProtocolError,
+    // The target could not be reached, or the upstream refused or broke
+    // the tunnel.
+    UpstreamError,
+}
+
+impl CloseReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::ClientError => "client_error",
+            CloseReason::UpstreamEof => "upstream_eof",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::PolicyDenied => "policy_denied",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::UpstreamError => "upstream_error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Why a connection could not go on.
+#[derive(Debug)]
+pub enum ProxyError {
+    // The peer sent something the protocol doesn't allow.
+    Protocol(String),
+    // The target's name could not be resolved.
+    Dns(String),
+    // The upstream refused or broke the tunnel.
+    Upstream(String),
+    Timeout(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            ProxyError::Dns(msg) => write!(f, "DNS error: {}", msg),
+            ProxyError::Upstream(msg) => write!(f, "upstream error: {}", msg),
+            ProxyError::Timeout(msg) => write!(f, "timed out: {}", msg),
+            ProxyError::Io(err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProxyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(err: io::Error) -> Self {
+        ProxyError::Io(err)
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(err: ProxyError) -> Self {
+        match err {
+            ProxyError::Io(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
@@ -0,0 +1,10 @@
+// ` (fd 12)` for debug logs, to match sockets with lsof or ss output.
+#[cfg(unix)]
+pub fn fd_note<S: std::os::unix::io::AsRawFd>(socket: &S) -> String {
+    format!(" (fd {})", socket.as_raw_fd())
+}
+
+#[cfg(not(unix))]
+pub fn fd_note<S>(_socket: &S) -> String {
+    String::new()
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+// Buckets per power of two, each one about 19% wider than the previous.
+const SUB_BUCKETS: u32 = 4;
+// From 1ms up to 2^24ms, about 4.7 hours, longer durations share the last.
+const BUCKETS: usize = 24 * SUB_BUCKETS as usize + 1;
+
+// Bucketed histogram of durations. Recording is a log2 and an increment,
+// percentiles come out as the upper bound of the bucket they fall in.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        self.counts[bucket(duration)] += 1;
+        self.total += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    // Duration `quantile` (0.0 to 1.0) of the recorded ones are at most,
+    // None while nothing was recorded.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((quantile * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(upper_bound(i));
+            }
+        }
+        Some(upper_bound(BUCKETS - 1))
+    }
+
+    // `p50 12ms, p95 340ms, p99 1.2s` or `-` when empty.
+    pub fn summary(&self) -> String {
+        if self.total == 0 {
+            return String::from("-");
+        }
+        [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)]
+            .iter()
+            .map(|(name, quantile)| {
+                format!(
+                    "{} {}",
+                    name,
+                    format_duration(self.percentile(*quantile).unwrap())
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn bucket(duration: Duration) -> usize {
+    let ms = duration.as_secs_f64() * 1000.0;
+    if ms <= 1.0 {
+        return 0;
+    }
+    let i = (ms.log2() * SUB_BUCKETS as f64).ceil() as usize;
+    i.min(BUCKETS - 1)
+}
+
+fn upper_bound(bucket: usize) -> Duration {
+    let ms = 2f64.powf(bucket as f64 / SUB_BUCKETS as f64);
+    Duration::from_secs_f64(ms / 1000.0)
+}
+
+fn format_duration(duration: Duration) -> String {
+    let ms = duration.as_secs_f64() * 1000.0;
+    if ms < 1000.0 {
+        format!("{:.0}ms", ms)
+    } else {
+        format!("{:.1}s", ms / 1000.0)
+    }
+}
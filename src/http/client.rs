@@ -1,16 +1,25 @@
-use log::{debug, error};
-use std::{io, usize};
+use log::debug;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use bytes::BytesMut;
 use mio::event::Event;
 use mio::net::TcpStream;
-use mio::{Interest, Registry, Token};
-use std::io::{Read, Write};
+use mio::{Registry, Token};
 
 use crate::datatype::Target;
+use crate::error::{ProxyError, Step};
+use crate::keepalive::Keepalive;
+use crate::outbound::Outbound;
 use crate::proxy::Proxy;
+use crate::transport::Transport;
+use crate::upstream::UpstreamClient;
 
-use super::client_protocol::{connection_request, connection_response, relay_in, relay_out};
+use super::client_protocol::{
+    connection_request, connection_response, forward_request, relay_in, relay_out, status_line,
+};
+use super::{HostStyle, HttpVersion};
 
 #[derive(Debug, PartialEq)]
 pub enum HttpClientState {
@@ -22,31 +31,188 @@ pub enum HttpClientState {
 }
 
 pub struct HttpClient {
+    pub id: usize,
     pub remote: Proxy,
     pub target: Target,
-    pub stream: Option<TcpStream>,
-    pub buffer: BytesMut,
-    pub size: usize,
+    pub(crate) transport: Transport,
+    max_buffer: usize,
+    pub headers: Vec<(String, String)>,
+    pub host_style: HostStyle,
+    pub version: HttpVersion,
+    // Address of the SOCKS client announced in a PROXY protocol header.
+    pub source: Option<SocketAddr>,
     pub state: HttpClientState,
+    pub status: Option<u16>,
+    // Status line of the CONNECT response, reason phrase included.
+    pub status_line: Option<String>,
+    // Cleared when the CONNECT response carries `Connection: close`.
+    pub persistent: bool,
+    // Talks to the proxy as a forwarding proxy, no CONNECT is sent.
+    forwarding: bool,
+    // Start of the first forwarded request while its head is incomplete.
+    head: Option<BytesMut>,
 }
 
 impl HttpClient {
-    pub fn new(remote: Proxy, target: Target) -> Self {
-        let mut buffer = BytesMut::with_capacity(4096);
-        buffer.resize(4096, 0);
+    pub fn new(
+        id: usize,
+        remote: Proxy,
+        target: Target,
+        buffer_size: usize,
+        max_buffer: usize,
+    ) -> Self {
         Self {
+            id,
             remote,
             target,
-            stream: None,
-            buffer,
-            size: 0,
+            transport: Transport::new(id, "HTTP proxy", buffer_size),
+            max_buffer,
+            headers: Vec::new(),
+            host_style: HostStyle::AlwaysPort,
+            version: HttpVersion::Http11,
+            source: None,
             state: HttpClientState::ConnectionRequest,
+            status: None,
+            status_line: None,
+            persistent: true,
+            forwarding: false,
+            head: None,
         }
     }
 
-    pub fn handle(&mut self, event: &Event, value: Option<&BytesMut>) -> io::Result<bool> {
+    pub fn read_buffer(&mut self) -> io::Result<Step> {
+        self.read_buffer_up_to(self.max_buffer)
+    }
+
+    // Most a read takes at once, also the bound on the CONNECT response head.
+    #[inline]
+    pub fn max_buffer(&self) -> usize {
+        self.max_buffer
+    }
+
+    // Status of an `HTTP/1.x NNN` line, exactly three digits.
+    pub fn extract_statuscode(&self) -> Result<u16, ProxyError> {
+        let line = status_line(&self.transport.buffer[..self.transport.size]);
+        let invalid = || ProxyError::Protocol(format!("malformed status line {:?}", line));
+        let rest = match line.strip_prefix("HTTP/1.") {
+            Some(rest) => rest,
+            None => return Err(invalid()),
+        };
+        let mut parts = rest.splitn(3, ' ');
+        let minor = parts.next().unwrap_or_default();
+        let status = parts.next().unwrap_or_default();
+        if minor.len() != 1
+            || !minor.bytes().all(|b| b.is_ascii_digit())
+            || status.len() != 3
+            || !status.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        status.parse::<u16>().map_err(|_| invalid())
+    }
+
+    pub fn set_outbound(&mut self, outbound: Option<Outbound>) {
+        self.transport.set_outbound(outbound);
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.transport.set_keepalive(keepalive);
+    }
+
+    // Nagle's algorithm stays off unless this is false.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.transport.set_nodelay(nodelay);
+    }
+
+    // Extra headers sent along with the CONNECT request, in order.
+    pub fn set_headers(&mut self, headers: Vec<(String, String)>) {
+        self.headers = headers;
+    }
+
+    pub fn set_host_style(&mut self, style: HostStyle) {
+        self.host_style = style;
+    }
+
+    pub fn set_http_version(&mut self, version: HttpVersion) {
+        self.version = version;
+    }
+
+    // Bounds the time from connecting to the answer of the CONNECT.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.transport.set_connect_timeout(timeout);
+    }
+
+    // Prepends a PROXY protocol v1 line for the given client to the CONNECT.
+    pub fn set_proxy_protocol(&mut self, source: Option<SocketAddr>) {
+        self.source = source;
+    }
+
+    // Only plain HTTP on port 80 is forwarded, anything else still tunnels.
+    // The proxy is asked to close after the first request, which is the only
+    // one rewritten.
+    pub fn set_absolute_form(&mut self, enabled: bool) {
+        self.forwarding = enabled && self.target.port == 80;
+        if self.forwarding {
+            self.head = Some(BytesMut::new());
+            self.persistent = false;
+            self.set_state(HttpClientState::RelayingOUT);
+        }
+    }
+
+    pub fn set_retry(&mut self, retries: u32, delay: Duration) {
+        self.transport.set_retry(retries, delay);
+    }
+
+    #[inline]
+    pub fn set_state(&mut self, state: HttpClientState) {
+        self.state = state;
+    }
+
+    pub fn put_buff(&mut self, value: &[u8]) {
+        self.transport.put(value);
+    }
+
+    fn closing(&mut self, step: Step) -> Step {
+        if step == Step::Close {
+            self.set_state(HttpClientState::Closed);
+        }
+        step
+    }
+}
+
+impl UpstreamClient for HttpClient {
+    fn token(&self) -> Option<Token> {
+        self.transport.token
+    }
+
+    fn established(&self) -> bool {
+        self.forwarding
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    fn status_line(&self) -> Option<&str> {
+        self.status_line.as_deref()
+    }
+
+    fn last_error(&self) -> Option<io::ErrorKind> {
+        self.transport.last_error
+    }
+
+    fn buffer(&self) -> &BytesMut {
+        &self.transport.buffer
+    }
+
+    fn size(&self) -> usize {
+        self.transport.size
+    }
+
+    fn handle(&mut self, event: &Event, value: Option<&mut BytesMut>) -> io::Result<Step> {
         debug!(
-            "HTTP Client state: {:?}, readable: {}, writeable: {}",
+            "[#{}] HTTP Client state: {:?}, readable: {}, writeable: {}",
+            self.id,
             self.state,
             event.is_readable(),
             event.is_writable()
@@ -55,150 +221,134 @@ impl HttpClient {
         let result = match self.state {
             HttpClientState::ConnectionRequest => connection_request(self),
             HttpClientState::ConnectionEstablished => connection_response(self),
-            HttpClientState::RelayingOUT => {
-                self.buffer.clone_from(value.unwrap());
-                self.size = self.buffer.len();
-                relay_out(self)
-            }
-            HttpClientState::RelayingIN => {
-                let result = relay_in(self);
-                if result.is_err() {
-                    return result;
+            HttpClientState::RelayingOUT => match value {
+                Some(buffer) => {
+                    self.transport.swap_buffer(buffer);
+                    relay_out(self)
                 }
-                if self.size == 0 && result.unwrap() {
-                    return Ok(true);
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no buffer to relay",
+                )),
+            },
+            HttpClientState::RelayingIN => {
+                let step = relay_in(self)?;
+                if self.transport.size == 0 && step == Step::Close {
+                    return Ok(Step::Close);
                 }
-                Ok(false)
+                Ok(Step::Yield)
             }
-            _ => Ok(false),
+            _ => Ok(Step::Yield),
         };
         match result {
-            Ok(true) | Err(_) => return Ok(true),
-            _ => {}
+            Ok(Step::Close) | Err(_) => Ok(Step::Close),
+            Ok(step) => Ok(step),
         }
+    }
 
-        Ok(false)
-    }
-
-    pub fn read_buffer(&mut self) -> io::Result<bool> {
-        let stream = self.stream.as_mut().unwrap();
-        loop {
-            debug!(
-                "HTTP Client buffer:{}, size: {}",
-                self.buffer.len(),
-                self.size
-            );
-            match stream.read(&mut self.buffer[self.size..]) {
-                Ok(0) => {
-                    self.set_state(HttpClientState::Closed);
-                    return Ok(true);
-                }
-                Ok(n) => {
-                    self.size += n;
-                    if self.size == self.buffer.len() {
-                        self.buffer.resize(self.buffer.len() + 1024, 0);
-                    }
-                }
-                Err(ref err) if HttpClient::would_block(err) => break,
-                Err(ref err) if HttpClient::interrupted(err) => continue,
-                Err(err) => {
-                    return Err(err);
-                }
+    fn read_buffer_up_to(&mut self, limit: usize) -> io::Result<Step> {
+        debug!(
+            "[#{}] HTTP Client buffer:{}, size: {}",
+            self.id,
+            self.transport.buffer.capacity(),
+            self.transport.size
+        );
+        match self.transport.read_up_to(limit) {
+            Ok(step) => Ok(self.closing(step)),
+            // The proxy said it would drop the socket, a reset is just how
+            // it chose to do that
+            Err(ref err) if !self.persistent && err.kind() == io::ErrorKind::ConnectionReset => {
+                Ok(self.closing(Step::Close))
             }
+            Err(err) => Err(err),
         }
-        if self.size != self.buffer.len() {
-            self.buffer.resize(self.size, 0);
-        }
-        Ok(false)
     }
 
-    pub fn write_buffer(&mut self) -> io::Result<bool> {
-        let stream = self.stream.as_mut().unwrap();
-        match stream.write(&self.buffer) {
-            Ok(n) if n < self.size => Err(io::ErrorKind::WriteZero.into()),
-            Ok(n) => {
-                self.size -= n;
-                Ok(false)
-            }
-            Err(ref err) if HttpClient::would_block(err) => Ok(false),
-            Err(ref err) if HttpClient::interrupted(err) => {
-                self.set_state(HttpClientState::Closed);
-                Ok(true)
-            }
-            Err(err) => Err(err),
+    fn write_buffer(&mut self) -> io::Result<Step> {
+        if let Some(mut head) = self.head.take() {
+            head.unsplit(self.transport.buffer.split());
+            self.transport.size = 0;
+            let step = match forward_request(self, &head, self.max_buffer) {
+                Some(request) => self.transport.send(&request)?,
+                None => {
+                    self.head = Some(head);
+                    return Ok(Step::Continue);
+                }
+            };
+            return Ok(self.closing(step));
         }
+        let step = self.transport.write_buffer()?;
+        Ok(self.closing(step))
     }
 
-    pub fn clone_buffer(&mut self, source: &BytesMut) {
-        self.buffer.clone_from(source);
-        self.size = source.len();
+    fn pending(&self) -> usize {
+        self.transport.pending()
     }
 
-    pub fn extract_statuscode(&self) -> io::Result<u16> {
-        if self.size < 12 {
-            return Err(io::ErrorKind::UnexpectedEof.into());
-        }
+    fn flush(&mut self) -> io::Result<Step> {
+        let step = self.transport.flush()?;
+        Ok(self.closing(step))
+    }
 
-        let status = &self.buffer[9..12];
-        let mut result: u16 = 0;
-        for i in status.iter().take(3) {
-            result = result * 10 + (*i as u16 - 0x30);
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        // The client is done, whatever was held back goes out as it is
+        if let Some(head) = self.head.take() {
+            let request = forward_request(self, &head, 0).unwrap_or_default();
+            self.transport.send(&request)?;
         }
+        self.transport.shutdown_write()
+    }
 
-        Ok(result)
+    fn swap_buffer(&mut self, buffer: &mut BytesMut) {
+        self.transport.swap_buffer(buffer);
     }
 
-    pub fn clear_buffer(&mut self) {
-        self.buffer.clear();
-        self.buffer.resize(4096, 0);
-        self.size = 0;
+    fn clear_buffer(&mut self) {
+        self.transport.clear_buffer();
     }
 
-    pub fn reset_buffer(&mut self) {
-        self.buffer.clear();
+    fn reset_buffer(&mut self) {
+        self.transport.reset_buffer();
     }
 
-    pub fn connect(&mut self, token: Token, registry: &Registry) -> io::Result<bool> {
-        if self.stream.is_none() {
-            self.stream = match TcpStream::connect(self.remote.addr) {
-                Ok(s) => {
-                    debug!("Connect to HTTP proxy {}", self.remote.addr);
-                    s.set_nodelay(true)?;
-                    Some(s)
-                }
-                Err(err) => {
-                    error!(
-                        "Failed to connect to HTTP proxy {}, reason: {}",
-                        self.remote.addr, err
-                    );
-                    return Ok(true);
-                }
-            };
-        }
+    fn connect(&mut self, token: Token, registry: &Registry) -> io::Result<Step> {
+        self.transport.connect(&self.remote, token, registry)
+    }
 
-        let stream = self.stream.as_mut().unwrap();
+    fn retry_at(&self) -> Option<Instant> {
+        self.transport.retry_at()
+    }
 
-        registry.register(stream, token, Interest::READABLE.add(Interest::WRITABLE))?;
+    fn deadline(&self) -> Option<Instant> {
+        self.transport.connect_deadline
+    }
 
-        Ok(false)
+    fn retry(&mut self, registry: &Registry) -> io::Result<Step> {
+        self.transport.retry(&self.remote, registry)
     }
 
-    #[inline]
-    pub fn set_state(&mut self, state: HttpClientState) {
-        self.state = state;
+    fn check_connected(&mut self, registry: &Registry) -> io::Result<bool> {
+        let connected = self.transport.check_connected(&self.remote, registry)?;
+        // Forwarding has no CONNECT answer to wait for
+        if connected && self.forwarding {
+            self.transport.connect_deadline = None;
+        }
+        Ok(connected)
     }
 
-    pub fn put_buff(&mut self, value: &[u8]) {
-        let len = value.len();
-        self.buffer.extend(value);
-        self.size += len;
+    fn take_stream(&mut self) -> Option<TcpStream> {
+        self.transport.stream.take()
+    }
+
+    fn adopt(&mut self, stream: TcpStream, token: Token) {
+        self.transport.adopt(stream, token);
     }
 
-    fn would_block(err: &io::Error) -> bool {
-        err.kind() == io::ErrorKind::WouldBlock
+    fn reregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.transport.reregister(registry)
     }
 
-    fn interrupted(err: &io::Error) -> bool {
-        err.kind() == io::ErrorKind::Interrupted
+    fn deregister(&mut self, registry: &Registry) {
+        self.transport.deregister(registry);
     }
 }
@@ -1,15 +1,48 @@
 use super::client::HttpClient;
 use super::client::HttpClientState;
-use log::{debug, error};
+use super::{basic_auth, parse_header, HostStyle, HttpVersion};
+use crate::datatype::Target;
+use crate::error::Step;
+use crate::upstream::UpstreamClient;
+use bytes::Buf;
+use log::{debug, error, info};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 
-pub fn connection_request(client: &mut HttpClient) -> io::Result<bool> {
-    debug!("HTTP Client Connection Request");
+pub fn connection_request(client: &mut HttpClient) -> io::Result<Step> {
+    debug!("[#{}] HTTP Client Connection Request", client.id);
 
     client.reset_buffer();
 
-    let msg = format!("CONNECT\x20{host}:{port}\x20HTTP/1.1\r\nProxy-Connection: keep-alive\r\nConnection: keep-alive\r\nHost: {host}:{port}\r\n\r\n", host = client.target.domain,
- port = client.target.port);
+    // The PROXY line has to come first, the CONNECT follows in the same write
+    if let Some(source) = client.source {
+        let header = proxy_header(source, &client.target);
+        client.put_buff(header.as_bytes());
+    }
+    let host = client.target.host();
+    let port = client.target.port;
+    let authority = match client.host_style {
+        HostStyle::OmitDefault if port == 80 || port == 443 => host.clone(),
+        _ => format!("{}:{}", host, port),
+    };
+    // HTTP/1.0 proxies mishandle the keep-alive hints, they are left out
+    let (version, hints) = match client.version {
+        HttpVersion::Http11 => (
+            "1.1",
+            "Proxy-Connection: keep-alive\r\nConnection: keep-alive\r\n",
+        ),
+        HttpVersion::Http10 => ("1.0", ""),
+    };
+    let mut msg = format!(
+        "CONNECT\x20{host}:{port}\x20HTTP/{version}\r\n{hints}Host: {authority}\r\n",
+        host = host,
+        port = port,
+        version = version,
+        hints = hints,
+        authority = authority
+    );
+    msg.push_str(&extra_headers(client));
+    msg.push_str("\r\n");
     client.put_buff(msg.as_bytes());
     let result = client.write_buffer();
 
@@ -17,74 +50,306 @@ pub fn connection_request(client: &mut HttpClient) -> io::Result<bool> {
     result
 }
 
-pub fn connection_response(client: &mut HttpClient) -> io::Result<bool> {
-    debug!("HTTP Client Connection Response");
+// The first request of a forwarded connection, rewritten to absolute form
+// once its head is complete. None while more of it is needed, anything that
+// doesn't look like a request or outgrows `limit` goes out untouched.
+pub fn forward_request(client: &HttpClient, head: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n");
+    if end.is_none() && head.len() < limit && request_prefix(head) {
+        return None;
+    }
+    let mut msg = Vec::new();
+    if let Some(source) = client.source {
+        msg.extend_from_slice(proxy_header(source, &client.target).as_bytes());
+    }
+    match end.and_then(|i| Some((i, absolute_form(client, &head[..i])?))) {
+        Some((i, request)) => {
+            debug!("[#{}] HTTP Client forwarding request", client.id);
+            msg.extend_from_slice(request.as_bytes());
+            msg.extend_from_slice(&head[i + 4..]);
+        }
+        None => {
+            info!(
+                "[#{}] Not an HTTP request, passed on to {} as is",
+                client.id, client.remote.addr
+            );
+            msg.extend_from_slice(head);
+        }
+    }
+    Some(msg)
+}
 
-    client.clear_buffer();
-    match client.read_buffer() {
-        Ok(false) => {}
-        Ok(true) => {
-            debug!("HTTP Client connection response interrupted");
-            return Ok(true);
+// Whether the bytes so far may still start a request line.
+fn request_prefix(head: &[u8]) -> bool {
+    let method = match head.iter().position(|b| *b == b' ') {
+        Some(i) => &head[..i],
+        None => head,
+    };
+    method.len() <= 16 && method.iter().all(u8::is_ascii_uppercase)
+}
+
+// Request head with an absolute-form target, the hop-by-hop headers of the
+// client replaced by `Connection: close`.
+fn absolute_form(client: &HttpClient, head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?;
+    let path = request_line.next()?;
+    let version = request_line.next()?;
+    if request_line.next().is_some()
+        || method.is_empty()
+        || !method.bytes().all(|b| b.is_ascii_uppercase())
+        || !path.starts_with('/')
+        || !version.starts_with("HTTP/1.")
+    {
+        return None;
+    }
+    let host = client.target.host();
+    let authority = match client.target.port {
+        80 => host,
+        port => format!("{}:{}", host, port),
+    };
+    let mut msg = format!("{} http://{}{} {}\r\n", method, authority, path, version);
+    let hop_by_hop = |line: &str| {
+        parse_header(line).is_some_and(|(name, _)| {
+            ["connection", "proxy-connection", "keep-alive"]
+                .iter()
+                .any(|hop| name.eq_ignore_ascii_case(hop))
+        })
+    };
+    for line in lines.filter(|line| !hop_by_hop(line)) {
+        msg.push_str(line);
+        msg.push_str("\r\n");
+    }
+    msg.push_str("Connection: close\r\n");
+    msg.push_str(&extra_headers(client));
+    msg.push_str("\r\n");
+    Some(msg)
+}
+
+// Configured headers, then the credentials of the proxy URL unless one of
+// them already authorizes.
+fn extra_headers(client: &HttpClient) -> String {
+    let mut headers = String::new();
+    for (name, value) in client.headers.iter() {
+        headers.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let (Some((username, password)), false) =
+        (client.remote.credentials(), configured_auth(client))
+    {
+        headers.push_str(&format!(
+            "Proxy-Authorization: {}\r\n",
+            basic_auth(username, password)
+        ));
+    }
+    headers
+}
+
+fn configured_auth(client: &HttpClient) -> bool {
+    client
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("proxy-authorization"))
+}
+
+// First line of a response, e.g. `HTTP/1.1 502 Bad Gateway`.
+pub fn status_line(buffer: &[u8]) -> String {
+    let end = buffer
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).trim().to_string()
+}
+
+// Header fields of a response head, the status line skipped.
+fn response_headers(head: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(head)
+        .split("\r\n")
+        .skip(1)
+        .filter_map(parse_header)
+        .collect()
+}
+
+// Whether the response leaves the connection open, `close` in either
+// Connection or Proxy-Connection says it does not.
+fn persistent(headers: &[(String, String)]) -> bool {
+    !headers.iter().any(|(name, value)| {
+        (name.eq_ignore_ascii_case("connection") || name.eq_ignore_ascii_case("proxy-connection"))
+            && value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("close"))
+    })
+}
+
+// PROXY protocol v1 line, UNKNOWN when the target has no address of the
+// same family as the client.
+fn proxy_header(source: SocketAddr, target: &Target) -> String {
+    let destination = target
+        .ip
+        .parse::<IpAddr>()
+        .or_else(|_| target.domain.parse::<IpAddr>());
+    match (source.ip(), destination) {
+        (IpAddr::V4(src), Ok(IpAddr::V4(dst))) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            src,
+            dst,
+            source.port(),
+            target.port
+        ),
+        (IpAddr::V6(src), Ok(IpAddr::V6(dst))) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            src,
+            dst,
+            source.port(),
+            target.port
+        ),
+        _ => String::from("PROXY UNKNOWN\r\n"),
+    }
+}
+
+pub fn connection_response(client: &mut HttpClient) -> io::Result<Step> {
+    debug!("[#{}] HTTP Client Connection Response", client.id);
+
+    // What came with earlier reads stays, the head may arrive in pieces
+    let closed = match client.read_buffer() {
+        // A refusing proxy may close right after its response
+        Ok(Step::Close) if client.transport.size > 0 => true,
+        Ok(Step::Close) => {
+            error!(
+                "[#{}] HTTP proxy {} closed the connection before answering the CONNECT",
+                client.id, client.remote.addr
+            );
+            return Ok(Step::Close);
         }
         Err(err) => {
             error!(
-                "During HTTP Client connection response, error occured: {}",
-                err
+                "[#{}] During HTTP Client connection response, error occured: {}",
+                client.id, err
             );
             return Err(err);
         }
-    }
+        Ok(_) => false,
+    };
 
-    if client.size == 0 {
-        return Ok(true);
+    // The status is only looked at once the whole head is in, a proxy that
+    // closed is taken at what it sent
+    let end = client
+        .transport
+        .buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n");
+    if end.is_none() && !closed {
+        if client.transport.size < client.max_buffer() {
+            return Ok(Step::Yield);
+        }
+        error!(
+            "[#{}] HTTP proxy {} sent a response head larger than {} bytes",
+            client.id,
+            client.remote.addr,
+            client.max_buffer()
+        );
+        return Ok(Step::Close);
     }
 
     let status_code = match client.extract_statuscode() {
         Ok(u) => u,
         Err(err) => {
-            error!("HTTP Client got unexpected response");
-            return Err(err);
+            error!(
+                "[#{}] HTTP Client got unexpected response from {}: {}",
+                client.id, client.remote.addr, err
+            );
+            return Err(err.into());
         }
     };
 
-    if status_code != 200 {
-        error!("HTTP Client received non-200 response");
-        return Ok(true);
+    client.status = Some(status_code);
+    client.status_line = Some(status_line(&client.transport.buffer));
+    client.transport.connect_deadline = None;
+    match status_code {
+        200 => {}
+        407 => {
+            let sent = if client.remote.credentials().is_some() || configured_auth(client) {
+                "the credentials were rejected"
+            } else {
+                "no credentials were sent"
+            };
+            error!(
+                "[#{}] HTTP proxy {} requires authentication, {}: {}",
+                client.id,
+                client.remote.addr,
+                sent,
+                client.status_line.as_deref().unwrap_or_default()
+            );
+            return Ok(Step::Close);
+        }
+        _ => {
+            error!(
+                "[#{}] HTTP Client received non-200 response: {}",
+                client.id,
+                client.status_line.as_deref().unwrap_or_default()
+            );
+            return Ok(Step::Close);
+        }
+    }
+
+    let end = match end {
+        Some(i) => i,
+        None => {
+            error!(
+                "[#{}] HTTP proxy {} closed the connection before finishing its response",
+                client.id, client.remote.addr
+            );
+            return Ok(Step::Close);
+        }
+    };
+    if !persistent(&response_headers(&client.transport.buffer[..end])) {
+        info!(
+            "[#{}] HTTP proxy {} does not keep the tunnel open, expecting it to close",
+            client.id, client.remote.addr
+        );
+        client.persistent = false;
     }
+    // Anything after the header already belongs to the tunnel and is kept
+    // for the SOCKS client.
+    client.transport.buffer.advance(end + 4);
+    client.transport.size -= end + 4;
 
-    debug!("HTTP Client tunnel established");
+    debug!("[#{}] HTTP Client tunnel established", client.id);
     client.set_state(HttpClientState::RelayingOUT);
-    Ok(false)
+    Ok(Step::Continue)
 }
 
 // Receive from HTTP Proxy
-pub fn relay_in(client: &mut HttpClient) -> io::Result<bool> {
-    debug!("HTTP Client Relay IN");
+pub fn relay_in(client: &mut HttpClient) -> io::Result<Step> {
+    debug!("[#{}] HTTP Client Relay IN", client.id);
 
     client.clear_buffer();
     match client.read_buffer() {
-        Ok(false) => {}
-        Ok(true) => {
-            debug!("HTTP Client Relay IN interrupted");
-            return Ok(true);
+        Ok(Step::Close) => {
+            debug!("[#{}] HTTP Client Relay IN interrupted", client.id);
+            return Ok(Step::Close);
         }
         Err(err) => {
-            error!("During HTTP Client Relay IN, error occured: {}", err);
+            error!(
+                "[#{}] During HTTP Client Relay IN, error occured: {}",
+                client.id, err
+            );
             return Err(err);
         }
+        Ok(_) => {}
     }
 
     client.set_state(HttpClientState::RelayingOUT);
-    Ok(false)
+    Ok(Step::Continue)
 }
 
 // Send to HTTP Proxy
-pub fn relay_out(client: &mut HttpClient) -> io::Result<bool> {
-    debug!("HTTP Client Relay OUT");
+pub fn relay_out(client: &mut HttpClient) -> io::Result<Step> {
+    debug!("[#{}] HTTP Client Relay OUT", client.id);
 
-    if client.size == 0 {
-        return Ok(true);
+    if client.transport.size == 0 {
+        return Ok(Step::Close);
     }
 
     client.set_state(HttpClientState::RelayingIN);
@@ -1,2 +1,73 @@
 pub mod client;
 mod client_protocol;
+
+// How the port shows up in the Host header of a CONNECT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostStyle {
+    AlwaysPort,
+    // Leaves out 80 and 443, which some appliances insist on.
+    OmitDefault,
+}
+
+impl HostStyle {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "always-port" => Some(HostStyle::AlwaysPort),
+            "omit-default" => Some(HostStyle::OmitDefault),
+            _ => None,
+        }
+    }
+}
+
+// HTTP version spoken with the upstream proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http11,
+    // Drops the keep-alive hints old proxies mishandle.
+    Http10,
+}
+
+impl HttpVersion {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "1.1" => Some(HttpVersion::Http11),
+            "1.0" => Some(HttpVersion::Http10),
+            _ => None,
+        }
+    }
+}
+
+// Splits a `Name: Value` header, refusing anything that would break out of
+// the header line.
+pub fn parse_header(value: &str) -> Option<(String, String)> {
+    let i = value.find(':')?;
+    let name = value[..i].trim();
+    let value = value[i + 1..].trim();
+    let token = |c: char| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c);
+    if name.is_empty() || !name.chars().all(token) || value.contains(['\r', '\n']) {
+        return None;
+    }
+    Some((String::from(name), String::from(value)))
+}
+
+// Value of a `Basic` authorization header, a missing password counts as
+// empty.
+pub fn basic_auth(username: &str, password: Option<&str>) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let plain = format!("{}:{}", username, password.unwrap_or_default());
+    let mut encoded = String::from("Basic ");
+    for chunk in plain.as_bytes().chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+// TCP keepalive probing of a relayed socket, so NATs along the way keep
+// the state of idle tunnels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Keepalive {
+    pub fn apply<S: AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs(self.idle))?;
+            setsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPINTVL,
+                secs(self.interval),
+            )?;
+        }
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, secs(self.idle))?;
+        Ok(())
+    }
+}
+
+// Whole seconds, at least one since zero is rejected by the kernel.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn secs(duration: Duration) -> libc::c_int {
+    duration.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int
+}
+
+fn setsockopt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the option value is a plain int passed with its size.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
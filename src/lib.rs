@@ -0,0 +1,28 @@
+pub mod accesslog;
+pub mod acl;
+pub mod breaker;
+mod buffer;
+pub mod chain;
+pub mod check;
+pub mod config;
+pub mod datatype;
+pub mod direct;
+pub mod dns;
+pub mod error;
+mod fd;
+pub mod histogram;
+pub mod http;
+pub mod keepalive;
+pub mod logger;
+pub mod outbound;
+#[cfg(unix)]
+pub mod privileges;
+pub mod proxy;
+pub mod ratelimit;
+pub mod selector;
+#[cfg(unix)]
+mod signal;
+pub mod socks;
+pub mod timers;
+mod transport;
+pub mod upstream;
@@ -0,0 +1,160 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+use env_logger::filter::{Builder, Filter};
+use log::{Level, Log, Metadata, Record};
+
+// Where log records go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogTarget {
+    Stderr,
+    File(PathBuf),
+    Syslog,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(File),
+    Syslog,
+}
+
+struct Logger {
+    filter: Filter,
+    format: LogFormat,
+    sink: Mutex<Sink>,
+}
+
+impl Logger {
+    fn line(&self, record: &Record) -> String {
+        match self.format {
+            LogFormat::Text => format!(
+                "{} {:<5} {} > {}",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut line = self.line(record);
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match &mut *sink {
+            Sink::Stderr => {
+                line.push('\n');
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            // A single write per line keeps appends from several processes
+            // from interleaving.
+            Sink::File(file) => {
+                line.push('\n');
+                let _ = file.write_all(line.as_bytes());
+            }
+            Sink::Syslog => {
+                let message = match CString::new(line) {
+                    Ok(message) => message,
+                    Err(_) => return,
+                };
+                // SAFETY: both strings are NUL terminated and the format
+                // consumes exactly the one argument passed.
+                unsafe {
+                    libc::syslog(
+                        priority(record.level()),
+                        b"%s\0".as_ptr() as *const libc::c_char,
+                        message.as_ptr(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            if let Sink::File(file) = &mut *sink {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+fn priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+// Installs the global logger. `spec` takes the same directives as
+// RUST_PROXYCHAIN_LOG, e.g. `info` or `proxychain::http=debug`.
+pub fn init(target: LogTarget, format: LogFormat, spec: &str) -> io::Result<()> {
+    let sink = match target {
+        LogTarget::Stderr => Sink::Stderr,
+        LogTarget::File(path) => {
+            Sink::File(OpenOptions::new().create(true).append(true).open(path)?)
+        }
+        LogTarget::Syslog => {
+            // SAFETY: the ident is a static NUL terminated string, which
+            // openlog keeps a pointer to.
+            unsafe {
+                libc::openlog(
+                    b"proxychain\0".as_ptr() as *const libc::c_char,
+                    libc::LOG_PID,
+                    libc::LOG_DAEMON,
+                );
+            }
+            Sink::Syslog
+        }
+    };
+    let filter = Builder::new().parse(spec).build();
+    let max_level = filter.filter();
+    let logger = Logger {
+        filter,
+        format,
+        sink: Mutex::new(sink),
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(io::Error::other)?;
+    log::set_max_level(max_level);
+    Ok(())
+}
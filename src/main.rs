@@ -1,12 +1,24 @@
-mod datatype;
-mod http;
-mod proxy;
-mod socks;
-use std::env;
+use std::{
+    env,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
 
 use clap::{App, Arg};
-use proxy::Proxy;
-use socks::server::Socks5Server;
+use proxychain::accesslog::AccessLogFormat;
+use proxychain::acl::{AccessList, Cidr, DestinationRules, DirectRules, PortRange};
+use proxychain::breaker::BreakerConfig;
+use proxychain::datatype::{BndMode, IpFamily, Target};
+use proxychain::dns::DnsProtocol;
+use proxychain::http::{parse_header, HostStyle, HttpVersion};
+use proxychain::keepalive::Keepalive;
+use proxychain::logger::{self, LogFormat, LogTarget};
+use proxychain::outbound::Outbound;
+use proxychain::proxy::{Proxy, ProxyProtocol};
+use proxychain::selector::RoundRobin;
+use proxychain::socks::server::Socks5Server;
 
 fn main() {
     let matches = App::new("proxychain")
@@ -27,32 +39,868 @@ fn main() {
                 .short("o")
                 .long("out")
                 .value_name("out")
-                .help("Sets remote proxy to connect to")
+                .help("Sets remote proxy to connect to, repeatable, PROXYCHAIN_UPSTREAM when unset")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("balance")
+                .long("balance")
+                .value_name("strategy")
+                .help("Sets how connections are spread over the remote proxies")
+                .takes_value(true)
+                .possible_values(&["first", "round-robin"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("chain")
+                .long("chain")
+                .help("Goes through every remote proxy in the order given instead of picking one")
+                .conflicts_with("balance"),
+        )
+        .arg(
+            Arg::with_name("listen-backlog")
+                .long("listen-backlog")
+                .value_name("N")
+                .help("Sets the queue length of pending local connections")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("buffer-size")
+                .long("buffer-size")
+                .value_name("bytes")
+                .help("Sets the initial size of each connection buffer")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-buffer")
+                .long("max-buffer")
+                .value_name("bytes")
+                .help("Sets how much is read from a peer before flushing it on")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("rate-limit")
+                .long("rate-limit")
+                .value_name("bytes-per-sec")
+                .help("Sets the maximum throughput of each connection")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .value_name("cidr")
+                .help("Only accepts clients from this range, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .value_name("cidr")
+                .help("Rejects clients from this range, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("block-domain")
+                .long("block-domain")
+                .value_name("suffix")
+                .help("Refuses targets under this domain, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("block-port")
+                .long("block-port")
+                .value_name("port[-port]")
+                .help("Refuses targets on these ports, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("block-internal")
+                .long("block-internal")
+                .help("Refuses targets resolving to loopback, link-local or private addresses"),
+        )
+        .arg(
+            Arg::with_name("block-cidr")
+                .long("block-cidr")
+                .value_name("cidr")
+                .help("Refuses targets resolving into this range, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("direct")
+                .long("direct")
+                .value_name("cidr|suffix")
+                .help("Connects to matching targets without the remote proxy, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("direct-loopback")
+                .long("direct-loopback")
+                .help("Connects to loopback targets without the remote proxy"),
+        )
+        .arg(
+            Arg::with_name("prefer-ipv4")
+                .long("prefer-ipv4")
+                .help("Tries IPv4 addresses of a resolved target first")
+                .conflicts_with("prefer-ipv6"),
+        )
+        .arg(
+            Arg::with_name("prefer-ipv6")
+                .long("prefer-ipv6")
+                .help("Tries IPv6 addresses of a resolved target first"),
+        )
+        .arg(
+            Arg::with_name("upstream-retries")
+                .long("upstream-retries")
+                .value_name("N")
+                .help("Sets how many times to retry an unreachable remote proxy")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("upstream-retry-delay")
+                .long("upstream-retry-delay")
+                .value_name("ms")
+                .help("Sets the initial delay between remote proxy retries")
+                .takes_value(true)
+                .requires("upstream-retries")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("dns")
+                .long("dns")
+                .value_name("addr")
+                .help("Resolves target domains through this DNS server")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("dns-protocol")
+                .long("dns-protocol")
+                .value_name("protocol")
+                .help("Sets the protocol used to reach the DNS server")
+                .takes_value(true)
+                .possible_values(&["udp", "tcp"])
+                .requires("dns")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("bind-outbound")
+                .long("bind-outbound")
+                .value_name("ip|interface")
+                .help("Makes outgoing connections from this local IP or interface")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("keepalive-idle")
+                .long("keepalive-idle")
+                .value_name("secs")
+                .help("Sets the idle time before TCP keepalive probes, 60 by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("keepalive-interval")
+                .long("keepalive-interval")
+                .value_name("secs")
+                .help("Sets the interval between TCP keepalive probes, 10 by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("breaker-failures")
+                .long("breaker-failures")
+                .value_name("count")
+                .help("Skips an upstream after this many failed handshakes, 5 by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("breaker-window")
+                .long("breaker-window")
+                .value_name("secs")
+                .help("Sets how recent counted upstream failures are, 10 by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("breaker-cooldown")
+                .long("breaker-cooldown")
+                .value_name("secs")
+                .help("Sets how long a failing upstream is skipped, 30 by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("nodelay")
+                .long("nodelay")
+                .value_name("true|false")
+                .help("Sets TCP_NODELAY on relayed sockets, true by default")
+                .takes_value(true)
+                .possible_values(&["true", "false"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("inbound-nodelay")
+                .long("inbound-nodelay")
+                .value_name("true|false")
+                .help("Sets TCP_NODELAY on client connections only, overriding --nodelay")
+                .takes_value(true)
+                .possible_values(&["true", "false"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("outbound-nodelay")
+                .long("outbound-nodelay")
+                .value_name("true|false")
+                .help("Sets TCP_NODELAY on upstream connections only, overriding --nodelay")
+                .takes_value(true)
+                .possible_values(&["true", "false"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no-keepalive")
+                .long("no-keepalive")
+                .help("Disables TCP keepalive on relayed sockets")
+                .conflicts_with_all(&["keepalive-idle", "keepalive-interval"]),
+        )
+        .arg(
+            Arg::with_name("auth")
+                .long("auth")
+                .value_name("user:pass")
+                .help("Offers username/password authentication to local clients")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("require-auth")
+                .long("require-auth")
+                .help("Refuses local clients that don't authenticate, SOCKS4 ones included")
+                .requires("auth"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("secs")
+                .help("Closes connections idle for this long")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("N")
+                .help("Refuses new connections while this many are open")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-conns-per-ip")
+                .long("max-conns-per-ip")
+                .value_name("N")
+                .help("Refuses new connections from an IP while it has this many open")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("event-capacity")
+                .long("event-capacity")
+                .value_name("N")
+                .help("Events handled per poll, grows when polls keep filling it [default: 1024]")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("secs")
+                .help("Gives up on remote proxies not answering the CONNECT in time")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-domain-length")
+                .long("max-domain-length")
+                .value_name("len")
+                .help("Rejects requested hostnames longer than this")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-request-size")
+                .long("max-request-size")
+                .value_name("bytes")
+                .help("Closes clients sending a larger handshake request, 65536 by default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("enforce-sni")
+                .long("enforce-sni")
+                .help("Closes tunnels whose TLS ClientHello names another host, TLS targets only"),
+        )
+        .arg(
+            Arg::with_name("enable-resolve")
+                .long("enable-resolve")
+                .help("Answers the non-standard SOCKS5 RESOLVE command (0xF0)"),
+        )
+        .arg(
+            Arg::with_name("max-domain-resolutions-cache")
+                .long("max-domain-resolutions-cache")
+                .value_name("N")
+                .help("Caches up to N resolved domains for the TTL of their answer")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max-pending-upstream")
+                .long("max-pending-upstream")
+                .value_name("N")
+                .help("Limits the upstream CONNECT handshakes in flight at once")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("advertise-addr")
+                .long("advertise-addr")
+                .value_name("ADDR")
+                .help("Sets ADDR, an IP with an optional port, reported by --bnd-mode advertise")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("bnd-mode")
+                .long("bnd-mode")
+                .value_name("mode")
+                .help("Sets what replies report as bound, zero (default), local or advertise")
+                .takes_value(true)
+                .possible_values(&["zero", "local", "advertise"])
+                .requires_if("advertise", "advertise-addr")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("send-proxy-protocol")
+                .long("send-proxy-protocol")
+                .help("Sends a PROXY protocol v1 header with the client address upstream"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .value_name("USER")
+                .help("Switches to this user once listening, Unix only")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .value_name("GROUP")
+                .help("Switches to this group once listening, defaults to the user's")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("http-absolute-form")
+                .long("http-absolute-form")
+                .help("Forwards HTTP requests to port 80 in absolute form instead of a CONNECT"),
+        )
+        .arg(
+            Arg::with_name("connect-header")
+                .long("connect-header")
+                .value_name("Name: Value")
+                .help("Adds a header to the CONNECT sent upstream, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("connect-host-style")
+                .long("connect-host-style")
+                .value_name("style")
+                .help("Sets the CONNECT Host header style, always-port or omit-default")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("upstream-http-version")
+                .long("upstream-http-version")
+                .value_name("version")
+                .help("Sets the HTTP version of the CONNECT, 1.1 or 1.0")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("path")
+                .help("Reads rules and upstreams from a JSON file, again on SIGHUP")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("admin-sock")
+                .long("admin-sock")
+                .value_name("path")
+                .help("Takes stats, list and kill <id> commands on a Unix socket")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("health-addr")
+                .long("health-addr")
+                .value_name("addr")
+                .help("Answers every TCP connection on this address with OK, for health checks")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .long("access-log")
+                .value_name("path")
+                .help("Writes an entry per completed connection to this file")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("access-log-format")
+                .long("access-log-format")
+                .value_name("format")
+                .help("Sets the format of the access log entries")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Checks that the upstream proxies are reachable and exits"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .value_name("host:port")
+                .help("Prints where a connection to the target would go and why, then exits")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("path")
+                .help("Appends the log to a file instead of stderr")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("syslog")
+                .long("syslog")
+                .help("Sends the log to syslog instead of stderr")
+                .conflicts_with("log-file"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("format")
+                .help("Sets the log format, text or json")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("level")
+                .help("Sets the log level or RUST_PROXYCHAIN_LOG style directives")
                 .takes_value(true)
                 .required(false),
         )
         .arg(
             Arg::with_name("v")
                 .short("v")
-                .help("Sets if enable verbose information"),
+                .multiple(true)
+                .help("Sets if enable verbose information, -vv adds relay throughput"),
+        )
+        .arg(
+            Arg::with_name("latency-report")
+                .long("latency-report")
+                .value_name("secs")
+                .help("Logs percentiles of connection durations and time to first byte this often")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("stats-interval")
+                .long("stats-interval")
+                .value_name("secs")
+                .help("Sets how often -vv logs the throughput of each relay, 5 by default")
+                .takes_value(true)
+                .required(false),
         )
         .get_matches();
 
-    match matches.occurrences_of("v") {
-        0 => {
-            env::set_var("RUST_PROXYCHAIN_LOG", "info");
-        }
-        _ => {
-            env::set_var("RUST_PROXYCHAIN_LOG", "debug");
-        }
+    // --log-level wins over -v, which wins over RUST_PROXYCHAIN_LOG
+    let spec = match (matches.value_of("log-level"), matches.occurrences_of("v")) {
+        (Some(level), _) => String::from(level),
+        (None, 0) => env::var("RUST_PROXYCHAIN_LOG").unwrap_or_else(|_| String::from("info")),
+        (None, _) => String::from("debug"),
+    };
+    let format = LogFormat::parse(matches.value_of("log-format").unwrap_or("text"))
+        .expect("Invalid log format");
+    let target = match matches.value_of("log-file") {
+        Some(path) => LogTarget::File(PathBuf::from(path)),
+        None if matches.is_present("syslog") => LogTarget::Syslog,
+        None => LogTarget::Stderr,
+    };
+    if target == LogTarget::Stderr && format == LogFormat::Text {
+        pretty_env_logger::formatted_builder()
+            .parse_filters(&spec)
+            .init();
+    } else {
+        logger::init(target, format, &spec).expect("Invalid log file");
     }
 
-    pretty_env_logger::init_custom_env("RUST_PROXYCHAIN_LOG");
+    let in_value = matches.value_of("in").expect("IN proxy needed");
+    let in_proxy = Proxy::parse(in_value);
+    // Clients are spoken SOCKS or plain HTTP to, anything else would be
+    // taken for one of them without a word
+    if in_proxy.protocol() == &ProxyProtocol::HTTPSProxy {
+        eprintln!(
+            "Unsupported IN proxy {}: only socks5:// and http:// can be listened on",
+            in_value
+        );
+        process::exit(1);
+    }
+    // -o wins over PROXYCHAIN_UPSTREAM, a comma-separated list of URLs
+    let out_values: Vec<String> = match matches.values_of("out") {
+        Some(values) => values.map(String::from).collect(),
+        None => env::var("PROXYCHAIN_UPSTREAM")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .collect(),
+    };
+    if out_values.is_empty() && !matches.is_present("config") {
+        panic!("OUT proxy needed");
+    }
+    let out_proxies: Vec<Proxy> = out_values.iter().map(|value| Proxy::parse(value)).collect();
+    if let Some(proxy) = out_proxies
+        .iter()
+        .find(|proxy| proxy.protocol() == &ProxyProtocol::HTTPSProxy)
+    {
+        eprintln!(
+            "Unsupported OUT proxy {}: https:// upstreams are not supported yet",
+            proxy.url()
+        );
+        process::exit(1);
+    }
+
+    let mut acl = AccessList::default();
+    for value in matches.values_of("allow").into_iter().flatten() {
+        acl.allow(Cidr::parse(value).expect("Invalid allow CIDR"));
+    }
+    for value in matches.values_of("deny").into_iter().flatten() {
+        acl.deny(Cidr::parse(value).expect("Invalid deny CIDR"));
+    }
+
+    let mut rules = DestinationRules::default();
+    for value in matches.values_of("block-domain").into_iter().flatten() {
+        rules.block_domain(value);
+    }
+    for value in matches.values_of("block-port").into_iter().flatten() {
+        rules.block_port(PortRange::parse(value).expect("Invalid blocked port range"));
+    }
+    for value in matches.values_of("block-cidr").into_iter().flatten() {
+        rules.block_cidr(Cidr::parse(value).expect("Invalid blocked CIDR"));
+    }
+    if matches.is_present("block-internal") {
+        rules.block_internal();
+    }
 
-    let in_proxy = Proxy::parse(matches.value_of("in").expect("IN proxy needed"));
-    let out_proxy = Proxy::parse(matches.value_of("out").expect("OUT proxy needed"));
+    let mut direct = DirectRules::default();
+    for value in matches.values_of("direct").into_iter().flatten() {
+        direct.add(value);
+    }
+    if matches.is_present("direct-loopback") {
+        direct.add_loopback();
+    }
 
+    let http_inbound = in_proxy.protocol() == &ProxyProtocol::HTTPProxy;
     let mut server = Socks5Server::new(in_proxy);
-    server.subproxy(out_proxy);
-    server.serve().unwrap();
+    if let Some(value) = matches.value_of("listen-backlog") {
+        server.backlog(value.parse().expect("Invalid listen backlog"));
+    }
+    server.acl(acl);
+    server.rules(rules);
+    server.direct(direct);
+    if let Some(value) = matches.value_of("upstream-retries") {
+        let retries: u32 = value.parse().expect("Invalid upstream retries");
+        let delay: u64 = matches
+            .value_of("upstream-retry-delay")
+            .unwrap_or("500")
+            .parse()
+            .expect("Invalid upstream retry delay");
+        server.upstream_retry(retries, Duration::from_millis(delay));
+    }
+    if matches.is_present("prefer-ipv4") {
+        server.prefer(IpFamily::V4);
+    } else if matches.is_present("prefer-ipv6") {
+        server.prefer(IpFamily::V6);
+    }
+    if let Some(value) = matches.value_of("dns") {
+        let protocol = matches.value_of("dns-protocol").unwrap_or("udp");
+        let protocol = DnsProtocol::parse(protocol).expect("Invalid DNS protocol");
+        let addr = match value.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(value.parse::<IpAddr>().expect("Invalid DNS server"), 53),
+        };
+        server.dns(addr, protocol);
+    }
+    if let Some(value) = matches.value_of("bind-outbound") {
+        server.outbound(Outbound::parse(value).expect("Invalid outbound address"));
+    }
+    if matches.is_present("no-keepalive") {
+        server.keepalive(None);
+    } else {
+        let mut keepalive = Keepalive::default();
+        if let Some(value) = matches.value_of("keepalive-idle") {
+            let secs: u64 = value.parse().expect("Invalid keepalive idle");
+            keepalive.idle = Duration::from_secs(secs);
+        }
+        if let Some(value) = matches.value_of("keepalive-interval") {
+            let secs: u64 = value.parse().expect("Invalid keepalive interval");
+            keepalive.interval = Duration::from_secs(secs);
+        }
+        server.keepalive(Some(keepalive));
+    }
+    let nodelay = |flag: &str| {
+        matches
+            .value_of(flag)
+            .or_else(|| matches.value_of("nodelay"))
+            != Some("false")
+    };
+    server.nodelay(nodelay("inbound-nodelay"), nodelay("outbound-nodelay"));
+    // Any of the breaker flags turns it on, the others keep their defaults
+    let breaker_flags = ["breaker-failures", "breaker-window", "breaker-cooldown"];
+    if breaker_flags.iter().any(|flag| matches.is_present(flag)) {
+        let mut breaker = BreakerConfig::default();
+        if let Some(value) = matches.value_of("breaker-failures") {
+            breaker.failures = value.parse().expect("Invalid breaker failures");
+        }
+        if let Some(value) = matches.value_of("breaker-window") {
+            let secs: u64 = value.parse().expect("Invalid breaker window");
+            breaker.window = Duration::from_secs(secs);
+        }
+        if let Some(value) = matches.value_of("breaker-cooldown") {
+            let secs: u64 = value.parse().expect("Invalid breaker cooldown");
+            breaker.cooldown = Duration::from_secs(secs);
+        }
+        server.breaker(breaker);
+    }
+    if let Some(value) = matches.value_of("auth") {
+        if http_inbound {
+            eprintln!("--auth is not supported for HTTP clients yet");
+            process::exit(1);
+        }
+        let (username, password) = value.split_once(':').expect("Invalid auth credentials");
+        server.auth(username, password);
+    }
+    if matches.is_present("require-auth") {
+        server.require_auth(true);
+    }
+    if let Some(value) = matches.value_of("idle-timeout") {
+        let secs: u64 = value.parse().expect("Invalid idle timeout");
+        server.idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(value) = matches.value_of("latency-report") {
+        let secs: u64 = value.parse().expect("Invalid latency report interval");
+        server.latency_report(Duration::from_secs(secs.max(1)));
+    }
+    if matches.occurrences_of("v") >= 2 {
+        let secs: u64 = matches
+            .value_of("stats-interval")
+            .unwrap_or("5")
+            .parse()
+            .expect("Invalid stats interval");
+        server.throughput_interval(Duration::from_secs(secs.max(1)));
+    }
+    if let Some(value) = matches.value_of("max-connections") {
+        server.max_connections(value.parse().expect("Invalid max connections"));
+    }
+    if let Some(value) = matches.value_of("max-conns-per-ip") {
+        server.max_conns_per_ip(value.parse().expect("Invalid max connections per IP"));
+    }
+    if let Some(value) = matches.value_of("event-capacity") {
+        server.event_capacity(value.parse().expect("Invalid event capacity"));
+    }
+    if let Some(value) = matches.value_of("connect-timeout") {
+        let secs: u64 = value.parse().expect("Invalid connect timeout");
+        server.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(value) = matches.value_of("max-domain-resolutions-cache") {
+        server.dns_cache(value.parse().expect("Invalid DNS cache size"));
+    }
+    if let Some(value) = matches.value_of("max-pending-upstream") {
+        server.max_pending_upstream(value.parse().expect("Invalid max pending upstream"));
+    }
+    if let Some(value) = matches.value_of("max-domain-length") {
+        server.max_domain_len(value.parse().expect("Invalid max domain length"));
+    }
+    if let Some(value) = matches.value_of("max-request-size") {
+        server.max_request_size(value.parse().expect("Invalid max request size"));
+    }
+    if matches.is_present("enforce-sni") {
+        server.enforce_sni(true);
+    }
+    if matches.is_present("enable-resolve") {
+        server.enable_resolve(true);
+    }
+    if let Some(value) = matches.value_of("advertise-addr") {
+        let addr = value
+            .parse::<SocketAddr>()
+            .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+            .expect("Invalid advertised address");
+        server.advertise_addr(addr);
+    }
+    if let Some(value) = matches.value_of("bnd-mode") {
+        server.bnd_mode(BndMode::parse(value).expect("Invalid BND mode"));
+    }
+    if matches.is_present("send-proxy-protocol") {
+        server.send_proxy_protocol(true);
+    }
+    if let Some(value) = matches.value_of("user") {
+        server.user(value);
+    }
+    if let Some(value) = matches.value_of("group") {
+        server.group(value);
+    }
+    if matches.is_present("http-absolute-form") {
+        server.http_absolute_form(true);
+    }
+    for value in matches.values_of("connect-header").into_iter().flatten() {
+        let (name, value) = parse_header(value).expect("Invalid CONNECT header");
+        server.connect_header(name, value);
+    }
+    if let Some(value) = matches.value_of("connect-host-style") {
+        server.connect_host_style(HostStyle::parse(value).expect("Invalid connect host style"));
+    }
+    if let Some(value) = matches.value_of("upstream-http-version") {
+        server.upstream_http_version(
+            HttpVersion::parse(value).expect("Invalid upstream HTTP version"),
+        );
+    }
+    if let Some(path) = matches.value_of("config") {
+        server.config_file(PathBuf::from(path));
+    }
+    if let Some(path) = matches.value_of("admin-sock") {
+        server.admin_socket(PathBuf::from(path));
+    }
+    if let Some(value) = matches.value_of("health-addr") {
+        server.health_addr(value.parse().expect("Invalid health check address"));
+    }
+    if matches.is_present("access-log") || matches.is_present("access-log-format") {
+        let format =
+            AccessLogFormat::parse(matches.value_of("access-log-format").unwrap_or("text"))
+                .expect("Invalid access log format");
+        server.access_log(matches.value_of("access-log").map(PathBuf::from), format);
+    }
+    if matches.value_of("balance") == Some("round-robin") {
+        server.selector(Box::new(RoundRobin::new(out_proxies.clone())));
+    }
+    if matches.is_present("chain") {
+        server.chain(true);
+    }
+    for proxy in out_proxies {
+        server.subproxy(proxy);
+    }
+    if matches.is_present("buffer-size") || matches.is_present("max-buffer") {
+        let initial: usize = matches
+            .value_of("buffer-size")
+            .unwrap_or("4096")
+            .parse()
+            .expect("Invalid buffer size");
+        let max: usize = matches
+            .value_of("max-buffer")
+            .unwrap_or("1048576")
+            .parse()
+            .expect("Invalid max buffer");
+        if initial == 0 || max < initial {
+            panic!("Buffer size must be non-zero and at most the max buffer");
+        }
+        server.buffer(initial, max);
+    }
+    if let Some(value) = matches.value_of("rate-limit") {
+        let rate: u64 = value.parse().expect("Invalid rate limit");
+        if rate == 0 {
+            panic!("Rate limit must be greater than zero");
+        }
+        server.rate_limit(rate);
+    }
+    if matches.is_present("check") {
+        let reachable = server.check(Duration::from_secs(5));
+        process::exit(if reachable { 0 } else { 1 });
+    }
+    if let Some(value) = matches.value_of("explain") {
+        let target = explain_target(value);
+        let resolved = match target.candidates.first() {
+            Some(addr) => addr.ip().to_string(),
+            None => String::from("unresolved"),
+        };
+        println!(
+            "{}:{} ({}): {}",
+            target.host(),
+            target.port,
+            resolved,
+            server.explain(&target)
+        );
+        process::exit(0);
+    }
+    if let Err(err) = server.serve() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+// Resolves with the system resolver, CIDR rules only ever match an address.
+fn explain_target(value: &str) -> Target {
+    let (host, port) = value.rsplit_once(':').expect("Invalid explain target");
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port: u16 = port.parse().expect("Invalid explain target");
+    let mut target = Target::new();
+    target.domain = String::from(host);
+    let ips: Vec<IpAddr> = match host.parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+            .unwrap_or_default(),
+    };
+    target.set_candidates(&ips, port, None);
+    target
 }
@@ -0,0 +1,71 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use mio::net::{TcpSocket, TcpStream};
+
+// Local side outgoing connections are made from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outbound {
+    Addr(IpAddr),
+    Device(String),
+}
+
+impl Outbound {
+    // Takes either a local IP or an interface name.
+    pub fn parse(value: &str) -> Option<Self> {
+        if let Ok(ip) = value.parse() {
+            return Some(Outbound::Addr(ip));
+        }
+        // Interface names are limited to IFNAMSIZ including the nul byte
+        if value.is_empty() || value.len() >= 16 || value.contains(['/', ' ', '\0']) {
+            return None;
+        }
+        Some(Outbound::Device(String::from(value)))
+    }
+}
+
+// Starts a non-blocking connect to `addr`, from the given local side if any.
+pub fn connect(addr: SocketAddr, outbound: Option<&Outbound>) -> io::Result<TcpStream> {
+    let outbound = match outbound {
+        Some(outbound) => outbound,
+        None => return TcpStream::connect(addr),
+    };
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    match outbound {
+        Outbound::Addr(ip) => socket.bind(SocketAddr::new(*ip, 0))?,
+        Outbound::Device(name) => bind_device(&socket, name)?,
+    }
+    socket.connect(addr)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &TcpSocket, name: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the name is passed with its length and only read by the kernel.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &TcpSocket, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
@@ -0,0 +1,89 @@
+use std::ffi::CString;
+use std::io;
+
+// Switches the process to `user` and `group` once the listener is bound,
+// by name or numeric id. The group defaults to the primary group of the
+// user and is changed first, since that takes root.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let user = match user {
+        Some(name) => Some(lookup_user(name)?),
+        None => None,
+    };
+    let gid = match (group, user) {
+        (Some(name), _) => Some(lookup_group(name)?),
+        (None, Some((_, gid))) => Some(gid),
+        (None, None) => None,
+    };
+
+    if let Some(gid) = gid {
+        // SAFETY: plain syscalls, the group list is a single valid gid.
+        unsafe {
+            // Only root can clear the supplementary groups
+            if libc::geteuid() == 0 && libc::setgroups(1, &gid) == -1 {
+                return Err(failure("supplementary groups", gid));
+            }
+            if libc::setgid(gid) == -1 {
+                return Err(failure("group", gid));
+            }
+        }
+    }
+    if let Some((uid, _)) = user {
+        // SAFETY: plain syscalls.
+        unsafe {
+            if libc::setuid(uid) == -1 {
+                return Err(failure("user", uid));
+            }
+            // Regaining root must be impossible now
+            if uid != 0 && libc::setuid(0) != -1 {
+                return Err(io::Error::other(
+                    "Privileges could be regained after dropping them",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let cname = CString::new(name).map_err(|_| unknown("user", name))?;
+    // SAFETY: getpwnam returns null or a valid entry, which is read before
+    // any other lookup could overwrite it.
+    let entry = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) });
+    }
+    let uid = name.parse().map_err(|_| unknown("user", name))?;
+    // SAFETY: as above.
+    let entry = unsafe { libc::getpwuid(uid) };
+    let gid = if entry.is_null() {
+        uid
+    } else {
+        unsafe { (*entry).pw_gid }
+    };
+    Ok((uid, gid))
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let cname = CString::new(name).map_err(|_| unknown("group", name))?;
+    // SAFETY: getgrnam returns null or a valid entry, read right away.
+    let entry = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    name.parse().map_err(|_| unknown("group", name))
+}
+
+fn unknown(kind: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Failed to drop privileges: unknown {} {}", kind, name),
+    )
+}
+
+fn failure(what: &str, id: u32) -> io::Error {
+    let err = io::Error::last_os_error();
+    io::Error::new(
+        err.kind(),
+        format!("Failed to switch to {} {}: {}", what, id, err),
+    )
+}
@@ -1,11 +1,19 @@
-use std::net::SocketAddr;
+use log::{info, warn};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 use url::Url;
 
-#[derive(Debug, Clone)]
+use crate::dns::{lookup_srv, order_srv, random_up_to, SrvRecord};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProxyProtocol {
     HTTPProxy,
+    HTTPSProxy,
     SOCKS5Proxy,
+    // SOCKS5 leaving hostnames to the proxy to resolve, as curl's socks5h.
+    SOCKS5hProxy,
 }
 
 #[derive(Debug, Clone)]
@@ -17,35 +25,121 @@ pub struct Proxy {
     username: Option<String>,
     password: Option<String>,
     pub addr: SocketAddr,
+    pub addrs: Vec<SocketAddr>,
 }
 
 impl Proxy {
+    // A scheme ending in `+srv`, e.g. `http+srv://_proxy._tcp.example.com`,
+    // takes the proxies from the SRV records of the host.
     pub fn parse(value: &str) -> Self {
-        let url = Url::parse(value).expect("Invalid proxy URL");
-        let protocol = match url.scheme() {
+        Self::try_parse(value).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    // Like `parse`, an invalid or unresolvable URL is an error instead.
+    pub fn try_parse(value: &str) -> Result<Self, String> {
+        let url = Url::parse(value).map_err(|err| invalid_url(value, err))?;
+        let records = if url.scheme().ends_with("+srv") {
+            let name = url
+                .host_str()
+                .ok_or_else(|| invalid_url(value, "no host"))?;
+            match lookup_srv(name) {
+                Ok(records) => order_srv(records, random_up_to),
+                Err(err) => {
+                    warn!("Failed to look up the SRV records of {}: {}", name, err);
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        Self::try_discovered(value, &records)
+    }
+
+    // Proxy of a URL whose `+srv` records were already looked up and ordered,
+    // the first one is the proxy, the others back it up. Without any the
+    // name stripped of its service labels is used on the default port.
+    pub fn discovered(value: &str, records: &[SrvRecord]) -> Self {
+        Self::try_discovered(value, records).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn try_discovered(value: &str, records: &[SrvRecord]) -> Result<Self, String> {
+        let url = Url::parse(value).map_err(|err| invalid_url(value, err))?;
+        let (scheme, srv) = match url.scheme().strip_suffix("+srv") {
+            Some(scheme) => (scheme, true),
+            None => (url.scheme(), false),
+        };
+        let protocol = match scheme {
             "http" => ProxyProtocol::HTTPProxy,
+            "https" => ProxyProtocol::HTTPSProxy,
             "socks" | "socks5" => ProxyProtocol::SOCKS5Proxy,
-            _ => {
-                panic!("Invalid proxy scheme")
-            }
+            "socks5h" => ProxyProtocol::SOCKS5hProxy,
+            _ => return Err(format!("Invalid proxy scheme {}", scheme)),
         };
-        let host = String::from(url.host_str().expect("Invalid proxy URL"));
-        let port = match url.port() {
+        let mut host = String::from(
+            url.host_str()
+                .ok_or_else(|| invalid_url(value, "no host"))?,
+        );
+        let mut port = match url.port() {
             Some(u) => u,
             None => match protocol {
                 ProxyProtocol::HTTPProxy => 80,
-                ProxyProtocol::SOCKS5Proxy => 1080,
+                ProxyProtocol::HTTPSProxy => 443,
+                ProxyProtocol::SOCKS5Proxy | ProxyProtocol::SOCKS5hProxy => 1080,
             },
         };
+        let unresolved =
+            |err: io::Error| format!("Failed to resolve proxy host {}: {}", value, err);
+        let mut addrs = Vec::new();
+        if srv {
+            let name = host.clone();
+            for record in records {
+                match (record.target.as_str(), record.port).to_socket_addrs() {
+                    Ok(found) => addrs.extend(found),
+                    Err(err) => warn!("Failed to resolve SRV target {}: {}", record.target, err),
+                }
+            }
+            match records.first() {
+                Some(record) => {
+                    host = record.target.clone();
+                    port = record.port;
+                    info!("Discovered proxy {}:{} for {}", host, port, name);
+                }
+                None => {
+                    host = name
+                        .split('.')
+                        .skip_while(|label| label.starts_with('_'))
+                        .collect::<Vec<_>>()
+                        .join(".");
+                    warn!("No SRV record for {}, using {}:{}", name, host, port);
+                    addrs = (host.as_str(), port)
+                        .to_socket_addrs()
+                        .map_err(unresolved)?
+                        .collect();
+                }
+            }
+        }
         let username = if url.username().is_empty() {
             None
         } else {
             Some(String::from(url.username()))
         };
         let password = url.password().map(String::from);
+        // Mismatches are worth a warning only, proxies listen where they like.
+        match (&protocol, port) {
+            (ProxyProtocol::HTTPProxy, 443) => {
+                warn!("Proxy {} uses port 443 over plain HTTP", value)
+            }
+            (ProxyProtocol::HTTPSProxy, 80) => warn!("Proxy {} uses port 80 over HTTPS", value),
+            _ => {}
+        }
+        if !srv {
+            addrs = url.socket_addrs(|| Some(port)).map_err(unresolved)?;
+        }
+        let addr = *addrs
+            .first()
+            .ok_or_else(|| format!("Failed to resolve proxy host {}: no address", value))?;
         let url = String::from(value);
-        let addr: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
-        Self {
+        Ok(Self {
             protocol,
             url,
             host,
@@ -53,6 +147,29 @@ impl Proxy {
             username,
             password,
             addr,
-        }
+            addrs,
+        })
+    }
+
+    #[inline]
+    pub fn protocol(&self) -> &ProxyProtocol {
+        &self.protocol
+    }
+
+    #[inline]
+    pub fn url(&self) -> &str {
+        &self.url
     }
+
+    // Username and password given in the URL, the password may be absent.
+    #[inline]
+    pub fn credentials(&self) -> Option<(&str, Option<&str>)> {
+        self.username
+            .as_deref()
+            .map(|username| (username, self.password.as_deref()))
+    }
+}
+
+fn invalid_url(value: &str, err: impl fmt::Display) -> String {
+    format!("Invalid proxy URL {}: {}", value, err)
 }
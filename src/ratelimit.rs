@@ -0,0 +1,61 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+const REFILL_CHUNK: u64 = 4096;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: u64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    // Number of bytes that may be moved right now.
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens as usize
+    }
+
+    pub fn consume(&mut self, amount: usize) {
+        self.tokens = self.tokens.saturating_sub(amount as u64);
+    }
+
+    // Time until a reasonably sized chunk can be moved again.
+    pub fn wait_time(&mut self) -> Duration {
+        self.refill();
+        if self.tokens > 0 || self.rate == 0 {
+            return Duration::from_millis(0);
+        }
+        let chunk = cmp::min(self.capacity, REFILL_CHUNK);
+        Duration::from_nanos(chunk * 1_000_000_000 / self.rate)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if self.tokens >= self.capacity {
+            self.last = now;
+            return;
+        }
+        let elapsed = now.duration_since(self.last).as_nanos();
+        let gained = elapsed * self.rate as u128 / 1_000_000_000;
+        if gained >= (self.capacity - self.tokens) as u128 {
+            self.tokens = self.capacity;
+            self.last = now;
+        } else if gained > 0 {
+            let gained = gained as u64;
+            self.tokens += gained;
+            self.last += Duration::from_nanos(gained * 1_000_000_000 / self.rate);
+        }
+    }
+}
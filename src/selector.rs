@@ -0,0 +1,160 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::acl::DirectRules;
+use crate::datatype::Target;
+use crate::proxy::Proxy;
+
+// Picks the upstream proxy a connection is chained through.
+pub trait UpstreamSelector {
+    fn select(&self, target: &Target, peer: SocketAddr) -> Option<&Proxy>;
+
+    // What `select` would pick and why, without counting as a connection.
+    fn explain(&self, target: &Target, peer: SocketAddr) -> Option<(&Proxy, String)> {
+        self.select(target, peer)
+            .map(|proxy| (proxy, String::from("picked by the selector")))
+    }
+
+    // Every proxy the selector may return.
+    fn proxies(&self) -> Vec<&Proxy>;
+
+    // Whether the handshake with a proxy handed out by `select` succeeded.
+    fn report(&self, _proxy: &Proxy, _ok: bool) {}
+
+    // The same kind of selector over another list of proxies, for a reload.
+    // None when it can't be built from a list and has to stay as it is.
+    fn with_proxies(&self, _proxies: Vec<Proxy>) -> Option<Box<dyn UpstreamSelector>> {
+        None
+    }
+}
+
+// Always the first proxy of the list.
+pub struct FirstAvailable {
+    proxies: Vec<Proxy>,
+}
+
+impl FirstAvailable {
+    pub fn new(proxies: Vec<Proxy>) -> Self {
+        Self { proxies }
+    }
+}
+
+impl UpstreamSelector for FirstAvailable {
+    fn select(&self, _target: &Target, _peer: SocketAddr) -> Option<&Proxy> {
+        self.proxies.first()
+    }
+
+    fn explain(&self, _target: &Target, _peer: SocketAddr) -> Option<(&Proxy, String)> {
+        self.proxies
+            .first()
+            .map(|proxy| (proxy, String::from("first upstream")))
+    }
+
+    fn proxies(&self) -> Vec<&Proxy> {
+        self.proxies.iter().collect()
+    }
+
+    fn with_proxies(&self, proxies: Vec<Proxy>) -> Option<Box<dyn UpstreamSelector>> {
+        Some(Box::new(FirstAvailable::new(proxies)))
+    }
+}
+
+// Spreads connections over the proxies in turn.
+pub struct RoundRobin {
+    proxies: Vec<Proxy>,
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new(proxies: Vec<Proxy>) -> Self {
+        Self {
+            proxies,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl UpstreamSelector for RoundRobin {
+    fn select(&self, _target: &Target, _peer: SocketAddr) -> Option<&Proxy> {
+        if self.proxies.is_empty() {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.proxies.get(i % self.proxies.len())
+    }
+
+    // The turn is only peeked at, the next connection may get another one.
+    fn explain(&self, _target: &Target, _peer: SocketAddr) -> Option<(&Proxy, String)> {
+        if self.proxies.is_empty() {
+            return None;
+        }
+        let i = self.next.load(Ordering::Relaxed) % self.proxies.len();
+        Some((
+            &self.proxies[i],
+            format!("round-robin, upstream {} of {}", i + 1, self.proxies.len()),
+        ))
+    }
+
+    fn proxies(&self) -> Vec<&Proxy> {
+        self.proxies.iter().collect()
+    }
+
+    // The turn starts over at the first proxy.
+    fn with_proxies(&self, proxies: Vec<Proxy>) -> Option<Box<dyn UpstreamSelector>> {
+        Some(Box::new(RoundRobin::new(proxies)))
+    }
+}
+
+// Sends targets matching a rule to its proxy, the first matching rule wins
+// and anything else is left to the fallback selector.
+pub struct RuleBased {
+    rules: Vec<(DirectRules, Proxy)>,
+    fallback: Box<dyn UpstreamSelector>,
+}
+
+impl RuleBased {
+    pub fn new(fallback: Box<dyn UpstreamSelector>) -> Self {
+        Self {
+            rules: Vec::new(),
+            fallback,
+        }
+    }
+
+    pub fn route(&mut self, rules: DirectRules, proxy: Proxy) {
+        self.rules.push((rules, proxy));
+    }
+}
+
+impl UpstreamSelector for RuleBased {
+    fn select(&self, target: &Target, peer: SocketAddr) -> Option<&Proxy> {
+        match self.rules.iter().find(|(rules, _)| rules.matches(target)) {
+            Some((_, proxy)) => Some(proxy),
+            None => self.fallback.select(target, peer),
+        }
+    }
+
+    fn explain(&self, target: &Target, peer: SocketAddr) -> Option<(&Proxy, String)> {
+        for (i, (rules, proxy)) in self.rules.iter().enumerate() {
+            if let Some(matched) = rules.matching(target) {
+                return Some((proxy, format!("rule {} matched {}", i + 1, matched)));
+            }
+        }
+        self.fallback
+            .explain(target, peer)
+            .map(|(proxy, reason)| (proxy, format!("no rule matched, {}", reason)))
+    }
+
+    fn proxies(&self) -> Vec<&Proxy> {
+        let mut proxies: Vec<&Proxy> = self.rules.iter().map(|(_, proxy)| proxy).collect();
+        proxies.extend(self.fallback.proxies());
+        proxies
+    }
+
+    // The rules keep their proxies, only the fallback gets the new list.
+    fn with_proxies(&self, proxies: Vec<Proxy>) -> Option<Box<dyn UpstreamSelector>> {
+        Some(Box::new(RuleBased {
+            rules: self.rules.clone(),
+            fallback: self.fallback.with_proxies(proxies)?,
+        }))
+    }
+}
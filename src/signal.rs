@@ -0,0 +1,101 @@
+use log::error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::io::FromRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::thread;
+
+use mio::Waker;
+
+// Write end of the pipe the SIGHUP handler signals through, -1 until the
+// handler is installed.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+static INSTALL: Once = Once::new();
+// Flag and waker of every poll that asked to hear of a SIGHUP.
+static WATCHERS: Mutex<Vec<(Arc<AtomicBool>, Arc<Waker>)>> = Mutex::new(Vec::new());
+
+// Sets `flag` and wakes the poll of `waker` on every SIGHUP. The handler
+// only writes a byte to a pipe, a thread reading it does the rest outside
+// of the signal context.
+pub fn on_hangup(flag: Arc<AtomicBool>, waker: Arc<Waker>) -> io::Result<()> {
+    let mut installed = Ok(());
+    INSTALL.call_once(|| installed = install());
+    installed?;
+    if PIPE.load(Ordering::Relaxed) < 0 {
+        return Err(io::Error::other(
+            "the SIGHUP handler could not be installed",
+        ));
+    }
+    lock().push((flag, waker));
+    Ok(())
+}
+
+fn install() -> io::Result<()> {
+    let mut fds = [0 as c_int; 2];
+    // SAFETY: `fds` has room for both ends of the pipe. The handler must
+    // never block on a full pipe, its end doesn't.
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) == -1
+            || libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // SAFETY: the read end was just opened and is owned by nothing else.
+    let pipe = unsafe { File::from_raw_fd(fds[0]) };
+    thread::Builder::new()
+        .name(String::from("signal"))
+        .spawn(move || watch(pipe))?;
+    PIPE.store(fds[1], Ordering::Relaxed);
+
+    // SAFETY: a zeroed sigaction is valid, the handler only calls write(2),
+    // which is async-signal-safe.
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sighup as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, ptr::null_mut()) == -1 {
+            PIPE.store(-1, Ordering::Relaxed);
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn on_sighup(_: c_int) {
+    let fd = PIPE.load(Ordering::Relaxed);
+    // SAFETY: `fd` is the write end of the pipe, which is never closed. A
+    // full pipe drops the byte, a wake-up is pending anyway.
+    unsafe {
+        libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+    }
+}
+
+fn watch(mut pipe: File) {
+    let mut byte = [0u8; 1];
+    loop {
+        if let Err(err) = pipe.read_exact(&mut byte) {
+            error!("Stopped watching for SIGHUP: {}", err);
+            return;
+        }
+        for (flag, waker) in lock().iter() {
+            flag.store(true, Ordering::Relaxed);
+            if let Err(err) = waker.wake() {
+                error!("Failed to wake up the event loop: {}", err);
+            }
+        }
+    }
+}
+
+// The list holds no invariant a panicking holder could break.
+fn lock() -> MutexGuard<'static, Vec<(Arc<AtomicBool>, Arc<Waker>)>> {
+    match WATCHERS.lock() {
+        Ok(watchers) => watchers,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use log::{debug, info, warn};
+use mio::net::{UnixListener, UnixStream};
+use mio::{Interest, Registry, Token};
+use slab::Slab;
+
+// Admin sessions held at once, each takes a token right after the listener.
+pub const ADMIN_SESSIONS: usize = 8;
+
+// One line sent to the admin socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Stats,
+    List,
+    Kill(usize),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("stats"), None, _) => Ok(Command::Stats),
+            (Some("list"), None, _) => Ok(Command::List),
+            (Some("kill"), Some(id), None) => id
+                .parse()
+                .map(Command::Kill)
+                .map_err(|_| format!("invalid connection id {}", id)),
+            _ => Err(format!("unknown command {}", line.trim())),
+        }
+    }
+}
+
+struct Session {
+    stream: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    eof: bool,
+}
+
+impl Session {
+    fn read(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 512];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(());
+                }
+                Ok(n) => self.input.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Line based control channel on a Unix socket. The listener sits on `base`
+// and sessions on the ADMIN_SESSIONS tokens after it; the server answers
+// the commands since only it knows the connections.
+pub struct Admin {
+    listener: UnixListener,
+    base: Token,
+    sessions: Slab<Session>,
+}
+
+impl Admin {
+    pub fn bind(path: &Path, registry: &Registry, base: Token) -> io::Result<Self> {
+        // A socket left behind by an earlier run would fail the bind
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let mut listener = UnixListener::bind(path)?;
+        registry.register(&mut listener, base, Interest::READABLE)?;
+        info!("Admin socket listening on {}", path.display());
+        Ok(Self {
+            listener,
+            base,
+            sessions: Slab::with_capacity(ADMIN_SESSIONS),
+        })
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        token.0 >= self.base.0 && token.0 <= self.base.0 + ADMIN_SESSIONS
+    }
+
+    // Handles an event of the admin range and returns the commands it
+    // completed, answered through `reply` and sent by `flush`.
+    pub fn handle(&mut self, token: Token, registry: &Registry) -> Vec<Result<Command, String>> {
+        if token == self.base {
+            self.accept(registry);
+            return Vec::new();
+        }
+        let session = match self.sessions.get_mut(token.0 - self.base.0 - 1) {
+            Some(session) => session,
+            None => return Vec::new(),
+        };
+        if let Err(err) = session.read() {
+            debug!("Admin session read failed: {}", err);
+            session.eof = true;
+        }
+        let mut commands = Vec::new();
+        while let Some(i) = session.input.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = session.input.drain(..=i).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                commands.push(Command::parse(&line));
+            }
+        }
+        commands
+    }
+
+    pub fn reply(&mut self, token: Token, text: &str) {
+        if let Some(session) = self.sessions.get_mut(token.0 - self.base.0 - 1) {
+            session.output.extend_from_slice(text.as_bytes());
+        }
+    }
+
+    // Writes what the socket takes, a session is closed once its peer is
+    // done and every answer went out.
+    pub fn flush(&mut self, token: Token, registry: &Registry) {
+        if token == self.base {
+            return;
+        }
+        let key = token.0 - self.base.0 - 1;
+        let session = match self.sessions.get_mut(key) {
+            Some(session) => session,
+            None => return,
+        };
+        let done = match session.write() {
+            Ok(()) => session.eof && session.output.is_empty(),
+            Err(err) => {
+                debug!("Admin session write failed: {}", err);
+                true
+            }
+        };
+        if done {
+            let mut session = self.sessions.remove(key);
+            if let Err(err) = registry.deregister(&mut session.stream) {
+                debug!("Admin session deregister failed: {}", err);
+            }
+        }
+    }
+
+    fn accept(&mut self, registry: &Registry) {
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    warn!("Failed to accept admin connection: {}", err);
+                    return;
+                }
+            };
+            if self.sessions.len() >= ADMIN_SESSIONS {
+                warn!(
+                    "Rejected admin connection, {} sessions open",
+                    ADMIN_SESSIONS
+                );
+                continue;
+            }
+            let entry = self.sessions.vacant_entry();
+            let token = Token(self.base.0 + 1 + entry.key());
+            if let Err(err) = registry.register(
+                &mut stream,
+                token,
+                Interest::READABLE.add(Interest::WRITABLE),
+            ) {
+                warn!("Failed to set up admin connection: {}", err);
+                continue;
+            }
+            entry.insert(Session {
+                stream,
+                input: Vec::new(),
+                output: Vec::new(),
+                eof: false,
+            });
+        }
+    }
+}
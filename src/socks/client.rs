@@ -0,0 +1,252 @@
+use log::debug;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use mio::event::Event;
+use mio::net::TcpStream;
+use mio::{Registry, Token};
+
+use crate::datatype::Target;
+use crate::error::Step;
+use crate::keepalive::Keepalive;
+use crate::outbound::Outbound;
+use crate::proxy::Proxy;
+use crate::transport::Transport;
+use crate::upstream::UpstreamClient;
+
+use super::client_protocol::{
+    auth_response, connection_response, method_request, method_response, relay_in, relay_out,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Socks5ClientState {
+    MethodRequest,
+    MethodResponse,
+    AuthResponse,
+    ConnectionResponse,
+    RelayingIN,
+    RelayingOUT,
+    Closed,
+}
+
+// Tunnels through an upstream SOCKS5 proxy, authenticating with the
+// username and password of its URL (RFC 1929) when it asks for them.
+pub struct Socks5Client {
+    pub id: usize,
+    pub remote: Proxy,
+    pub target: Target,
+    pub(crate) transport: Transport,
+    max_buffer: usize,
+    pub state: Socks5ClientState,
+    pub reply: Option<u8>,
+    // The proxy asked for credentials that were missing or wrong.
+    pub unauthorized: bool,
+}
+
+impl Socks5Client {
+    pub fn new(
+        id: usize,
+        remote: Proxy,
+        target: Target,
+        buffer_size: usize,
+        max_buffer: usize,
+    ) -> Self {
+        Self {
+            id,
+            remote,
+            target,
+            transport: Transport::new(id, "SOCKS5 proxy", buffer_size),
+            max_buffer,
+            state: Socks5ClientState::MethodRequest,
+            reply: None,
+            unauthorized: false,
+        }
+    }
+
+    pub fn read_buffer(&mut self) -> io::Result<Step> {
+        self.read_buffer_up_to(self.max_buffer)
+    }
+
+    pub fn set_outbound(&mut self, outbound: Option<Outbound>) {
+        self.transport.set_outbound(outbound);
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.transport.set_keepalive(keepalive);
+    }
+
+    // Nagle's algorithm stays off unless this is false.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.transport.set_nodelay(nodelay);
+    }
+
+    // Bounds the time from connecting to the reply of the CONNECT.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.transport.set_connect_timeout(timeout);
+    }
+
+    pub fn set_retry(&mut self, retries: u32, delay: Duration) {
+        self.transport.set_retry(retries, delay);
+    }
+
+    #[inline]
+    pub fn set_state(&mut self, state: Socks5ClientState) {
+        self.state = state;
+    }
+
+    // Queues a handshake message behind any unsent tail, leaving what was
+    // read so far in the buffer.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<Step> {
+        let step = self.transport.send(msg)?;
+        Ok(self.closing(step))
+    }
+
+    // Drops the first `n` bytes of the buffer, a parsed handshake message.
+    pub fn consume(&mut self, n: usize) {
+        self.transport.consume(n);
+    }
+
+    fn closing(&mut self, step: Step) -> Step {
+        if step == Step::Close {
+            self.set_state(Socks5ClientState::Closed);
+        }
+        step
+    }
+}
+
+impl UpstreamClient for Socks5Client {
+    fn token(&self) -> Option<Token> {
+        self.transport.token
+    }
+
+    fn established(&self) -> bool {
+        false
+    }
+
+    fn reply(&self) -> Option<u8> {
+        self.reply
+    }
+
+    fn last_error(&self) -> Option<io::ErrorKind> {
+        self.transport.last_error
+    }
+
+    fn buffer(&self) -> &BytesMut {
+        &self.transport.buffer
+    }
+
+    fn size(&self) -> usize {
+        self.transport.size
+    }
+
+    fn handle(&mut self, event: &Event, value: Option<&mut BytesMut>) -> io::Result<Step> {
+        debug!(
+            "[#{}] SOCKS5 Client state: {:?}, readable: {}, writeable: {}",
+            self.id,
+            self.state,
+            event.is_readable(),
+            event.is_writable()
+        );
+
+        let result = match self.state {
+            Socks5ClientState::MethodRequest => method_request(self),
+            Socks5ClientState::MethodResponse => method_response(self),
+            Socks5ClientState::AuthResponse => auth_response(self),
+            Socks5ClientState::ConnectionResponse => connection_response(self),
+            Socks5ClientState::RelayingOUT => match value {
+                Some(buffer) => {
+                    self.transport.swap_buffer(buffer);
+                    relay_out(self)
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no buffer to relay",
+                )),
+            },
+            Socks5ClientState::RelayingIN => {
+                let step = relay_in(self)?;
+                if self.transport.size == 0 && step == Step::Close {
+                    return Ok(Step::Close);
+                }
+                Ok(Step::Yield)
+            }
+            _ => Ok(Step::Yield),
+        };
+        match result {
+            Ok(Step::Close) | Err(_) => Ok(Step::Close),
+            Ok(step) => Ok(step),
+        }
+    }
+
+    fn read_buffer_up_to(&mut self, limit: usize) -> io::Result<Step> {
+        let step = self.transport.read_up_to(limit)?;
+        Ok(self.closing(step))
+    }
+
+    fn write_buffer(&mut self) -> io::Result<Step> {
+        let step = self.transport.write_buffer()?;
+        Ok(self.closing(step))
+    }
+
+    fn pending(&self) -> usize {
+        self.transport.pending()
+    }
+
+    fn flush(&mut self) -> io::Result<Step> {
+        let step = self.transport.flush()?;
+        Ok(self.closing(step))
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.transport.shutdown_write()
+    }
+
+    fn swap_buffer(&mut self, buffer: &mut BytesMut) {
+        self.transport.swap_buffer(buffer);
+    }
+
+    fn clear_buffer(&mut self) {
+        self.transport.clear_buffer();
+    }
+
+    fn reset_buffer(&mut self) {
+        self.transport.reset_buffer();
+    }
+
+    fn connect(&mut self, token: Token, registry: &Registry) -> io::Result<Step> {
+        self.transport.connect(&self.remote, token, registry)
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        self.transport.retry_at()
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.transport.connect_deadline
+    }
+
+    fn retry(&mut self, registry: &Registry) -> io::Result<Step> {
+        self.transport.retry(&self.remote, registry)
+    }
+
+    fn check_connected(&mut self, registry: &Registry) -> io::Result<bool> {
+        self.transport.check_connected(&self.remote, registry)
+    }
+
+    fn take_stream(&mut self) -> Option<TcpStream> {
+        self.transport.stream.take()
+    }
+
+    fn adopt(&mut self, stream: TcpStream, token: Token) {
+        self.transport.adopt(stream, token);
+    }
+
+    fn reregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.transport.reregister(registry)
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        self.transport.deregister(registry);
+    }
+}
@@ -0,0 +1,256 @@
+use super::client::Socks5Client;
+use super::client::Socks5ClientState;
+use crate::error::Step;
+use crate::proxy::ProxyProtocol;
+use crate::upstream::UpstreamClient;
+use log::{debug, error};
+use std::io;
+use std::net::IpAddr;
+
+// Offers username/password only when the proxy URL carries credentials.
+pub fn method_request(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Method Request", client.id);
+
+    client.clear_buffer();
+    let msg: &[u8] = if client.remote.credentials().is_some() {
+        &[0x05, 0x02, 0x00, 0x02]
+    } else {
+        &[0x05, 0x01, 0x00]
+    };
+    client.set_state(Socks5ClientState::MethodResponse);
+    client.send(msg)
+}
+
+pub fn method_response(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Method Response", client.id);
+
+    let step = read_handshake(client)?;
+    if client.transport.size < 2 {
+        return Ok(incomplete(step));
+    }
+    let (version, method) = (client.transport.buffer[0], client.transport.buffer[1]);
+    client.consume(2);
+    if version != 0x05 {
+        error!(
+            "[#{}] SOCKS5 proxy {} answered with version {:#04x}",
+            client.id, client.remote.addr, version
+        );
+        return Ok(Step::Close);
+    }
+    match (method, client.remote.credentials()) {
+        (0x00, _) => connection_request(client),
+        (0x02, Some((username, password))) => {
+            let password = password.unwrap_or("");
+            if username.len() > 255 || password.len() > 255 {
+                error!(
+                    "[#{}] Credentials for SOCKS5 proxy {} exceed 255 bytes",
+                    client.id, client.remote.addr
+                );
+                return refuse(client);
+            }
+            let mut msg = vec![0x01, username.len() as u8];
+            msg.extend_from_slice(username.as_bytes());
+            msg.push(password.len() as u8);
+            msg.extend_from_slice(password.as_bytes());
+            client.set_state(Socks5ClientState::AuthResponse);
+            client.send(&msg)
+        }
+        (0x02, None) => {
+            error!(
+                "[#{}] SOCKS5 proxy {} requires authentication, no credentials were given",
+                client.id, client.remote.addr
+            );
+            client.unauthorized = true;
+            refuse(client)
+        }
+        _ => {
+            error!(
+                "[#{}] SOCKS5 proxy {} accepts none of the offered methods",
+                client.id, client.remote.addr
+            );
+            client.unauthorized = true;
+            refuse(client)
+        }
+    }
+}
+
+// Status of the username/password sub-negotiation (RFC 1929).
+pub fn auth_response(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Auth Response", client.id);
+
+    let step = read_handshake(client)?;
+    if client.transport.size < 2 {
+        return Ok(incomplete(step));
+    }
+    let status = client.transport.buffer[1];
+    client.consume(2);
+    if status != 0x00 {
+        error!(
+            "[#{}] SOCKS5 proxy {} rejected the credentials, status {:#04x}",
+            client.id, client.remote.addr, status
+        );
+        client.unauthorized = true;
+        return refuse(client);
+    }
+    connection_request(client)
+}
+
+// Over socks5h a domain is passed on as is for the proxy to resolve, even
+// when the rules had it resolved here, so the final hop still sees the name
+// for SNI and virtual hosts. Plain socks5 gets the address resolved here.
+// Literals keep their address type.
+fn connection_request(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Connection Request", client.id);
+
+    let mut msg = vec![0x05, 0x01, 0x00];
+    let remote = client.remote.protocol() == &ProxyProtocol::SOCKS5hProxy;
+    let address = match client.target.domain.parse::<IpAddr>() {
+        Ok(ip) => Ok(ip),
+        Err(_) if !remote && !client.target.candidates.is_empty() => Ok(client.target.addr.ip()),
+        Err(err) => Err(err),
+    };
+    match address {
+        Ok(IpAddr::V4(ip)) => {
+            msg.push(0x01);
+            msg.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            msg.push(0x04);
+            msg.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let domain = client.target.domain.as_bytes();
+            if domain.len() > 255 {
+                error!(
+                    "[#{}] Domain {} is too long for SOCKS5 proxy {}",
+                    client.id, client.target.domain, client.remote.addr
+                );
+                return refuse(client);
+            }
+            msg.push(0x03);
+            msg.push(domain.len() as u8);
+            msg.extend_from_slice(domain);
+        }
+    }
+    msg.extend_from_slice(&client.target.port.to_be_bytes());
+    client.set_state(Socks5ClientState::ConnectionResponse);
+    client.send(&msg)
+}
+
+pub fn connection_response(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Connection Response", client.id);
+
+    let step = read_handshake(client)?;
+    // The bound address decides the length of the reply
+    let len = match client.transport.buffer.get(3) {
+        Some(0x01) => 4 + 4 + 2,
+        Some(0x04) => 4 + 16 + 2,
+        Some(0x03) => match client.transport.buffer.get(4) {
+            Some(n) => 4 + 1 + *n as usize + 2,
+            None => usize::MAX,
+        },
+        Some(atyp) => {
+            error!(
+                "[#{}] SOCKS5 proxy {} replied with address type {:#04x}",
+                client.id, client.remote.addr, atyp
+            );
+            return Ok(Step::Close);
+        }
+        None => usize::MAX,
+    };
+    if client.transport.size < len {
+        return Ok(incomplete(step));
+    }
+
+    let rep = client.transport.buffer[1];
+    client.transport.connect_deadline = None;
+    client.reply = Some(rep);
+    if rep != 0x00 {
+        error!(
+            "[#{}] SOCKS5 proxy {} refused the connection, REP: {:#04x}",
+            client.id, client.remote.addr, rep
+        );
+        return Ok(Step::Close);
+    }
+
+    // Anything after the reply already belongs to the tunnel and is kept
+    // for the SOCKS client.
+    client.consume(len);
+    debug!("[#{}] SOCKS5 Client tunnel established", client.id);
+    client.set_state(Socks5ClientState::RelayingOUT);
+    Ok(Step::Continue)
+}
+
+// Handshake messages may arrive in pieces, reads append to the buffer.
+fn read_handshake(client: &mut Socks5Client) -> io::Result<Step> {
+    match client.read_buffer() {
+        Ok(Step::Close) if client.transport.size == 0 => {
+            error!(
+                "[#{}] SOCKS5 proxy {} closed the connection during the handshake",
+                client.id, client.remote.addr
+            );
+            Ok(Step::Close)
+        }
+        Err(err) => {
+            error!(
+                "[#{}] During SOCKS5 Client handshake, error occured: {}",
+                client.id, err
+            );
+            Err(err)
+        }
+        result => result,
+    }
+}
+
+// A proxy closing before its message is complete ends the handshake,
+// otherwise the rest is waited for.
+fn incomplete(step: Step) -> Step {
+    match step {
+        Step::Close => Step::Close,
+        _ => Step::Yield,
+    }
+}
+
+// Fails the SOCKS request without any specific reason to pass on.
+fn refuse(client: &mut Socks5Client) -> io::Result<Step> {
+    client.transport.connect_deadline = None;
+    client.reply = Some(0x01);
+    client.set_state(Socks5ClientState::Closed);
+    Ok(Step::Close)
+}
+
+// Receive from SOCKS5 Proxy
+pub fn relay_in(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Relay IN", client.id);
+
+    client.clear_buffer();
+    match client.read_buffer() {
+        Ok(Step::Close) => {
+            debug!("[#{}] SOCKS5 Client Relay IN interrupted", client.id);
+            return Ok(Step::Close);
+        }
+        Err(err) => {
+            error!(
+                "[#{}] During SOCKS5 Client Relay IN, error occured: {}",
+                client.id, err
+            );
+            return Err(err);
+        }
+        Ok(_) => {}
+    }
+
+    client.set_state(Socks5ClientState::RelayingOUT);
+    Ok(Step::Continue)
+}
+
+// Send to SOCKS5 Proxy
+pub fn relay_out(client: &mut Socks5Client) -> io::Result<Step> {
+    debug!("[#{}] SOCKS5 Client Relay OUT", client.id);
+
+    if client.transport.size == 0 {
+        return Ok(Step::Close);
+    }
+
+    client.set_state(Socks5ClientState::RelayingIN);
+    client.write_buffer()
+}
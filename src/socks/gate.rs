@@ -0,0 +1,73 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use log::info;
+use mio::Token;
+
+// Caps the upstream CONNECT handshakes in flight. Handlers finding every
+// slot taken queue up and are admitted first come, first served once one
+// frees up.
+pub struct UpstreamGate {
+    max: Option<usize>,
+    in_flight: Cell<usize>,
+    waiting: RefCell<VecDeque<Token>>,
+}
+
+impl UpstreamGate {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            in_flight: Cell::new(0),
+            waiting: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    // Takes a slot, or queues the handler behind those already waiting.
+    pub fn acquire(&self, token: Token) -> bool {
+        let max = match self.max {
+            Some(max) => max,
+            None => return true,
+        };
+        let mut waiting = self.waiting.borrow_mut();
+        if self.in_flight.get() < max && waiting.is_empty() {
+            self.in_flight.set(self.in_flight.get() + 1);
+            return true;
+        }
+        if waiting.is_empty() {
+            info!(
+                "Throttling upstream connections, {} handshakes in flight",
+                max
+            );
+        }
+        waiting.push_back(token);
+        false
+    }
+
+    pub fn release(&self) {
+        if self.max.is_some() {
+            self.in_flight.set(self.in_flight.get().saturating_sub(1));
+        }
+    }
+
+    // Drops a handler from the queue when it closes before its turn.
+    pub fn forget(&self, token: Token) {
+        self.waiting.borrow_mut().retain(|t| *t != token);
+    }
+
+    // The next queued handler if a slot is free, which it then holds.
+    pub fn admit(&self) -> Option<Token> {
+        let max = self.max?;
+        if self.in_flight.get() >= max {
+            return None;
+        }
+        let token = self.waiting.borrow_mut().pop_front()?;
+        self.in_flight.set(self.in_flight.get() + 1);
+        Some(token)
+    }
+
+    // Whether `admit` would hand out a slot right now.
+    pub fn ready(&self) -> bool {
+        self.max
+            .is_some_and(|max| self.in_flight.get() < max && !self.waiting.borrow().is_empty())
+    }
+}
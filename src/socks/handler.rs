@@ -1,63 +1,177 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use fnv::FnvHashMap;
-use log::debug;
-use mio::{event::Event, net::TcpStream, Registry, Token};
+use log::{debug, error, info, warn};
+use mio::{event::Event, net::TcpStream, Interest, Registry, Token};
 use slab::Slab;
 use std::{
-    io::{self, Read, Write},
-    net::SocketAddr,
-    usize,
+    io,
+    net::{IpAddr, Shutdown, SocketAddr},
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    datatype::Target,
+    accesslog::{AccessLogEntry, Phases},
+    buffer::{read_buf, write_some},
+    chain::{hop_target, ChainClient},
+    config::Config,
+    datatype::{IpFamily, Target},
+    direct::client::DirectClient,
+    dns::DnsResolver,
+    error::{disconnected, CloseReason, ProxyError, Step},
+    fd::fd_note,
     http::client::HttpClient,
-    proxy::Proxy,
-    socks::server_protocol::{connection_response, relay_in, relay_out},
+    proxy::{Proxy, ProxyProtocol},
+    ratelimit::TokenBucket,
+    selector::UpstreamSelector,
+    socks::server_protocol::{
+        connection_failure, connection_resolved, connection_response, relay_in, relay_out,
+        reply_for_error, reply_for_status,
+    },
+    upstream::Client,
 };
 
-use super::server_protocol::{connection_request, method_request, method_response};
+use super::client::Socks5Client;
+use super::gate::UpstreamGate;
+use super::http_protocol;
+use super::parse::{parse_client_hello_sni, ParseError};
+use super::server_protocol::{auth_request, connection_request, method_request, method_response};
+use super::socks4_protocol;
+use super::tokens::TokenPool;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Socks5State {
     MethodRequest,
     MethodResponse,
+    AuthRequest,
     ConnectionRequest,
+    Resolving,
+    // Queued until the upstream gate has a free slot.
+    WaitingUpstream,
     ClientConnectionRequest,
     ClientConnectionResponse,
     ConnectionResponse,
     Relaying,
+    // Failed, waiting for the client to take the queued reply.
+    Closing,
     Closed,
 }
 
+// How long a failed connection may wait for its reply to be taken.
+const LINGER: Duration = Duration::from_secs(5);
+
 pub struct Socks5Handler<T> {
+    pub id: usize,
     pub token: Token,
     stream: TcpStream,
+    peer: SocketAddr,
     pub buffer: BytesMut,
     pub size: usize,
+    // Tail of a short write to the client, sent before anything newer.
+    unsent: BytesMut,
+    buffer_size: usize,
+    pub max_buffer: usize,
     intotal: usize,
+    outtotal: usize,
+    start: Instant,
+    // Phase transitions reported in the access log.
+    pub phases: Phases,
+    last_active: Instant,
+    // Time and byte totals of the last throughput sample.
+    sampled: (Instant, usize, usize),
+    established: bool,
     target: Target,
     pub state: Socks5State,
-    subproxy: Vec<Proxy>,
+    pub version: u8,
+    // Authentication method chosen during the negotiation.
+    pub method: u8,
+    // Set for a RESOLVE request, answered with the address instead of a relay.
+    pub resolve_only: bool,
+    pub config: Rc<Config>,
+    pub resolver: Rc<DnsResolver>,
+    selector: Rc<dyn UpstreamSelector>,
+    gate: Rc<UpstreamGate>,
+    // Whether this handler holds a slot of the gate.
+    slot: bool,
     pub client: Slab<T>,
+    pub upstream: Option<usize>,
+    // Which sides of the tunnel have finished sending.
+    pub client_eof: bool,
+    pub upstream_eof: bool,
+    // Upstream the target was routed to, for the access log.
+    route: Option<String>,
+    // Upstream proxy whose handshake outcome the selector still awaits.
+    reporting: Option<Proxy>,
+    // Status line the upstream refused the tunnel with.
+    upstream_status: Option<String>,
+    limiter: Option<TokenBucket>,
+    throttled: bool,
+    // Set once the server tore the connection down.
+    closed: bool,
+    // When a Closing connection is given up on.
+    linger_until: Option<Instant>,
+    // First bytes of the client, held back until their SNI was checked.
+    hello: Option<BytesMut>,
+    // Why the connection ends, the first reason given sticks.
+    close_reason: Option<CloseReason>,
 }
 
-impl Socks5Handler<HttpClient> {
-    pub fn new(token: Token, stream: TcpStream, subproxy: Vec<Proxy>) -> Self {
-        let mut buffer = BytesMut::with_capacity(4096);
-        buffer.resize(4096, 0);
-        let mut outbuf = BytesMut::with_capacity(4096);
-        outbuf.resize(4096, 0);
+impl Socks5Handler<Client> {
+    pub fn new(
+        id: usize,
+        token: Token,
+        // As accepted, the address is kept for once the socket is gone
+        (stream, peer): (TcpStream, SocketAddr),
+        config: Rc<Config>,
+        resolver: Rc<DnsResolver>,
+        selector: Rc<dyn UpstreamSelector>,
+        gate: Rc<UpstreamGate>,
+    ) -> Self {
+        let buffer = BytesMut::with_capacity(config.buffer_size);
         Self {
+            id,
             token,
             stream,
+            peer,
             buffer,
             size: 0,
+            unsent: BytesMut::new(),
+            buffer_size: config.buffer_size,
+            max_buffer: config.max_buffer,
             intotal: 0,
+            outtotal: 0,
+            start: Instant::now(),
+            phases: Phases::default(),
+            last_active: Instant::now(),
+            sampled: (Instant::now(), 0, 0),
+            established: false,
             target: Target::new(),
             state: Socks5State::MethodRequest,
-            subproxy,
+            version: 0x05,
+            method: 0x00,
+            resolve_only: false,
             client: Slab::new(),
+            upstream: None,
+            client_eof: false,
+            upstream_eof: false,
+            route: None,
+            reporting: None,
+            upstream_status: None,
+            // A zero rate would never refill, it counts as no limit
+            limiter: config
+                .rate_limit
+                .filter(|rate| *rate > 0)
+                .map(TokenBucket::new),
+            hello: config.enforce_sni.then(BytesMut::new),
+            config,
+            resolver,
+            selector,
+            gate,
+            slot: false,
+            throttled: false,
+            closed: false,
+            linger_until: None,
+            close_reason: None,
         }
     }
 
@@ -65,52 +179,102 @@ impl Socks5Handler<HttpClient> {
         &mut self,
         event: &Event,
         token: Token,
-        unique_token: &mut Token,
+        tokens: &mut TokenPool,
         registry: &Registry,
         subtoken: &mut FnvHashMap<Token, Token>,
-    ) -> io::Result<bool> {
+    ) -> Result<Step, ProxyError> {
         debug!(
-            "SOCKS5 connection state: {:?}, readable: {}, writeable: {}",
+            "[#{}] SOCKS5 connection state: {:?}, readable: {}, writeable: {}",
+            self.id,
             self.state,
             event.is_readable(),
             event.is_writable()
         );
+        self.last_active = Instant::now();
+
+        if self.state == Socks5State::Closing {
+            return Ok(self.flush_closing(event, token));
+        }
 
         if event.is_readable() {
             let result = match self.state {
-                Socks5State::MethodRequest if token == self.token => method_request(self),
-                Socks5State::ConnectionRequest if token == self.token => {
-                    let handle_result = connection_request(self);
-                    let proxy = self.subproxy.get(0).unwrap().clone();
-                    let mut client = HttpClient::new(proxy, self.target.clone());
-                    let next_token = unique_token.0;
-                    unique_token.0 += 1;
-                    let connect_result = client.connect(Token(next_token), registry);
-                    subtoken.insert(Token(next_token), self.token);
-                    self.client.insert(client);
-                    if connect_result.is_err() || handle_result.is_err() {
-                        return Ok(true);
+                Socks5State::MethodRequest if token == self.token => {
+                    match method_request(self) {
+                        Ok(Step::Continue) | Ok(Step::Yield) => {}
+                        result => return self.closing(result),
                     }
-                    if connect_result.unwrap() || handle_result.unwrap() {
-                        return Ok(true);
+                    if self.state == Socks5State::ClientConnectionRequest {
+                        self.connect_client(tokens, registry, subtoken)
+                    } else {
+                        Ok(Step::Continue)
                     }
-                    Ok(false)
                 }
-                Socks5State::ClientConnectionResponse => {
-                    let client = self.client.get_mut(0).unwrap();
-                    self.state = Socks5State::ConnectionResponse;
-                    client.handle(event, None)
+                Socks5State::AuthRequest if token == self.token => auth_request(self),
+                // Nothing is read while the lookup is pending, a client that
+                // went away meanwhile is let go right away though
+                Socks5State::Resolving if token == self.token => self.client_gone(),
+                Socks5State::ConnectionRequest if token == self.token => {
+                    match connection_request(self) {
+                        Ok(Step::Continue) | Ok(Step::Yield) => {}
+                        result => return self.closing(result),
+                    }
+                    if self.state == Socks5State::ClientConnectionRequest {
+                        self.connect_client(tokens, registry, subtoken)
+                    } else {
+                        Ok(Step::Continue)
+                    }
                 }
-                _ => Ok(false),
+                Socks5State::ClientConnectionResponse => match self.client_key(token) {
+                    Some(key) => {
+                        self.state = Socks5State::ConnectionResponse;
+                        let result = self.client[key]
+                            .handle(event, None)
+                            .map_err(ProxyError::from);
+                        let answered = match self.client[key].status() {
+                            Some(status) if status != 200 => Err(reply_for_status(status)),
+                            Some(_) => Ok(true),
+                            None => match self.client[key].reply() {
+                                Some(rep) if rep != 0x00 => Err(rep),
+                                reply => Ok(reply.is_some()),
+                            },
+                        };
+                        if answered.is_err() {
+                            self.upstream_status = self.client[key].status_line().map(String::from);
+                        }
+                        match answered {
+                            Err(rep) => {
+                                self.close_as(CloseReason::UpstreamError);
+                                connection_failure(self, rep)
+                            }
+                            // Reply right away rather than on the next writable
+                            // edge, which may never come if the origin speaks
+                            // first; the reply goes out before its bytes.
+                            Ok(true) if matches!(result, Ok(step) if step != Step::Close) => {
+                                self.respond()
+                            }
+                            // A SOCKS5 upstream takes several round trips
+                            Ok(false) if matches!(result, Ok(step) if step != Step::Close) => {
+                                self.state = Socks5State::ClientConnectionResponse;
+                                result
+                            }
+                            // The upstream went away before answering at all
+                            Ok(false) => connection_failure(self, 0x01),
+                            _ => result,
+                        }
+                    }
+                    None => Ok(Step::Yield),
+                },
+                _ => Ok(Step::Yield),
             };
             match result {
-                Ok(true) | Err(_) => return Ok(true),
-                _ => {}
+                Ok(Step::Continue) | Ok(Step::Yield) => {}
+                result => return self.closing(result),
             }
         }
 
         debug!(
-            "SOCKS5 connection state: {:?}, readable: {}, writeable: {}",
+            "[#{}] SOCKS5 connection state: {:?}, readable: {}, writeable: {}",
+            self.id,
             self.state,
             event.is_readable(),
             event.is_writable()
@@ -119,45 +283,422 @@ impl Socks5Handler<HttpClient> {
         if event.is_writable() {
             let result = match self.state {
                 Socks5State::MethodResponse => method_response(self),
-                Socks5State::ClientConnectionRequest => {
-                    let client = self.client.get_mut(0).unwrap();
-                    self.state = Socks5State::ClientConnectionResponse;
-                    client.handle(event, None)
-                }
-                Socks5State::ConnectionResponse => connection_response(self),
-                _ => Ok(false),
+                Socks5State::ClientConnectionRequest => match self.client_key(token) {
+                    Some(key) => match self.client[key].check_connected(registry) {
+                        Ok(true) if self.client[key].established() => {
+                            self.phases.connected = Some(Instant::now());
+                            self.state = Socks5State::ConnectionResponse;
+                            self.respond()
+                        }
+                        Ok(true) => {
+                            self.phases.connected = Some(Instant::now());
+                            self.state = Socks5State::ClientConnectionResponse;
+                            self.client[key]
+                                .handle(event, None)
+                                .map_err(ProxyError::from)
+                        }
+                        Ok(false) => Ok(Step::Yield),
+                        Err(err) => self.connect_failed(Some(err.kind())),
+                    },
+                    None => Ok(Step::Yield),
+                },
+                // The CONNECT may not have gone out in one write
+                Socks5State::ClientConnectionResponse => match self.client_key(token) {
+                    Some(key) => Ok(self.client[key].flush()?),
+                    None => Ok(Step::Yield),
+                },
+                Socks5State::ConnectionResponse => self.respond(),
+                _ => Ok(Step::Yield),
             };
             match result {
-                Ok(true) | Err(_) => return Ok(true),
-                _ => {}
+                Ok(Step::Continue) | Ok(Step::Yield) => {}
+                result => return self.closing(result),
             }
         }
 
+        // Pump both directions whichever socket fired, the other side of a
+        // full-duplex tunnel may be ready as well and no further edge would
+        // be reported for data already waiting there.
         if self.state == Socks5State::Relaying {
-            if token != self.token {
-                return relay_out(self);
+            if !self.established {
+                self.sampled = (Instant::now(), self.intotal, self.outtotal);
+            }
+            self.established = true;
+            let result = match relay_in(self) {
+                Ok(Step::Close) => return Ok(Step::Close),
+                Ok(_) => relay_out(self),
+                result => result,
+            };
+            return match result {
+                Err(ProxyError::Io(ref err)) if disconnected(err) => {
+                    debug!("[#{}] SOCKS5 Relay ended by the peer: {}", self.id, err);
+                    Ok(Step::Close)
+                }
+                result => result,
+            };
+        }
+
+        Ok(Step::Yield)
+    }
+
+    // Resumes a request parked in Resolving with the answer of the resolver.
+    pub fn resolved(
+        &mut self,
+        ips: Option<Vec<IpAddr>>,
+        tokens: &mut TokenPool,
+        registry: &Registry,
+        subtoken: &mut FnvHashMap<Token, Token>,
+    ) -> Result<Step, ProxyError> {
+        if self.state != Socks5State::Resolving {
+            return Ok(Step::Yield);
+        }
+        self.phases.resolved = Some(Instant::now());
+        match connection_resolved(self, ips) {
+            Ok(Step::Continue) | Ok(Step::Yield) => {}
+            result => return self.closing(result),
+        }
+        match self.connect_client(tokens, registry, subtoken) {
+            Ok(step) if step != Step::Close => Ok(step),
+            result => self.closing(result),
+        }
+    }
+
+    // Resumes a request parked in WaitingUpstream, the gate handed it a slot.
+    pub fn admitted(
+        &mut self,
+        tokens: &mut TokenPool,
+        registry: &Registry,
+        subtoken: &mut FnvHashMap<Token, Token>,
+    ) -> Result<Step, ProxyError> {
+        if self.state != Socks5State::WaitingUpstream {
+            self.gate.release();
+            return Ok(Step::Yield);
+        }
+        self.slot = true;
+        self.state = Socks5State::ClientConnectionRequest;
+        match self.connect_client(tokens, registry, subtoken) {
+            Ok(step) if step != Step::Close => Ok(step),
+            result => self.closing(result),
+        }
+    }
+
+    // Holds back the close of a connection failing its handshake while its
+    // reply still waits to be sent, false when it can go right away.
+    pub fn linger(&mut self) -> bool {
+        if self.established || self.pending() == 0 || self.state == Socks5State::Closing {
+            return false;
+        }
+        debug!(
+            "[#{}] Flushing {} bytes before closing",
+            self.id,
+            self.pending()
+        );
+        self.release_slot();
+        self.state = Socks5State::Closing;
+        self.linger_until = Some(Instant::now() + LINGER);
+        true
+    }
+
+    fn flush_closing(&mut self, event: &Event, token: Token) -> Step {
+        if token != self.token || !event.is_writable() {
+            return Step::Yield;
+        }
+        match self.flush_stream() {
+            Ok(Step::Continue) if self.pending() > 0 => Step::Yield,
+            _ => Step::Close,
+        }
+    }
+
+    // A failed step ends the connection, its reason gets logged here once.
+    fn closing(&mut self, result: Result<Step, ProxyError>) -> Result<Step, ProxyError> {
+        if let Err(err) = result.as_ref() {
+            self.fail_as(err);
+        }
+        match result {
+            Err(ProxyError::Io(err)) => debug!("[#{}] SOCKS5 connection failed: {}", self.id, err),
+            Err(err) => error!("[#{}] {}", self.id, err),
+            Ok(_) => {}
+        }
+        Ok(Step::Close)
+    }
+
+    #[inline]
+    pub fn close_as(&mut self, reason: CloseReason) {
+        self.close_reason.get_or_insert(reason);
+    }
+
+    // Reason for an error ending the connection, unless one was given
+    // already. IO errors before any upstream exists were the client's.
+    pub fn fail_as(&mut self, err: &ProxyError) {
+        let reason = match err {
+            ProxyError::Protocol(_) => CloseReason::ProtocolError,
+            ProxyError::Io(_) if self.upstream.is_none() => CloseReason::ClientEof,
+            _ => CloseReason::UpstreamError,
+        };
+        self.close_as(reason);
+    }
+
+    // Every other way to close gives a reason, what is left is the client
+    // going away mid-handshake.
+    pub fn close_reason(&self) -> CloseReason {
+        self.close_reason.unwrap_or(CloseReason::ClientEof)
+    }
+
+    // Closes once the client closed or reset its socket, without taking
+    // anything it sent ahead.
+    fn client_gone(&mut self) -> Result<Step, ProxyError> {
+        match self.stream.peek(&mut [0u8; 1]) {
+            Ok(0) => {
+                debug!("[#{}] SOCKS5 client went away while resolving", self.id);
+                self.close_as(CloseReason::ClientEof);
+                Ok(Step::Close)
+            }
+            Ok(_) => Ok(Step::Yield),
+            Err(ref err) if Self::would_block(err) || Self::interrupted(err) => Ok(Step::Yield),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Slab key of the upstream client registered under the given token.
+    pub fn client_key(&self, token: Token) -> Option<usize> {
+        self.client
+            .iter()
+            .find(|(_, client)| client.token() == Some(token))
+            .map(|(key, _)| key)
+    }
+
+    fn connect_client(
+        &mut self,
+        tokens: &mut TokenPool,
+        registry: &Registry,
+        subtoken: &mut FnvHashMap<Token, Token>,
+    ) -> Result<Step, ProxyError> {
+        if !self.slot {
+            if !self.gate.acquire(self.token) {
+                debug!("[#{}] Waiting for an upstream slot", self.id);
+                self.state = Socks5State::WaitingUpstream;
+                return Ok(Step::Yield);
+            }
+            self.slot = true;
+        }
+        let mut client: Client = if self.config.direct.matches(&self.target) {
+            info!(
+                "[#{}] Connecting to {}:{} directly",
+                self.id, self.target.domain, self.target.port
+            );
+            self.route = Some(String::from("direct"));
+            let mut client = DirectClient::new(
+                self.id,
+                self.target.clone(),
+                self.config.prefer.unwrap_or(IpFamily::V6),
+                self.buffer_size,
+            );
+            client.set_outbound(self.config.outbound.clone());
+            client.set_keepalive(self.config.keepalive);
+            client.set_nodelay(self.config.outbound_nodelay);
+            Box::new(client)
+        } else {
+            let peer = self.peer;
+            if self.config.chain && self.config.subproxy.len() > 1 {
+                self.chain_client(peer)
             } else {
-                return relay_in(self);
+                let proxy = match self.selector.select(&self.target, peer) {
+                    Some(proxy) => proxy.clone(),
+                    None => {
+                        connection_failure(self, 0x01)?;
+                        return Err(ProxyError::Upstream(format!(
+                            "no upstream proxy for {}:{}",
+                            self.target.domain, self.target.port
+                        )));
+                    }
+                };
+                self.route = Some(format!("{}:{}", proxy.host, proxy.port));
+                self.reporting = Some(proxy.clone());
+                self.proxy_client(proxy, self.target.clone(), Some(peer), true)
+            }
+        };
+        self.phases.connect = Some(Instant::now());
+        let next_token = tokens.take();
+        let connect_result = client.connect(next_token, registry);
+        subtoken.insert(next_token, self.token);
+        self.upstream = Some(self.client.insert(client));
+        match connect_result {
+            Ok(Step::Close) => self.connect_failed(None),
+            Err(err) => self.connect_failed(Some(err.kind())),
+            Ok(_) => Ok(Step::Continue),
+        }
+    }
+
+    // Client for one upstream proxy. The PROXY protocol header and retries
+    // only apply to the hop connecting to its proxy, which has a `source`,
+    // forwarding only to the `last` one.
+    fn proxy_client(
+        &self,
+        proxy: Proxy,
+        target: Target,
+        source: Option<SocketAddr>,
+        last: bool,
+    ) -> Client {
+        if matches!(
+            proxy.protocol(),
+            ProxyProtocol::SOCKS5Proxy | ProxyProtocol::SOCKS5hProxy
+        ) {
+            let mut client =
+                Socks5Client::new(self.id, proxy, target, self.buffer_size, self.max_buffer);
+            client.set_outbound(self.config.outbound.clone());
+            client.set_keepalive(self.config.keepalive);
+            client.set_nodelay(self.config.outbound_nodelay);
+            client.set_connect_timeout(self.config.connect_timeout);
+            if source.is_some() {
+                client.set_retry(
+                    self.config.upstream_retries,
+                    self.config.upstream_retry_delay,
+                );
+            }
+            Box::new(client)
+        } else {
+            let mut client =
+                HttpClient::new(self.id, proxy, target, self.buffer_size, self.max_buffer);
+            client.set_outbound(self.config.outbound.clone());
+            client.set_keepalive(self.config.keepalive);
+            client.set_nodelay(self.config.outbound_nodelay);
+            client.set_headers(self.config.connect_headers.clone());
+            client.set_host_style(self.config.connect_host_style);
+            client.set_http_version(self.config.upstream_http_version);
+            client.set_connect_timeout(self.config.connect_timeout);
+            client.set_proxy_protocol(self.config.send_proxy_protocol.then_some(source).flatten());
+            client.set_absolute_form(self.config.http_absolute_form && last);
+            if source.is_some() {
+                client.set_retry(
+                    self.config.upstream_retries,
+                    self.config.upstream_retry_delay,
+                );
             }
+            Box::new(client)
+        }
+    }
+
+    // Goes through every upstream in order, each hop targeting the next one.
+    // The selector and its breaker are left out, a chain has no alternative.
+    fn chain_client(&mut self, peer: SocketAddr) -> Client {
+        let proxies = self.config.subproxy.clone();
+        self.route = Some(
+            proxies
+                .iter()
+                .map(|proxy| format!("{}:{}", proxy.host, proxy.port))
+                .collect::<Vec<_>>()
+                .join(" > "),
+        );
+        let hops = proxies
+            .iter()
+            .enumerate()
+            .map(|(i, proxy)| {
+                let target = match proxies.get(i + 1) {
+                    Some(next) => hop_target(next),
+                    None => self.target.clone(),
+                };
+                let source = (i == 0).then_some(peer);
+                self.proxy_client(proxy.clone(), target, source, i + 1 == proxies.len())
+            })
+            .collect();
+        Box::new(ChainClient::new(self.id, hops))
+    }
+
+    // Tells the client why the upstream could not be reached before closing.
+    fn connect_failed(&mut self, kind: Option<io::ErrorKind>) -> Result<Step, ProxyError> {
+        let kind = kind.or_else(|| {
+            self.upstream
+                .and_then(|key| self.client.get(key))
+                .and_then(|client| client.last_error())
+        });
+        connection_failure(self, reply_for_error(kind))
+    }
+
+    fn respond(&mut self) -> Result<Step, ProxyError> {
+        let result = if self.http() {
+            http_protocol::connection_response(self)
+        } else if self.version == 0x04 {
+            socks4_protocol::connection_response(self)
+        } else {
+            connection_response(self)
+        };
+        // The handshake is over either way, the slot goes to the next one
+        self.release_slot();
+        self.report_upstream(true);
+        self.phases.answered = Some(Instant::now());
+        match result {
+            Ok(Step::Continue) | Ok(Step::Yield) => self.flush_early_data(),
+            result => result,
         }
+    }
 
-        Ok(false)
+    // Tells the selector how the handshake with the chosen upstream went.
+    pub fn report_upstream(&mut self, ok: bool) {
+        if let Some(proxy) = self.reporting.take() {
+            self.selector.report(&proxy, ok);
+        }
+    }
+
+    fn release_slot(&mut self) {
+        if self.slot {
+            self.slot = false;
+            self.gate.release();
+        }
     }
 
-    pub fn read_stream(&mut self) -> io::Result<bool> {
-        loop {
-            debug!("SOCKS5 buffer:{}, size: {}", self.buffer.len(), self.size);
-            match self.stream.read(&mut self.buffer[self.size..]) {
+    // Passes on tunnel bytes the upstream sent along with its handshake.
+    fn flush_early_data(&mut self) -> Result<Step, ProxyError> {
+        let key = match self.upstream {
+            Some(key) if self.client.contains(key) => key,
+            _ => return Ok(Step::Continue),
+        };
+        if self.client[key].size() == 0 {
+            return Ok(Step::Continue);
+        }
+        self.phases.first_byte.get_or_insert_with(Instant::now);
+        self.reset_buffer();
+        self.size = self.client[key].size();
+        self.client[key].swap_buffer(&mut self.buffer);
+        self.client[key].clear_buffer();
+        Ok(self.write_stream()?)
+    }
+
+    // Handshake frames are small, a client filling max_request_size
+    // without completing one is cut off instead of growing the buffer.
+    pub fn read_stream(&mut self) -> io::Result<Step> {
+        let max = self.config.max_request_size;
+        let room = max.saturating_sub(self.size);
+        let step = self.read_stream_up_to(room.min(self.max_buffer))?;
+        if self.size >= max {
+            self.state = Socks5State::Closed;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("request exceeds {} bytes", max),
+            ));
+        }
+        Ok(step)
+    }
+
+    pub fn read_stream_up_to(&mut self, limit: usize) -> io::Result<Step> {
+        let mut remaining = limit;
+        while remaining > 0 {
+            debug!(
+                "[#{}] SOCKS5 buffer:{}, size: {}",
+                self.id,
+                self.buffer.capacity(),
+                self.size
+            );
+            match read_buf(&mut self.stream, &mut self.buffer, remaining) {
                 Ok(0) => {
-                    self.state = Socks5State::Closed;
-                    return Ok(true);
+                    if self.state != Socks5State::Relaying {
+                        self.state = Socks5State::Closed;
+                    }
+                    return Ok(Step::Close);
                 }
                 Ok(n) => {
                     self.size += n;
                     self.intotal += n;
-                    if self.size == self.buffer.len() {
-                        self.buffer.resize(self.buffer.len() + 1024, 0);
-                    }
+                    remaining -= n;
                 }
                 Err(ref err) if Socks5Handler::would_block(err) => break,
                 Err(ref err) if Socks5Handler::interrupted(err) => continue,
@@ -166,27 +707,94 @@ impl Socks5Handler<HttpClient> {
                 }
             }
         }
-        if self.size != self.buffer.len() {
-            self.buffer.resize(self.size, 0);
+        Ok(Step::Continue)
+    }
+
+    pub fn shutdown_stream(&mut self) {
+        if let Err(err) = self.stream.shutdown(Shutdown::Write) {
+            debug!("[#{}] SOCKS5 shutdown failed: {}", self.id, err);
         }
-        Ok(false)
     }
 
-    pub fn write_stream(&mut self) -> io::Result<bool> {
-        match self.stream.write(&self.buffer) {
-            Ok(n) if n < self.size => {
-                println!("{} {}", n, self.size);
-                println!("{:?}", self.buffer);
-                Err(io::ErrorKind::WriteZero.into())
+    // Queues the buffer behind any unsent tail and writes what the socket
+    // takes, the rest waits for the next writable edge.
+    pub fn write_stream(&mut self) -> io::Result<Step> {
+        self.unsent.unsplit(self.buffer.split());
+        self.size = 0;
+        self.flush_stream()
+    }
+
+    pub fn flush_stream(&mut self) -> io::Result<Step> {
+        match write_some(&mut self.stream, &self.unsent)? {
+            Some(n) => {
+                if n < self.unsent.len() {
+                    debug!(
+                        "[#{}] SOCKS5 short write: {} of {}",
+                        self.id,
+                        n,
+                        self.unsent.len()
+                    );
+                }
+                self.outtotal += n;
+                self.unsent.advance(n);
+                Ok(Step::Continue)
             }
-            Ok(_) => Ok(false),
-            Err(ref err) if Socks5Handler::would_block(err) => Ok(false),
-            Err(ref err) if Socks5Handler::interrupted(err) => {
+            None => {
                 self.set_state(Socks5State::Closed);
-                Ok(true)
+                Ok(Step::Close)
+            }
+        }
+    }
+
+    // With enforce_sni the first bytes read from the client are held back
+    // until they make up a whole ClientHello, whose SNI has to name the
+    // target. False while more is needed, the buffer is emptied meanwhile,
+    // once passed the buffer holds everything held back.
+    pub fn screen_hello(&mut self) -> Result<bool, ProxyError> {
+        let held = match self.hello.as_mut() {
+            Some(held) => held,
+            None => return Ok(true),
+        };
+        held.unsplit(self.buffer.split());
+        self.size = 0;
+        let sni = match parse_client_hello_sni(held) {
+            Ok(sni) => sni,
+            Err(ParseError::Incomplete) => return Ok(false),
+            Err(ParseError::Protocol(reason)) | Err(ParseError::Reply(_, reason)) => {
+                self.close_as(CloseReason::PolicyDenied);
+                return Err(ProxyError::Protocol(format!(
+                    "{} to {}:{}, SNI can't be checked",
+                    reason, self.target.domain, self.target.port
+                )));
+            }
+        };
+        let expected = self.target.domain.trim_end_matches('.');
+        match sni {
+            Some(name) if name.trim_end_matches('.').eq_ignore_ascii_case(expected) => {}
+            Some(name) => {
+                self.close_as(CloseReason::PolicyDenied);
+                return Err(ProxyError::Protocol(format!(
+                    "SNI {} doesn't match the target {}:{}",
+                    name, self.target.domain, self.target.port
+                )));
+            }
+            None => {
+                self.close_as(CloseReason::PolicyDenied);
+                return Err(ProxyError::Protocol(format!(
+                    "TLS ClientHello without SNI to {}:{}",
+                    self.target.domain, self.target.port
+                )));
             }
-            Err(err) => Err(err),
         }
+        self.buffer = self.hello.take().unwrap_or_default();
+        self.size = self.buffer.len();
+        Ok(true)
+    }
+
+    // Bytes a short write to the client left behind.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.unsent.len()
     }
 
     #[inline]
@@ -194,6 +802,35 @@ impl Socks5Handler<HttpClient> {
         self.state = state;
     }
 
+    #[inline]
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    // Whether the client is an HTTP proxy client rather than a SOCKS one.
+    #[inline]
+    pub fn http(&self) -> bool {
+        self.config.inbound == ProxyProtocol::HTTPProxy
+    }
+
+    // Domains are left to the upstream when every proxy it may pick is
+    // socks5h. Blocked CIDRs and RESOLVE still need the address here.
+    #[inline]
+    pub fn resolves_remotely(&self, target: &Target) -> bool {
+        let proxies = self.selector.proxies();
+        !self.resolve_only
+            && !self.config.rules.has_cidrs()
+            && !self.config.direct.matches(target)
+            && !proxies.is_empty()
+            && proxies
+                .iter()
+                .all(|proxy| proxy.protocol() == &ProxyProtocol::SOCKS5hProxy)
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
     #[inline]
     pub fn set_target(&mut self, value: Target) {
         self.target = value;
@@ -208,7 +845,7 @@ impl Socks5Handler<HttpClient> {
     #[inline]
     pub fn clear_buffer(&mut self) {
         self.buffer.clear();
-        self.buffer.resize(4096, 0);
+        self.buffer.reserve(self.buffer_size);
         self.size = 0;
     }
 
@@ -218,16 +855,216 @@ impl Socks5Handler<HttpClient> {
         self.size = 0;
     }
 
+    // Address of the client as accepted, still there once it went away.
     #[inline]
-    pub fn extract_buffer(&mut self, buf: &mut [u8], start: usize) {
-        for (i, data) in buf.iter_mut().enumerate() {
-            *data = self.buffer[i + start];
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    // Bytes the relay may read this tick, usize::MAX when unlimited.
+    pub fn quota(&mut self) -> usize {
+        match self.limiter.as_mut() {
+            Some(limiter) => limiter.available(),
+            None => usize::MAX,
         }
     }
 
-    #[inline]
-    pub fn stream_addr(&self) -> io::Result<SocketAddr> {
-        self.stream.peer_addr()
+    pub fn consume(&mut self, quota: usize, amount: usize) {
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.consume(amount);
+            if amount >= quota {
+                debug!("[#{}] SOCKS5 relay throttled", self.id);
+                self.throttled = true;
+            }
+        }
+    }
+
+    // Time until the handler has work to do without any socket event.
+    pub fn wait_time(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        if let Some(at) = self.linger_until {
+            return Some(at.saturating_duration_since(now));
+        }
+        let retry = self
+            .client
+            .iter()
+            .filter_map(|(_, client)| client.retry_at())
+            .map(|at| at.saturating_duration_since(now))
+            .min();
+        let throttle = match self.limiter.as_mut() {
+            Some(limiter) if self.throttled => Some(limiter.wait_time()),
+            _ => None,
+        };
+        let deadline = self
+            .client
+            .iter()
+            .filter_map(|(_, client)| client.deadline())
+            .map(|at| at.saturating_duration_since(now))
+            .min();
+        let idle = self
+            .config
+            .idle_timeout
+            .map(|timeout| (self.last_active + timeout).saturating_duration_since(now));
+        let sample = match self.config.throughput_interval {
+            Some(interval) if self.established => {
+                Some((self.sampled.0 + interval).saturating_duration_since(now))
+            }
+            _ => None,
+        };
+        [retry, deadline, throttle, idle, sample]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    // Runs the work due after a poll timeout.
+    pub fn tick(&mut self, registry: &Registry) -> Result<Step, ProxyError> {
+        if let Some(at) = self.linger_until {
+            if Instant::now() < at {
+                return Ok(Step::Yield);
+            }
+            debug!(
+                "[#{}] Dropping {} reply bytes the client didn't take",
+                self.id,
+                self.pending()
+            );
+            return Ok(Step::Close);
+        }
+        if let Some(timeout) = self.config.idle_timeout {
+            if self.last_active.elapsed() >= timeout {
+                info!("[#{}] Closing idle connection", self.id);
+                self.close_as(CloseReason::IdleTimeout);
+                return Ok(Step::Close);
+            }
+        }
+        let now = Instant::now();
+        if let Some(interval) = self.config.throughput_interval {
+            if self.established && now >= self.sampled.0 + interval {
+                self.log_throughput(now);
+            }
+        }
+        let hung = self
+            .client
+            .iter()
+            .any(|(_, client)| client.deadline().is_some_and(|at| at <= now));
+        if hung {
+            connection_failure(self, 0x04)?;
+            let route = self.route.as_deref().unwrap_or("unknown");
+            return self.closing(Err(ProxyError::Timeout(format!(
+                "upstream {} did not answer the CONNECT",
+                route
+            ))));
+        }
+        let failed = self
+            .client
+            .iter_mut()
+            .any(|(_, client)| !matches!(client.retry(registry), Ok(Step::Yield)));
+        if failed {
+            self.connect_failed(None)?;
+            return Ok(Step::Close);
+        }
+        self.resume(registry)?;
+        Ok(Step::Continue)
+    }
+
+    // KB/s in each direction since the last sample and on average since the
+    // tunnel was answered, up is what the client sent.
+    fn log_throughput(&mut self, now: Instant) {
+        let (at, intotal, outtotal) = self.sampled;
+        let rate = |bytes: usize, since: Instant| {
+            let secs = now.saturating_duration_since(since).as_secs_f64();
+            if secs > 0.0 {
+                bytes as f64 / 1024.0 / secs
+            } else {
+                0.0
+            }
+        };
+        let answered = self.phases.answered.unwrap_or(self.start);
+        debug!(
+            "[#{}] Throughput to {}:{}: up {:.1} KB/s (avg {:.1}), down {:.1} KB/s (avg {:.1})",
+            self.id,
+            self.target.domain,
+            self.target.port,
+            rate(self.intotal - intotal, at),
+            rate(self.intotal, answered),
+            rate(self.outtotal - outtotal, at),
+            rate(self.outtotal, answered)
+        );
+        self.sampled = (now, self.intotal, self.outtotal);
+    }
+
+    // Re-arm the sockets of a throttled relay once the bucket has refilled,
+    // so the edge-triggered poll reports the data we left unread.
+    fn resume(&mut self, registry: &Registry) -> io::Result<()> {
+        if !self.throttled || self.quota() == 0 {
+            return Ok(());
+        }
+        debug!("[#{}] SOCKS5 relay resumed", self.id);
+        self.throttled = false;
+        registry.reregister(
+            &mut self.stream,
+            self.token,
+            Interest::READABLE.add(Interest::WRITABLE),
+        )?;
+        for (_, client) in self.client.iter_mut() {
+            client.reregister(registry)?;
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self, registry: &Registry) {
+        self.closed = true;
+        if self.state == Socks5State::WaitingUpstream {
+            self.gate.forget(self.token);
+        }
+        self.release_slot();
+        debug!(
+            "[#{}] Closing client connection, {}{}",
+            self.id,
+            self.close_reason(),
+            fd_note(&self.stream)
+        );
+        if let Err(err) = registry.deregister(&mut self.stream) {
+            debug!("[#{}] SOCKS5 deregister failed: {}", self.id, err);
+        }
+        for (_, client) in self.client.iter_mut() {
+            client.deregister(registry);
+        }
+    }
+
+    // How long the connection lasted, and took until the first byte from
+    // the upstream.
+    pub fn lifetime(&self) -> (Duration, Option<Duration>) {
+        let first_byte = self
+            .phases
+            .first_byte
+            .map(|at| at.saturating_duration_since(self.start));
+        (self.start.elapsed(), first_byte)
+    }
+
+    pub fn access_entry(&self) -> AccessLogEntry {
+        let client = self.peer.to_string();
+        let status = if self.established {
+            "relayed"
+        } else {
+            "failed"
+        };
+        AccessLogEntry {
+            timestamp: AccessLogEntry::now(),
+            id: self.id,
+            client,
+            domain: self.target.domain.clone(),
+            port: self.target.port,
+            upstream: self.route.clone(),
+            upstream_status: self.upstream_status.clone(),
+            status,
+            reason: self.close_reason().name(),
+            bytes_in: self.intotal as u64,
+            bytes_out: self.outtotal as u64,
+            duration: self.start.elapsed().as_secs_f64(),
+            timings: self.phases.timings(),
+        }
     }
 
     fn would_block(err: &io::Error) -> bool {
@@ -238,3 +1075,22 @@ impl Socks5Handler<HttpClient> {
         err.kind() == io::ErrorKind::Interrupted
     }
 }
+
+// Safety net for handlers dropped without going through close, e.g. while
+// unwinding from a panic or when serve bails out early.
+impl<T> Drop for Socks5Handler<T> {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        warn!(
+            "[#{}] SOCKS5 connection to {}:{} dropped without being closed",
+            self.id, self.target.domain, self.target.port
+        );
+        if let Err(err) = self.stream.shutdown(Shutdown::Both) {
+            debug!("[#{}] SOCKS5 shutdown failed: {}", self.id, err);
+        }
+        // Dropping the clients closes their sockets
+        self.client.clear();
+    }
+}
@@ -0,0 +1,49 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+
+use log::{debug, info, warn};
+use mio::net::TcpListener;
+use mio::{Interest, Registry, Token};
+
+// Plain TCP port for load balancers: every connection gets `OK` and the
+// number of active SOCKS connections, then is closed. Nothing is read.
+pub struct Health {
+    listener: TcpListener,
+    token: Token,
+}
+
+impl Health {
+    pub fn bind(addr: SocketAddr, registry: &Registry, token: Token) -> io::Result<Self> {
+        let mut listener = TcpListener::bind(addr).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to bind health check {}: {}", addr, err),
+            )
+        })?;
+        registry.register(&mut listener, token, Interest::READABLE)?;
+        info!("Health check listening on {}", addr);
+        Ok(Self { listener, token })
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        token == self.token
+    }
+
+    // The status is tiny, a fresh socket takes it in one write or not at all.
+    pub fn accept(&mut self, active: usize) {
+        let status = format!("OK\nactive {}\n", active);
+        loop {
+            let mut stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    warn!("Failed to accept health check connection: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = stream.write_all(status.as_bytes()) {
+                debug!("Health check write failed: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use log::debug;
+
+use crate::datatype::Target;
+use crate::error::{ProxyError, Step};
+use crate::upstream::Client;
+
+use super::handler::Socks5Handler;
+use super::handler::Socks5State;
+use super::parse::{parse_http_connect, Address};
+use super::server_protocol::{parse_failure, request_target, resolve_target};
+
+// Inbound HTTP proxy clients send a CONNECT instead of a SOCKS greeting,
+// read by method_request like a SOCKS4 request. Everything after the
+// target is shared with SOCKS.
+pub fn connection_request(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] HTTP Server Connection Request", handler.id);
+
+    let request = match parse_http_connect(
+        &handler.buffer[..handler.size],
+        handler.config.max_domain_len,
+    ) {
+        Ok(request) => request,
+        Err(err) => return parse_failure(handler, err),
+    };
+
+    let mut target: Target = Target::new();
+    match request.address {
+        Address::Domain(domain) => {
+            debug!("[#{}] Requested domain: {}", handler.id, domain);
+            target.port = request.port;
+            target.domain = domain;
+            resolve_target(handler, target)
+        }
+        Address::Ip(ip) => {
+            target.set_candidates(&[ip], request.port, None);
+            target.domain = target.ip.clone();
+            request_target(handler, target)
+        }
+    }
+}
+
+pub fn connection_response(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] HTTP Server Connection Response", handler.id);
+
+    let result = write_response(handler, "HTTP/1.1 200 Connection established\r\n\r\n");
+    handler.set_state(Socks5State::Relaying);
+
+    result
+}
+
+pub fn connection_failure(
+    handler: &mut Socks5Handler<Client>,
+    rep: u8,
+) -> Result<Step, ProxyError> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        status_for_reply(rep)
+    );
+    write_response(handler, &response)
+}
+
+// Maps the SOCKS5 REP a request failed with to the status sent instead.
+fn status_for_reply(rep: u8) -> &'static str {
+    match rep {
+        0x02 => "403 Forbidden",
+        0x07 => "501 Not Implemented",
+        0x08 => "400 Bad Request",
+        _ => "502 Bad Gateway",
+    }
+}
+
+fn write_response(handler: &mut Socks5Handler<Client>, response: &str) -> Result<Step, ProxyError> {
+    handler.reset_buffer();
+    for byte in response.bytes() {
+        handler.put_buffer(byte);
+    }
+
+    Ok(handler.write_stream()?)
+}
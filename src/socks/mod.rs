@@ -1,3 +1,12 @@
+pub mod admin;
+pub mod client;
+mod client_protocol;
+pub mod gate;
 pub mod handler;
+pub mod health;
+mod http_protocol;
+pub mod parse;
 pub mod server;
 mod server_protocol;
+mod socks4_protocol;
+pub mod tokens;
@@ -0,0 +1,350 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::datatype::valid_hostname;
+
+// HTTP request heads longer than this are refused instead of buffered.
+const MAX_HTTP_HEAD: usize = 8192;
+
+// Why a frame could not be taken as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    // More bytes are needed, the frame may arrive over several reads.
+    Incomplete,
+    // Ends the connection without a reply.
+    Protocol(String),
+    // Answered with the REP before closing.
+    Reply(u8, String),
+}
+
+// SOCKS5 greeting, the methods offered by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodRequest {
+    pub methods: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionRequest {
+    pub cmd: u8,
+    pub address: Address,
+    pub port: u16,
+}
+
+// RFC 1929 username/password request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub username: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+// SOCKS4 request, SOCKS4a hostnames come as a domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks4Request {
+    pub cmd: u8,
+    pub address: Address,
+    pub port: u16,
+    pub userid: Vec<u8>,
+}
+
+// CONNECT of an inbound HTTP proxy client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConnectRequest {
+    pub address: Address,
+    pub port: u16,
+}
+
+pub fn parse_method_request(buffer: &[u8]) -> Result<MethodRequest, ParseError> {
+    match buffer.first() {
+        None => return Err(ParseError::Incomplete),
+        Some(0x05) => {}
+        Some(_) => return Err(protocol("unsupported SOCKS version")),
+    }
+    let len = method_request_len(buffer).ok_or(ParseError::Incomplete)?;
+    if buffer.len() != len {
+        return Err(protocol("truncated request detected"));
+    }
+    Ok(MethodRequest {
+        methods: buffer[2..len].to_vec(),
+    })
+}
+
+// Domains are checked against the hostname charset and `max_domain_len`,
+// the command is left to the caller.
+pub fn parse_connection_request(
+    buffer: &[u8],
+    max_domain_len: usize,
+) -> Result<ConnectionRequest, ParseError> {
+    match buffer.first() {
+        None => return Err(ParseError::Incomplete),
+        Some(0x05) => {}
+        Some(_) => return Err(protocol("unsupported SOCKS version")),
+    }
+    let len = connection_request_len(buffer).ok_or(ParseError::Incomplete)?;
+    if buffer[2] != 0x00 {
+        return Err(protocol("unexpected SOCKS RSV detected"));
+    }
+    let address = match buffer[3] {
+        1 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&buffer[4..8]);
+            Address::Ip(Ipv4Addr::from(ip).into())
+        }
+        4 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&buffer[4..20]);
+            Address::Ip(Ipv6Addr::from(ip).into())
+        }
+        3 => {
+            let domain_len = buffer[4] as usize;
+            if domain_len == 0 {
+                return Err(protocol("truncated request detected"));
+            }
+            match String::from_utf8(buffer[5..5 + domain_len].to_vec()) {
+                Ok(s) if !valid_hostname(&s, max_domain_len) => {
+                    return Err(ParseError::Reply(
+                        0x08,
+                        format!("Invalid request domain: {:?}", s),
+                    ))
+                }
+                Ok(s) => Address::Domain(s),
+                Err(_) => return Err(protocol("unexpected request domain detected")),
+            }
+        }
+        _ => return Err(protocol("unexpected request ATYP detected")),
+    };
+    Ok(ConnectionRequest {
+        cmd: buffer[1],
+        address,
+        port: u16::from_be_bytes([buffer[len - 2], buffer[len - 1]]),
+    })
+}
+
+pub fn parse_auth_request(buffer: &[u8]) -> Result<AuthRequest, ParseError> {
+    match buffer.first() {
+        None => return Err(ParseError::Incomplete),
+        Some(0x01) => {}
+        Some(_) => return Err(protocol("unsupported SOCKS5 auth version")),
+    }
+    auth_request_len(buffer).ok_or(ParseError::Incomplete)?;
+    let ulen = buffer[1] as usize;
+    let plen = buffer[2 + ulen] as usize;
+    Ok(AuthRequest {
+        username: buffer[2..2 + ulen].to_vec(),
+        password: buffer[3 + ulen..3 + ulen + plen].to_vec(),
+    })
+}
+
+// Only the version byte is expected to be checked by the caller, it is
+// how SOCKS4 gets told apart from SOCKS5.
+pub fn parse_socks4_request(
+    buffer: &[u8],
+    max_domain_len: usize,
+) -> Result<Socks4Request, ParseError> {
+    if buffer.len() < 9 {
+        return Err(ParseError::Incomplete);
+    }
+    let cmd = buffer[1];
+    if cmd != 0x01 {
+        return Err(ParseError::Reply(
+            0x5B,
+            format!("Unsupported SOCKS4 CD: {}", cmd),
+        ));
+    }
+    let port = u16::from_be_bytes([buffer[2], buffer[3]]);
+    let ip = Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]);
+    let userid = null_terminated(&buffer[8..]).ok_or(ParseError::Incomplete)?;
+
+    // SOCKS4a marks a trailing hostname with the invalid address 0.0.0.x
+    let octets = ip.octets();
+    let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let rest = &buffer[8 + userid.len() + 1..];
+        let domain = null_terminated(rest).ok_or(ParseError::Incomplete)?;
+        match String::from_utf8(domain.to_vec()) {
+            Ok(s) if !valid_hostname(&s, max_domain_len) => {
+                return Err(ParseError::Reply(
+                    0x5B,
+                    format!("Invalid request domain: {:?}", s),
+                ))
+            }
+            Ok(s) => Address::Domain(s),
+            Err(_) => return Err(protocol("unexpected request domain detected")),
+        }
+    } else {
+        Address::Ip(ip.into())
+    };
+    Ok(Socks4Request {
+        cmd,
+        address,
+        port,
+        userid: userid.to_vec(),
+    })
+}
+
+// Only CONNECT is taken, other methods are answered with the REP for an
+// unsupported command. Headers are not looked at.
+pub fn parse_http_connect(
+    buffer: &[u8],
+    max_domain_len: usize,
+) -> Result<HttpConnectRequest, ParseError> {
+    let end = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None if buffer.len() < MAX_HTTP_HEAD => return Err(ParseError::Incomplete),
+        None => return Err(protocol("HTTP request head too large")),
+    };
+    let head = std::str::from_utf8(&buffer[..end])
+        .map_err(|_| protocol("unexpected HTTP request detected"))?;
+    let line = head.split("\r\n").next().unwrap_or_default();
+    let mut parts = line.split(' ');
+    let (method, authority) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(authority), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, authority)
+        }
+        _ => return Err(protocol("unexpected HTTP request line detected")),
+    };
+    if method != "CONNECT" {
+        return Err(ParseError::Reply(
+            0x07,
+            format!("Unsupported HTTP method: {}", method),
+        ));
+    }
+    let invalid = || ParseError::Reply(0x08, format!("Invalid CONNECT authority: {:?}", authority));
+    let (host, port) = authority.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let address = match host.parse::<IpAddr>() {
+        Ok(ip) => Address::Ip(ip),
+        Err(_) if valid_hostname(host, max_domain_len) => Address::Domain(String::from(host)),
+        Err(_) => return Err(invalid()),
+    };
+    Ok(HttpConnectRequest { address, port })
+}
+
+// Server name a TLS ClientHello asks for (RFC 6066), None when it has no
+// SNI. Only the first record is looked at, the ClientHello is expected to
+// fit in it as it does in practice.
+pub fn parse_client_hello_sni(buffer: &[u8]) -> Result<Option<String>, ParseError> {
+    match buffer.first() {
+        None => return Err(ParseError::Incomplete),
+        Some(0x16) => {}
+        Some(_) => return Err(protocol("not a TLS handshake")),
+    }
+    let len = match buffer.get(3..5) {
+        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => return Err(ParseError::Incomplete),
+    };
+    let record = buffer.get(5..5 + len).ok_or(ParseError::Incomplete)?;
+    if record.first() != Some(&0x01) {
+        return Err(protocol("not a TLS ClientHello"));
+    }
+    client_hello_sni(&mut Reader(record)).ok_or_else(|| protocol("malformed TLS ClientHello"))
+}
+
+// Handshake header, legacy version, random, session id, cipher suites and
+// compression methods come before the extensions.
+fn client_hello_sni(reader: &mut Reader) -> Option<Option<String>> {
+    reader.u8()?;
+    let len = reader.u24()?;
+    let mut hello = Reader(reader.take(len)?);
+    hello.take(2 + 32)?;
+    let len = hello.u8()? as usize;
+    hello.take(len)?;
+    let len = hello.u16()? as usize;
+    hello.take(len)?;
+    let len = hello.u8()? as usize;
+    hello.take(len)?;
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if kind != 0x0000 {
+            continue;
+        }
+        let mut names = Reader(data);
+        let len = names.u16()? as usize;
+        let mut names = Reader(names.take(len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == 0x00 {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|name| Some(String::from(name)));
+            }
+        }
+    }
+    Some(None)
+}
+
+// Big-endian fields from the front of a slice, None past its end.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+}
+
+fn null_terminated(buffer: &[u8]) -> Option<&[u8]> {
+    buffer.iter().position(|b| *b == 0).map(|i| &buffer[..i])
+}
+
+fn protocol(reason: &str) -> ParseError {
+    ParseError::Protocol(String::from(reason))
+}
+
+// Length of the SOCKS5 greeting, None while its methods are still missing.
+fn method_request_len(buffer: &[u8]) -> Option<usize> {
+    let len = 2 + *buffer.get(1)? as usize;
+    (buffer.len() >= len).then_some(len)
+}
+
+// Length of the SOCKS5 request, None while it is still incomplete. An
+// unknown ATYP is complete after the header so it gets rejected right away.
+fn connection_request_len(buffer: &[u8]) -> Option<usize> {
+    let len = match *buffer.get(3)? {
+        1 => 10,
+        3 => 7 + *buffer.get(4)? as usize,
+        4 => 22,
+        _ => 4,
+    };
+    (buffer.len() >= len).then_some(len)
+}
+
+// Length of the auth request, None while the credentials are incomplete.
+fn auth_request_len(buffer: &[u8]) -> Option<usize> {
+    let ulen = *buffer.get(1)? as usize;
+    let len = 3 + ulen + *buffer.get(2 + ulen)? as usize;
+    (buffer.len() >= len).then_some(len)
+}
@@ -1,130 +1,1218 @@
 use fnv::FnvHashMap;
 use log::{debug, error, info, warn};
-use mio::{net::TcpListener, Events, Interest, Poll, Token};
+use mio::{
+    net::{TcpListener, TcpSocket},
+    Events, Interest, Poll, Registry, Token, Waker,
+};
 use slab::Slab;
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{proxy::Proxy, socks::handler::Socks5Handler};
+use crate::{
+    accesslog::{AccessLog, AccessLogFormat},
+    acl::{AccessList, DestinationRules, DirectRules},
+    breaker::{BreakerConfig, CircuitBreaking},
+    check::{check, Answer},
+    config::{self, Config, ConfigFile},
+    datatype::{BndMode, IpFamily, Target},
+    dns::{DnsProtocol, DnsResolver},
+    error::{disconnected, CloseReason, ProxyError, Step},
+    fd::fd_note,
+    histogram::Histogram,
+    http::{HostStyle, HttpVersion},
+    keepalive::Keepalive,
+    outbound::Outbound,
+    proxy::{Proxy, ProxyProtocol},
+    selector::{FirstAvailable, UpstreamSelector},
+    socks::{
+        admin::{Admin, Command, ADMIN_SESSIONS},
+        gate::UpstreamGate,
+        handler::Socks5Handler,
+        health::Health,
+        tokens::TokenPool,
+    },
+    timers::Timers,
+    upstream::Client,
+};
+#[cfg(unix)]
+use crate::{privileges, signal};
 
-const SERVER: Token = Token(0);
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// Full polls in a row before the event capacity is doubled, up to
+// MAX_EVENT_GROWTH times the configured one.
+const FULL_POLLS: usize = 8;
+const MAX_EVENT_GROWTH: usize = 16;
+// Connections the slab and maps are sized for up front at most, a huge
+// --max-conns shouldn't allocate it all at startup.
+const MAX_PRESIZE: usize = 64 * 1024;
 
 pub struct Socks5Server {
-    ip: String,
-    port: u16,
     addr: SocketAddr,
-    subproxy: Vec<Proxy>,
+    backlog: u32,
+    config: Config,
+    selector: Option<Box<dyn UpstreamSelector>>,
+    slab: Slab<Socks5Handler<Client>>,
+    handler_map: FnvHashMap<Token, usize>,
+    subtoken: FnvHashMap<Token, Token>,
+    access_log: Option<AccessLog>,
+    active: Arc<AtomicUsize>,
+    // Connections held per source IP, only while it holds any.
+    per_ip: FnvHashMap<IpAddr, usize>,
+    runtime: Option<Runtime>,
 }
 
-impl Socks5Server {
-    pub fn new(proxy: Proxy) -> Self {
-        let ip = proxy.host;
-        let port = proxy.port;
+// State that only exists once the server is set up on a poll.
+struct Runtime {
+    listener: TcpListener,
+    base: Token,
+    resolver: Rc<DnsResolver>,
+    config: Rc<Config>,
+    selector: Rc<dyn UpstreamSelector>,
+    tokens: TokenPool,
+    // Earliest deadline of each handler, keyed by its client token
+    timers: Timers,
+    gate: Rc<UpstreamGate>,
+    // Set on SIGHUP, the config file is re-read before the next batch.
+    hangup: Arc<AtomicBool>,
+    admin: Option<Admin>,
+    health: Option<Health>,
+    next_id: usize,
+    accepting: bool,
+    // Recorded as connections close.
+    durations: Histogram,
+    first_byte: Histogram,
+    next_report: Option<Instant>,
+}
+
+// Chainable configuration of a server, everything not set keeps the
+// defaults of `Config`.
+pub struct Socks5ServerBuilder {
+    addr: SocketAddr,
+    backlog: u32,
+    config: Config,
+    selector: Option<Box<dyn UpstreamSelector>>,
+}
+
+impl Default for Socks5ServerBuilder {
+    fn default() -> Self {
         Self {
-            ip: ip.clone(),
-            port,
-            addr: format!("{}:{}", ip, port).parse().unwrap(),
-            subproxy: Vec::new(),
+            addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+            backlog: 1024,
+            config: Config::default(),
+            selector: None,
         }
     }
+}
 
-    pub fn serve(self) -> io::Result<()> {
-        let mut poll = Poll::new()?;
-        let mut slab = Slab::new();
-        let mut events = Events::with_capacity(1024);
-        let mut server = TcpListener::bind(self.addr).unwrap();
-        let mut handler_map: FnvHashMap<Token, usize> = FnvHashMap::default();
-        let mut subtoken: FnvHashMap<Token, Token> = FnvHashMap::default();
+impl Socks5ServerBuilder {
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    // HTTPProxy accepts HTTP CONNECT requests instead of SOCKS.
+    pub fn inbound(mut self, protocol: ProxyProtocol) -> Self {
+        self.config.inbound = protocol;
+        self
+    }
+
+    // Skips upstreams failing too often, for any selector.
+    pub fn breaker(mut self, config: BreakerConfig) -> Self {
+        self.config.breaker = Some(config);
+        self
+    }
+
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    // Can be repeated, the default selector uses the first upstream.
+    pub fn upstream(mut self, proxy: Proxy) -> Self {
+        self.config.subproxy.push(proxy);
+        self
+    }
+
+    // Tunnels through every upstream in the order they were added.
+    pub fn chain(mut self, enabled: bool) -> Self {
+        self.config.chain = enabled;
+        self
+    }
 
-        info!("Start SOCKS5 server listening on {}:{}", self.ip, self.port);
+    pub fn selector(mut self, selector: Box<dyn UpstreamSelector>) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    // Offers username/password authentication to clients supporting it.
+    pub fn auth(mut self, username: &str, password: &str) -> Self {
+        self.config.auth = Some((String::from(username), String::from(password)));
+        self
+    }
+
+    // Refuses clients not authenticating with the credentials of `auth`.
+    pub fn require_auth(mut self, required: bool) -> Self {
+        self.config.require_auth = required;
+        self
+    }
+
+    // Closes connections without any socket event for the given time.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    // Refuses new connections while this many are held.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    // Refuses new connections from an IP already holding this many.
+    pub fn max_conns_per_ip(mut self, max: usize) -> Self {
+        self.config.max_conns_per_ip = Some(max);
+        self
+    }
+
+    // Takes `stats`, `list` and `kill <id>` commands on a Unix socket.
+    pub fn admin_socket(mut self, path: PathBuf) -> Self {
+        self.config.admin_socket = Some(path);
+        self
+    }
+
+    // Reads rules and upstreams from a JSON file, again on every SIGHUP.
+    pub fn config_file(mut self, path: PathBuf) -> Self {
+        self.config.config_file = Some(path);
+        self
+    }
 
-        poll.registry()
-            .register(&mut server, SERVER, Interest::READABLE)?;
+    pub fn resolver(mut self, server: SocketAddr, protocol: DnsProtocol) -> Self {
+        self.config.dns = Some(server);
+        self.config.dns_protocol = protocol;
+        self
+    }
 
-        let mut unique_token = Token(SERVER.0 + 1);
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
 
+    pub fn build(self) -> Socks5Server {
+        Socks5Server {
+            addr: self.addr,
+            backlog: self.backlog,
+            config: self.config,
+            selector: self.selector,
+            slab: Slab::new(),
+            handler_map: FnvHashMap::default(),
+            subtoken: FnvHashMap::default(),
+            access_log: None,
+            active: Arc::new(AtomicUsize::new(0)),
+            per_ip: FnvHashMap::default(),
+            runtime: None,
+        }
+    }
+}
+
+impl Socks5Server {
+    // Listens on the address of `proxy`, for HTTP clients if its scheme is
+    // http:// and for SOCKS clients otherwise.
+    pub fn new(proxy: Proxy) -> Self {
+        let inbound = match proxy.protocol() {
+            ProxyProtocol::HTTPProxy => ProxyProtocol::HTTPProxy,
+            _ => ProxyProtocol::SOCKS5Proxy,
+        };
+        Socks5Server::builder()
+            .listen(proxy.addr)
+            .inbound(inbound)
+            .build()
+    }
+
+    pub fn builder() -> Socks5ServerBuilder {
+        Socks5ServerBuilder::default()
+    }
+
+    // Runs the server on a poll of its own until an error occurs. A poll
+    // returning as many events as fit leaves the rest for the next one, the
+    // capacity grows when that keeps happening.
+    pub fn serve(mut self) -> io::Result<()> {
+        let mut poll = Poll::new()?;
+        let initial = self.config.event_capacity.max(1);
+        let mut events = Events::with_capacity(initial);
+        self.setup(poll.registry(), Token(0))?;
+        self.drop_privileges()?;
+        let mut full = 0;
         loop {
-            poll.poll(&mut events, None)?;
-
-            for event in events.iter() {
-                match event.token() {
-                    SERVER => loop {
-                        let (mut connection, _) = match server.accept() {
-                            Ok((connection, address)) => (connection, address),
-                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                break;
-                            }
-                            Err(e) => {
-                                error!("Unexpected error: {}", e);
-                                return Err(e);
-                            }
-                        };
+            self.serve_with(&mut poll, &mut events)?;
+            let capacity = events.capacity();
+            full = if events.iter().count() < capacity {
+                0
+            } else {
+                full + 1
+            };
+            if full >= FULL_POLLS && capacity < initial * MAX_EVENT_GROWTH {
+                info!("Growing the event capacity to {}", capacity * 2);
+                events = Events::with_capacity(capacity * 2);
+                full = 0;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn drop_privileges(&self) -> io::Result<()> {
+        let (user, group) = (self.config.user.as_deref(), self.config.group.as_deref());
+        if user.is_none() && group.is_none() {
+            return Ok(());
+        }
+        privileges::drop_privileges(user, group)?;
+        info!(
+            "Dropped privileges, user: {}, group: {}",
+            user.unwrap_or("-"),
+            group.unwrap_or("-")
+        );
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn drop_privileges(&self) -> io::Result<()> {
+        if self.config.user.is_some() || self.config.group.is_some() {
+            warn!("Dropping privileges is only supported on Unix");
+        }
+        Ok(())
+    }
 
-                        let entry = slab.vacant_entry();
-                        let entry_key = entry.key();
-                        let token = Socks5Server::next(&mut unique_token);
-                        connection.set_nodelay(true)?;
-                        poll.registry().register(
-                            &mut connection,
-                            token,
-                            Interest::READABLE.add(Interest::WRITABLE),
-                        )?;
-                        entry.insert(Socks5Handler::new(token, connection, self.subproxy.clone()));
-                        handler_map.insert(token, entry_key);
-                    },
-                    token => {
-                        debug!("Incoming token: {:?}", token);
-                        let handler_key: usize = match handler_map.get(&token) {
-                            Some(k) => *k,
-                            None => {
-                                if let Some(token) = subtoken.get(&token) {
-                                    match handler_map.get(token) {
-                                        Some(k) => *k,
-                                        None => {
-                                            warn!("No available handler for token {}", token.0);
-                                            continue;
-                                        }
+    // The configured selector, or the first subproxy, behind the breaker
+    // when there is one.
+    fn take_selector(&mut self) -> Rc<dyn UpstreamSelector> {
+        let selector: Box<dyn UpstreamSelector> = match self.selector.take() {
+            Some(selector) => selector,
+            None => Box::new(FirstAvailable::new(self.config.subproxy.clone())),
+        };
+        match self.config.breaker {
+            Some(breaker) => Rc::new(CircuitBreaking::new(selector, breaker)),
+            None => selector.into(),
+        }
+    }
+
+    // The flags with the config file applied on top, when there is one.
+    fn load_config(&self) -> io::Result<Config> {
+        let mut config = self.config.clone();
+        if let Some(path) = self.config.config_file.as_deref() {
+            ConfigFile::load(path)
+                .and_then(|file| file.apply(&mut config))
+                .map_err(|err| {
+                    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+                })?;
+        }
+        Ok(config)
+    }
+
+    // Re-reads the config file. Only connections accepted from now on see
+    // the new rules and upstreams, the others keep what they started with.
+    fn reload(&mut self, runtime: &mut Runtime) {
+        let config = match self.load_config() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Failed to reload, keeping the configuration: {}", err);
+                return;
+            }
+        };
+        let changes = config::changes(&runtime.config, &config);
+        runtime.selector = reselect(
+            runtime.selector.clone(),
+            &runtime.config.subproxy,
+            &config.subproxy,
+        );
+        runtime.config = Rc::new(config);
+        if changes.is_empty() {
+            info!("Reloaded the config file, nothing changed");
+        } else {
+            info!("Reloaded the config file: {}", changes.join(", "));
+        }
+    }
+
+    // Where a connection to `target` would go and which rule decided it,
+    // checked in the order a connection is: blocking rules, direct rules,
+    // then the chain or the selector.
+    pub fn explain(mut self, target: &Target) -> String {
+        let flags = self.config.subproxy.clone();
+        match self.load_config() {
+            Ok(config) => self.config = config,
+            Err(err) => return format!("unknown, {}", err),
+        }
+        if let Some(rule) = self.config.rules.blocking(target) {
+            return format!("blocked by {}", rule);
+        }
+        if let Some(rule) = self.config.direct.matching(target) {
+            return format!("direct, --direct matched {}", rule);
+        }
+        if self.config.chain && self.config.subproxy.len() > 1 {
+            let hops: Vec<_> = self
+                .config
+                .subproxy
+                .iter()
+                .map(|proxy| format!("{}:{}", proxy.host, proxy.port))
+                .collect();
+            return format!("chain {}, --chain", hops.join(" > "));
+        }
+        let selector = reselect(self.take_selector(), &flags, &self.config.subproxy);
+        let peer = SocketAddr::from(([0, 0, 0, 0], 0));
+        match selector.explain(target, peer) {
+            Some((proxy, reason)) => format!("upstream {}:{}, {}", proxy.host, proxy.port, reason),
+            None => String::from("refused, no upstream proxy available"),
+        }
+    }
+
+    // Registers the listener and the resolver waker with a poll owned by the
+    // caller. The server takes every token from `base` upward: `base` is the
+    // listener, `base + 1` the resolver, `base + 2` and the ADMIN_SESSIONS
+    // tokens after it the admin socket, the next one the health check and
+    // the rest are handed out to connections, so the caller's own sources
+    // must use tokens below `base`. mio allows a single waker per poll, the
+    // caller must not create another.
+    pub fn setup(&mut self, registry: &Registry, base: Token) -> io::Result<()> {
+        let admin = match self.config.admin_socket.as_deref() {
+            Some(path) => Some(Admin::bind(path, registry, Token(base.0 + 2))?),
+            None => None,
+        };
+        let health_token = Token(base.0 + 3 + ADMIN_SESSIONS);
+        let health = match self.config.health_addr {
+            Some(addr) => Some(Health::bind(addr, registry, health_token)?),
+            None => None,
+        };
+        let mut listener = self.listen()?;
+        registry.register(&mut listener, base, Interest::READABLE)?;
+
+        let waker = Waker::new(registry, Token(base.0 + 1))?;
+        let resolver = Rc::new(DnsResolver::new(
+            self.config.dns,
+            self.config.dns_protocol,
+            self.config.dns_cache,
+            waker,
+        )?);
+        self.access_log = Some(AccessLog::open(
+            self.config.access_log.as_deref(),
+            self.config.access_log_format,
+        )?);
+        let config = self.load_config()?;
+        let selector = reselect(
+            self.take_selector(),
+            &self.config.subproxy,
+            &config.subproxy,
+        );
+        let hangup = Arc::new(AtomicBool::new(false));
+        if self.config.config_file.is_some() {
+            watch_hangup(&hangup, &resolver)?;
+        }
+
+        // Sized for the connection limit so a ramp-up doesn't keep
+        // reallocating, each connection has at least one upstream token
+        if let Some(max) = self.config.max_connections {
+            let capacity = max.min(MAX_PRESIZE);
+            self.slab.reserve(capacity);
+            self.handler_map.reserve(capacity);
+            self.subtoken.reserve(capacity);
+        }
+
+        match self.config.inbound {
+            ProxyProtocol::HTTPProxy => info!("Start HTTP proxy listening on {}", self.addr),
+            _ => info!("Start SOCKS5 server listening on {}", self.addr),
+        }
+
+        self.runtime = Some(Runtime {
+            listener,
+            base,
+            resolver,
+            config: Rc::new(config),
+            selector,
+            tokens: TokenPool::new(Token(health_token.0 + 1)),
+            timers: Timers::new(),
+            gate: Rc::new(UpstreamGate::new(self.config.max_pending_upstream)),
+            hangup,
+            admin,
+            health,
+            next_id: 0,
+            accepting: false,
+            durations: Histogram::new(),
+            first_byte: Histogram::new(),
+            next_report: self
+                .config
+                .latency_report
+                .map(|every| Instant::now() + every),
+        });
+        Ok(())
+    }
+
+    // Polls once, waiting no longer than the server's own timers allow, and
+    // handles the batch. The caller's events stay in `events` afterwards.
+    pub fn serve_with(&mut self, poll: &mut Poll, events: &mut Events) -> io::Result<()> {
+        poll.poll(events, self.timeout())?;
+        self.step(events, poll.registry())
+    }
+
+    // Time until `step` has work to do even without any event.
+    pub fn timeout(&mut self) -> Option<Duration> {
+        let runtime = self.runtime.as_mut()?;
+        let now = Instant::now();
+        let accepting = runtime.accepting;
+        // Queued handlers are admitted by `step`, right away if a slot is free
+        let admitting = runtime.gate.ready();
+        runtime
+            .timers
+            .next_deadline()
+            .map(|at| at.saturating_duration_since(now))
+            .into_iter()
+            .chain(accepting.then_some(ACCEPT_BACKOFF))
+            .chain(admitting.then_some(Duration::ZERO))
+            .chain(
+                runtime
+                    .next_report
+                    .map(|at| at.saturating_duration_since(now)),
+            )
+            .min()
+    }
+
+    // Handles one batch of events, those with tokens below the base given to
+    // `setup` are left alone. Timers are run as well, so this should also be
+    // called once `timeout` has passed.
+    pub fn step(&mut self, events: &Events, registry: &Registry) -> io::Result<()> {
+        let mut runtime = match self.runtime.take() {
+            Some(runtime) => runtime,
+            None => return Err(io::Error::other("SOCKS5 server is not set up")),
+        };
+        let result = self.process(&mut runtime, events, registry);
+        self.runtime = Some(runtime);
+        result
+    }
+
+    fn process(
+        &mut self,
+        runtime: &mut Runtime,
+        events: &Events,
+        registry: &Registry,
+    ) -> io::Result<()> {
+        if runtime.hangup.swap(false, Ordering::Relaxed) {
+            self.reload(runtime);
+        }
+        let server = runtime.base;
+        let resolver_token = Token(runtime.base.0 + 1);
+        // Handlers seen in this batch, their deadlines may have moved
+        let mut touched = Vec::new();
+        for event in events.iter() {
+            match event.token() {
+                token if token < server => continue,
+                token if token == server => runtime.accepting = true,
+                token if runtime.admin.as_ref().is_some_and(|a| a.owns(token)) => {
+                    self.admin(token, runtime, registry);
+                }
+                token if runtime.health.as_ref().is_some_and(|h| h.owns(token)) => {
+                    let active = self.active.load(Ordering::Relaxed);
+                    if let Some(health) = runtime.health.as_mut() {
+                        health.accept(active);
+                    }
+                }
+                token if token == resolver_token => {
+                    while let Some((token, id, ips)) = runtime.resolver.next() {
+                        // The handler that asked may be gone, its token reused
+                        let handler_key = match self.handler_map.get(&token) {
+                            Some(k) if self.slab[*k].id == id => *k,
+                            _ => continue,
+                        };
+                        let handler = &mut self.slab[handler_key];
+                        let result = handler.resolved(
+                            ips,
+                            &mut runtime.tokens,
+                            registry,
+                            &mut self.subtoken,
+                        );
+                        let step = survive(handler, result);
+                        if step == Step::Close && !self.slab[handler_key].linger() {
+                            self.close_handler(handler_key, registry, runtime);
+                        } else {
+                            touched.push(handler_key);
+                        }
+                    }
+                }
+                token => {
+                    debug!("Incoming token: {:?}", token);
+                    let handler_key: usize = match self.handler_map.get(&token) {
+                        Some(k) => *k,
+                        None => {
+                            if let Some(token) = self.subtoken.get(&token) {
+                                match self.handler_map.get(token) {
+                                    Some(k) => *k,
+                                    None => {
+                                        warn!("No available handler for token {}", token.0);
+                                        continue;
                                     }
-                                } else {
-                                    warn!("No available handler for token {}", token.0);
-                                    continue;
                                 }
-                            }
-                        };
-
-                        let handler = match slab.get_mut(handler_key) {
-                            Some(h) => h,
-                            None => {
-                                subtoken.remove(&token);
+                            } else {
+                                warn!("No available handler for token {}", token.0);
                                 continue;
                             }
-                        };
-                        let done = handler.handle(
-                            event,
-                            token,
-                            &mut unique_token,
-                            poll.registry(),
-                            &mut subtoken,
-                        )?;
-
-                        if done {
-                            slab.remove(handler_key);
-                            handler_map.remove(&token);
-                            subtoken.remove(&token);
+                        }
+                    };
+
+                    let handler = match self.slab.get_mut(handler_key) {
+                        Some(h) => h,
+                        None => {
+                            self.subtoken.remove(&token);
+                            continue;
+                        }
+                    };
+                    let result = handler.handle(
+                        event,
+                        token,
+                        &mut runtime.tokens,
+                        registry,
+                        &mut self.subtoken,
+                    );
+                    let step = survive(handler, result);
+
+                    if step == Step::Close && !self.slab[handler_key].linger() {
+                        self.close_handler(handler_key, registry, runtime);
+                    } else {
+                        touched.push(handler_key);
+                    }
+                }
+            }
+        }
+
+        self.accept(runtime, registry);
+
+        for token in runtime.timers.expired(Instant::now()) {
+            let key = match self.handler_map.get(&token) {
+                Some(key) => *key,
+                None => continue,
+            };
+            let result = self.slab[key].tick(registry);
+            let step = survive(&mut self.slab[key], result);
+            if step == Step::Close && !self.slab[key].linger() {
+                self.close_handler(key, registry, runtime);
+            } else {
+                touched.push(key);
+            }
+        }
+        for key in touched {
+            self.reschedule(key, &mut runtime.timers);
+        }
+        self.admit(runtime, registry)?;
+        self.report_latency(runtime);
+        // Closed sockets were deregistered, the next poll can't report them
+        runtime.tokens.recycle();
+        Ok(())
+    }
+
+    // Hands the slots freed by this batch to the queued handlers in order.
+    fn admit(&mut self, runtime: &mut Runtime, registry: &Registry) -> io::Result<()> {
+        while let Some(token) = runtime.gate.admit() {
+            let key = match self.handler_map.get(&token) {
+                Some(key) => *key,
+                None => {
+                    runtime.gate.release();
+                    continue;
+                }
+            };
+            let handler = &mut self.slab[key];
+            let result = handler.admitted(&mut runtime.tokens, registry, &mut self.subtoken);
+            let step = survive(handler, result);
+            if step == Step::Close && !self.slab[key].linger() {
+                self.close_handler(key, registry, runtime);
+            } else {
+                self.reschedule(key, &mut runtime.timers);
+            }
+        }
+        Ok(())
+    }
+
+    // Arms the timer of a handler for the earliest of its deadlines.
+    fn reschedule(&mut self, key: usize, timers: &mut Timers) {
+        if let Some(handler) = self.slab.get_mut(key) {
+            match handler.wait_time() {
+                Some(wait) => timers.schedule(handler.token, Instant::now() + wait),
+                None => timers.cancel(handler.token),
+            }
+        }
+    }
+
+    fn admin(&mut self, token: Token, runtime: &mut Runtime, registry: &Registry) {
+        let mut admin = match runtime.admin.take() {
+            Some(admin) => admin,
+            None => return,
+        };
+        for command in admin.handle(token, registry) {
+            let reply = self.answer(command, runtime, registry);
+            admin.reply(token, &reply);
+        }
+        admin.flush(token, registry);
+        runtime.admin = Some(admin);
+    }
+
+    // Answers an admin command from the state of the running server.
+    fn answer(
+        &mut self,
+        command: Result<Command, String>,
+        runtime: &mut Runtime,
+        registry: &Registry,
+    ) -> String {
+        match command {
+            Ok(Command::Stats) => {
+                let mut stats = format!(
+                    "active {}\naccepted {}\n",
+                    self.active.load(Ordering::Relaxed),
+                    runtime.next_id
+                );
+                for (name, histogram) in [
+                    ("duration", &runtime.durations),
+                    ("first_byte", &runtime.first_byte),
+                ] {
+                    for (label, quantile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+                        if let Some(value) = histogram.percentile(quantile) {
+                            stats.push_str(&format!(
+                                "{}_{} {:.3}\n",
+                                name,
+                                label,
+                                value.as_secs_f64()
+                            ));
                         }
                     }
                 }
+                if let Some((hits, misses)) = runtime.resolver.cache_stats() {
+                    stats.push_str(&format!(
+                        "dns_cache_hits {}\ndns_cache_misses {}\n",
+                        hits, misses
+                    ));
+                }
+                stats
+            }
+            Ok(Command::List) => self
+                .slab
+                .iter()
+                .map(|(_, handler)| {
+                    let entry = handler.access_entry();
+                    format!(
+                        "{} {} {}:{} {} {} {} {:.3}s\n",
+                        entry.id,
+                        entry.client,
+                        entry.domain,
+                        entry.port,
+                        entry.upstream.as_deref().unwrap_or("-"),
+                        entry.bytes_in,
+                        entry.bytes_out,
+                        entry.duration
+                    )
+                })
+                .collect(),
+            Ok(Command::Kill(id)) => match self.slab.iter().find(|(_, handler)| handler.id == id) {
+                Some((key, _)) => {
+                    info!("[#{}] Closed through the admin socket", id);
+                    self.slab[key].close_as(CloseReason::PolicyDenied);
+                    self.close_handler(key, registry, runtime);
+                    format!("killed {}\n", id)
+                }
+                None => format!("error: no connection {}\n", id),
+            },
+            Err(err) => format!("error: {}\n", err),
+        }
+    }
+
+    // Drain the pending connections. When out of file descriptors the rest
+    // stays queued and is retried after a short backoff, since the
+    // edge-triggered listener won't report them again.
+    fn accept(&mut self, runtime: &mut Runtime, registry: &Registry) {
+        let config = runtime.config.clone();
+        while runtime.accepting {
+            let (mut connection, address) = match runtime.listener.accept() {
+                Ok((connection, address)) => (connection, address),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    runtime.accepting = false;
+                    break;
+                }
+                Err(e) if Socks5Server::exhausted(&e) => {
+                    warn!("Failed to accept connection, retrying later: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            if !config.acl.permits(address.ip()) {
+                info!("Rejected connection from {}", address);
+                continue;
+            }
+            if let Some(max) = config.max_connections {
+                if self.active.load(Ordering::Relaxed) >= max {
+                    warn!(
+                        "Rejected connection from {}, limit of {} reached",
+                        address, max
+                    );
+                    continue;
+                }
+            }
+            if let Some(max) = config.max_conns_per_ip {
+                if self.per_ip.get(&address.ip()).copied().unwrap_or(0) >= max {
+                    warn!(
+                        "Rejected connection from {}, limit of {} per IP reached",
+                        address, max
+                    );
+                    continue;
+                }
+            }
+
+            let token = runtime.tokens.take();
+            let keepalive = config.keepalive;
+            if let Err(e) = connection
+                .set_nodelay(config.inbound_nodelay)
+                .and_then(|_| keepalive.map_or(Ok(()), |k| k.apply(&connection)))
+                .and_then(|_| {
+                    registry.register(
+                        &mut connection,
+                        token,
+                        Interest::READABLE.add(Interest::WRITABLE),
+                    )
+                })
+            {
+                error!("Failed to set up connection from {}: {}", address, e);
+                runtime.tokens.release(token);
+                continue;
+            }
+            runtime.next_id += 1;
+            debug!(
+                "[#{}] Accepted connection from {}{}",
+                runtime.next_id,
+                address,
+                fd_note(&connection)
+            );
+            let entry_key = self.slab.insert(Socks5Handler::new(
+                runtime.next_id,
+                token,
+                (connection, address),
+                config.clone(),
+                runtime.resolver.clone(),
+                runtime.selector.clone(),
+                runtime.gate.clone(),
+            ));
+            self.handler_map.insert(token, entry_key);
+            self.reschedule(entry_key, &mut runtime.timers);
+            self.active.fetch_add(1, Ordering::Relaxed);
+            // Counted under the accepted address, which the handler keeps
+            // for close even once its socket was reset
+            if config.max_conns_per_ip.is_some() {
+                *self.per_ip.entry(address.ip()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    // The single teardown point of a connection: removes the handler along
+    // with every token and timer pointing at it before handing the tokens
+    // back to the pool, deregisters its sockets so no stale events get
+    // routed afterwards and updates the active gauge.
+    fn close_handler(&mut self, key: usize, registry: &Registry, runtime: &mut Runtime) {
+        if !self.slab.contains(key) {
+            return;
+        }
+        let mut handler = self.slab.remove(key);
+        let tokens = &mut runtime.tokens;
+        self.handler_map.remove(&handler.token);
+        runtime.timers.cancel(handler.token);
+        tokens.release(handler.token);
+        for (_, client) in handler.client.iter() {
+            if let Some(token) = client.token() {
+                self.subtoken.remove(&token);
+                tokens.release(token);
+            }
+        }
+        handler.close(registry);
+        let (duration, first_byte) = handler.lifetime();
+        runtime.durations.record(duration);
+        if let Some(first_byte) = first_byte {
+            runtime.first_byte.record(first_byte);
+        }
+        if let Some(access_log) = self.access_log.as_mut() {
+            access_log.record(&handler.access_entry());
+        }
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        let ip = handler.peer().ip();
+        if let Some(count) = self.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&ip);
             }
         }
     }
 
+    fn report_latency(&self, runtime: &mut Runtime) {
+        let (at, every) = match (runtime.next_report, self.config.latency_report) {
+            (Some(at), Some(every)) if at <= Instant::now() => (at, every),
+            _ => return,
+        };
+        // A late report doesn't bring on a burst of catching up ones
+        let next = at + every;
+        runtime.next_report = Some(next.max(Instant::now() + every / 2));
+        info!(
+            "Durations of {} closed connections: {}, first byte: {}",
+            runtime.durations.count(),
+            runtime.durations.summary(),
+            runtime.first_byte.summary()
+        );
+    }
+
+    // Number of connections currently held, readable from other threads
+    // while the server runs.
+    pub fn active(&self) -> Arc<AtomicUsize> {
+        self.active.clone()
+    }
+
+    // Runs the tunnel handshake against every upstream instead of serving,
+    // returns whether all of them answered.
+    pub fn check(&self, timeout: Duration) -> bool {
+        let config = match self.load_config() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("{}", err);
+                return false;
+            }
+        };
+        let mut reachable = true;
+        // Upstreams from the config file replace those of the selector.
+        let proxies = match self.selector.as_ref() {
+            Some(selector) if config.config_file.is_none() => selector.proxies(),
+            _ => config.subproxy.iter().collect(),
+        };
+        for proxy in proxies {
+            match check(proxy, &config, timeout) {
+                Ok(Answer::Unauthorized) => {
+                    error!("Upstream {} requires authentication", proxy.addr);
+                    reachable = false;
+                }
+                Ok(Answer::Status(status)) => {
+                    info!("Upstream {} is reachable, status {}", proxy.addr, status)
+                }
+                Ok(Answer::Reply(rep)) => {
+                    info!("Upstream {} is reachable, REP {:#04x}", proxy.addr, rep)
+                }
+                Err(err) => {
+                    error!("Upstream {} is unreachable: {}", proxy.addr, err);
+                    reachable = false;
+                }
+            }
+        }
+        reachable
+    }
+
+    fn listen(&self) -> io::Result<TcpListener> {
+        let socket = if self.addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket
+            .bind(self.addr)
+            .and_then(|_| socket.listen(self.backlog))
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("Failed to bind {}: {}", self.addr, err))
+            })
+    }
+
+    // Accept errors caused by resource limits, which go away on their own
+    // once connections are closed.
+    fn exhausted(err: &io::Error) -> bool {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM)
+        )
+    }
+
+    #[inline]
+    pub fn backlog(&mut self, backlog: u32) {
+        self.backlog = backlog;
+    }
+
+    #[inline]
+    pub fn buffer(&mut self, initial: usize, max: usize) {
+        self.config.buffer_size = initial;
+        self.config.max_buffer = max;
+    }
+
+    // 0 leaves the relay unlimited.
+    #[inline]
+    pub fn rate_limit(&mut self, bytes_per_sec: u64) {
+        self.config.rate_limit = Some(bytes_per_sec).filter(|rate| *rate > 0);
+    }
+
+    #[inline]
+    pub fn upstream_retry(&mut self, retries: u32, delay: Duration) {
+        self.config.upstream_retries = retries;
+        self.config.upstream_retry_delay = delay;
+    }
+
+    #[inline]
+    pub fn prefer(&mut self, family: IpFamily) {
+        self.config.prefer = Some(family);
+    }
+
+    #[inline]
+    pub fn dns(&mut self, server: SocketAddr, protocol: DnsProtocol) {
+        self.config.dns = Some(server);
+        self.config.dns_protocol = protocol;
+    }
+
+    #[inline]
+    pub fn dns_cache(&mut self, size: usize) {
+        self.config.dns_cache = Some(size);
+    }
+
+    #[inline]
+    pub fn outbound(&mut self, outbound: Outbound) {
+        self.config.outbound = Some(outbound);
+    }
+
+    #[inline]
+    pub fn connect_header(&mut self, name: String, value: String) {
+        self.config.connect_headers.push((name, value));
+    }
+
+    #[inline]
+    pub fn connect_host_style(&mut self, style: HostStyle) {
+        self.config.connect_host_style = style;
+    }
+
+    #[inline]
+    pub fn upstream_http_version(&mut self, version: HttpVersion) {
+        self.config.upstream_http_version = version;
+    }
+
+    #[inline]
+    pub fn connect_timeout(&mut self, timeout: Duration) {
+        self.config.connect_timeout = Some(timeout);
+    }
+
+    #[inline]
+    pub fn max_pending_upstream(&mut self, max: usize) {
+        self.config.max_pending_upstream = Some(max);
+    }
+
+    #[inline]
+    pub fn max_domain_len(&mut self, len: usize) {
+        self.config.max_domain_len = len;
+    }
+
+    #[inline]
+    pub fn max_request_size(&mut self, size: usize) {
+        self.config.max_request_size = size;
+    }
+
+    #[inline]
+    pub fn enforce_sni(&mut self, enabled: bool) {
+        self.config.enforce_sni = enabled;
+    }
+
+    #[inline]
+    pub fn advertise_addr(&mut self, addr: SocketAddr) {
+        self.config.advertise_addr = Some(addr);
+    }
+
+    #[inline]
+    pub fn bnd_mode(&mut self, mode: BndMode) {
+        self.config.bnd_mode = mode;
+    }
+
+    #[inline]
+    pub fn send_proxy_protocol(&mut self, enabled: bool) {
+        self.config.send_proxy_protocol = enabled;
+    }
+
+    #[inline]
+    pub fn http_absolute_form(&mut self, enabled: bool) {
+        self.config.http_absolute_form = enabled;
+    }
+
+    #[inline]
+    pub fn event_capacity(&mut self, capacity: usize) {
+        self.config.event_capacity = capacity;
+    }
+
+    #[inline]
+    pub fn health_addr(&mut self, addr: SocketAddr) {
+        self.config.health_addr = Some(addr);
+    }
+
+    #[inline]
+    pub fn user(&mut self, user: &str) {
+        self.config.user = Some(String::from(user));
+    }
+
+    #[inline]
+    pub fn group(&mut self, group: &str) {
+        self.config.group = Some(String::from(group));
+    }
+
+    #[inline]
+    pub fn enable_resolve(&mut self, enabled: bool) {
+        self.config.enable_resolve = enabled;
+    }
+
+    #[inline]
+    pub fn admin_socket(&mut self, path: PathBuf) {
+        self.config.admin_socket = Some(path);
+    }
+
+    #[inline]
+    pub fn config_file(&mut self, path: PathBuf) {
+        self.config.config_file = Some(path);
+    }
+
+    #[inline]
+    pub fn access_log(&mut self, path: Option<PathBuf>, format: AccessLogFormat) {
+        self.config.access_log = path;
+        self.config.access_log_format = format;
+    }
+
+    #[inline]
+    pub fn latency_report(&mut self, interval: Duration) {
+        self.config.latency_report = Some(interval);
+    }
+
+    #[inline]
+    pub fn throughput_interval(&mut self, interval: Duration) {
+        self.config.throughput_interval = Some(interval);
+    }
+
+    #[inline]
+    pub fn breaker(&mut self, config: BreakerConfig) {
+        self.config.breaker = Some(config);
+    }
+
+    #[inline]
+    pub fn nodelay(&mut self, inbound: bool, outbound: bool) {
+        self.config.inbound_nodelay = inbound;
+        self.config.outbound_nodelay = outbound;
+    }
+
+    #[inline]
+    pub fn keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.config.keepalive = keepalive;
+    }
+
+    #[inline]
+    pub fn acl(&mut self, acl: AccessList) {
+        self.config.acl = acl;
+    }
+
+    #[inline]
+    pub fn rules(&mut self, rules: DestinationRules) {
+        self.config.rules = rules;
+    }
+
+    #[inline]
+    pub fn direct(&mut self, direct: DirectRules) {
+        self.config.direct = direct;
+    }
+
+    // Replaces the default of always using the first subproxy.
+    #[inline]
+    pub fn selector(&mut self, selector: Box<dyn UpstreamSelector>) {
+        self.selector = Some(selector);
+    }
+
+    #[inline]
+    pub fn chain(&mut self, enabled: bool) {
+        self.config.chain = enabled;
+    }
+
     #[inline]
     pub fn subproxy(&mut self, proxy: Proxy) {
-        self.subproxy.push(proxy);
+        self.config.subproxy.push(proxy);
+    }
+
+    #[inline]
+    pub fn auth(&mut self, username: &str, password: &str) {
+        self.config.auth = Some((String::from(username), String::from(password)));
+    }
+
+    #[inline]
+    pub fn require_auth(&mut self, required: bool) {
+        self.config.require_auth = required;
     }
 
-    fn next(current: &mut Token) -> Token {
-        let next = current.0;
-        current.0 += 1;
-        Token(next)
+    #[inline]
+    pub fn idle_timeout(&mut self, timeout: Duration) {
+        self.config.idle_timeout = Some(timeout);
+    }
+
+    #[inline]
+    pub fn max_connections(&mut self, max: usize) {
+        self.config.max_connections = Some(max);
+    }
+
+    #[inline]
+    pub fn max_conns_per_ip(&mut self, max: usize) {
+        self.config.max_conns_per_ip = Some(max);
+    }
+}
+
+// A connection failing in a way its handler didn't expect must not stop
+// the others, it is logged and closed.
+// The selector picking from `new` rather than the `old` upstreams, the same
+// one when they didn't change.
+fn reselect(
+    selector: Rc<dyn UpstreamSelector>,
+    old: &[Proxy],
+    new: &[Proxy],
+) -> Rc<dyn UpstreamSelector> {
+    let urls = |proxies: &[Proxy]| -> Vec<String> {
+        proxies
+            .iter()
+            .map(|proxy| String::from(proxy.url()))
+            .collect()
+    };
+    if urls(old) == urls(new) {
+        return selector;
+    }
+    match selector.with_proxies(new.to_vec()) {
+        Some(selector) => selector.into(),
+        None => {
+            warn!("The selector can't be given other upstreams, it is kept as it is");
+            selector
+        }
+    }
+}
+
+#[cfg(unix)]
+fn watch_hangup(hangup: &Arc<AtomicBool>, resolver: &DnsResolver) -> io::Result<()> {
+    signal::on_hangup(hangup.clone(), resolver.waker())
+}
+
+#[cfg(not(unix))]
+fn watch_hangup(_hangup: &Arc<AtomicBool>, _resolver: &DnsResolver) -> io::Result<()> {
+    warn!("Reloading the config file on SIGHUP is only supported on Unix");
+    Ok(())
+}
+
+fn survive(handler: &mut Socks5Handler<Client>, result: Result<Step, ProxyError>) -> Step {
+    let err = match result {
+        Ok(step) => return step,
+        Err(err) => err,
+    };
+    handler.fail_as(&err);
+    match err {
+        ProxyError::Io(err) if disconnected(&err) => debug!(
+            "[#{}] Closing the connection, the peer went away: {}",
+            handler.id, err
+        ),
+        err => error!("[#{}] Closing the connection on {}", handler.id, err),
     }
+    Step::Close
 }
@@ -1,294 +1,677 @@
 use log::{debug, error, info};
+use std::cmp;
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::usize;
-use trust_dns_resolver::config::ResolverConfig;
-use trust_dns_resolver::config::ResolverOpts;
-use trust_dns_resolver::proto::serialize::binary::BinDecodable;
-use trust_dns_resolver::Resolver;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Instant;
 
-use crate::datatype::Target;
-use crate::http::client::HttpClient;
+use crate::datatype::{BndMode, Target};
+use crate::error::{disconnected, CloseReason, ProxyError, Step};
+use crate::upstream::Client;
 
 use super::handler::Socks5Handler;
 use super::handler::Socks5State;
+use super::http_protocol;
+use super::parse::{
+    parse_auth_request, parse_connection_request, parse_method_request, Address, ParseError,
+};
+use super::socks4_protocol;
 
-pub fn method_request(handler: &mut Socks5Handler<HttpClient>) -> io::Result<bool> {
-    debug!("SOCKS5 Server Method Request");
+pub fn method_request(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Method Request", handler.id);
 
-    handler.clear_buffer();
+    // The frame may arrive over several reads, they pile up in the buffer
     match handler.read_stream() {
-        Ok(false) => {}
-        Ok(true) => {
-            debug!("SOCKS5 method request interrupted.");
-            return Ok(true);
+        Ok(Step::Continue) | Ok(Step::Yield) => {}
+        Ok(Step::Close) => {
+            debug!("[#{}] SOCKS5 method request interrupted.", handler.id);
+            return Ok(Step::Close);
         }
         Err(err) => {
-            error!("During SOCKS5 method request, error occured: {}", err);
-            return Err(err);
+            error!(
+                "[#{}] During SOCKS5 method request, error occured: {}",
+                handler.id, err
+            );
+            return Err(err.into());
         }
     }
 
-    let buffer = handler.buffer.as_mut();
     let buffer_len = handler.size;
-
-    if buffer_len < 3 {
-        handler.set_state(Socks5State::Closed);
-        return Ok(true);
+    if buffer_len == 0 {
+        return Ok(Step::Yield);
     }
 
-    let version = buffer[0];
-    let nmethod = buffer[1];
-
-    if version != 0x05 {
-        error!("Unsupported SOCKS version");
-        handler.set_state(Socks5State::Closed);
-        return Ok(true);
+    if handler.http() {
+        return http_protocol::connection_request(handler);
     }
-
-    if buffer_len != (2 + nmethod as usize) {
-        error!("Truncated request detected");
-        handler.set_state(Socks5State::Closed);
-        return Ok(true);
+    let version = handler.buffer[0];
+    if version == 0x04 {
+        return socks4_protocol::connection_request(handler);
     }
 
-    let mut support_no_auth = false;
-    for method in buffer.iter().skip(2).take(nmethod as usize) {
-        if *method == 0x00 {
-            support_no_auth = true;
+    let request = match parse_method_request(&handler.buffer[..buffer_len]) {
+        Ok(request) => request,
+        Err(err) => return parse_failure(handler, err),
+    };
+
+    let methods = &request.methods;
+    let offered = methods
+        .iter()
+        .map(|method| method_name(*method))
+        .collect::<Vec<_>>()
+        .join(", ");
+    debug!("[#{}] SOCKS5 methods offered: {}", handler.id, offered);
+
+    // Username/password is picked whenever credentials are configured and
+    // the client offers it, otherwise no authentication unless it is
+    // required. Anything else, GSSAPI-only clients included, is told no
+    // method is acceptable.
+    let method = match handler.config.auth {
+        Some(_) if methods.contains(&0x02) => 0x02,
+        _ if methods.contains(&0x00) && !handler.config.require_auth => 0x00,
+        _ => {
+            handler.close_as(CloseReason::PolicyDenied);
+            handler.reset_buffer();
+            handler.put_buffer(0x05);
+            handler.put_buffer(0xFF);
+            handler.write_stream()?;
+            handler.set_state(Socks5State::Closed);
+            return Err(ProxyError::Protocol(format!(
+                "no acceptable authentication method, offered: {}",
+                offered
+            )));
         }
-    }
-    if !support_no_auth {
-        handler.set_state(Socks5State::Closed);
-        return Ok(true);
-    }
-
-    debug!("SOCKS5 version:{} nmethod:{}", version, nmethod);
+    };
+    handler.method = method;
+
+    debug!(
+        "[#{}] SOCKS5 version:{} nmethod:{}",
+        handler.id,
+        version,
+        methods.len()
+    );
 
     handler.set_state(Socks5State::MethodResponse);
 
-    Ok(false)
+    Ok(Step::Continue)
+}
+
+fn method_name(method: u8) -> String {
+    match method {
+        0x00 => String::from("none"),
+        0x01 => String::from("GSSAPI"),
+        0x02 => String::from("username/password"),
+        method => format!("{:#04x}", method),
+    }
 }
 
-pub fn method_response(handler: &mut Socks5Handler<HttpClient>) -> io::Result<bool> {
-    debug!("SOCKS5 Server Method Response");
+pub fn method_response(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Method Response", handler.id);
 
     handler.reset_buffer();
     handler.put_buffer(0x05);
-    handler.put_buffer(0x00);
+    handler.put_buffer(handler.method);
 
     let result = handler.write_stream();
-    handler.set_state(Socks5State::ConnectionRequest);
+    handler.clear_buffer();
+    if handler.method == 0x02 {
+        handler.set_state(Socks5State::AuthRequest);
+    } else {
+        handler.set_state(Socks5State::ConnectionRequest);
+    }
 
-    result
+    Ok(result?)
 }
 
-pub fn connection_request(handler: &mut Socks5Handler<HttpClient>) -> io::Result<bool> {
-    debug!("SOCKS5 Server Connection Request");
+// Username/password sub-negotiation of RFC 1929.
+pub fn auth_request(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Auth Request", handler.id);
 
-    handler.clear_buffer();
     match handler.read_stream() {
-        Ok(false) => {}
-        Ok(true) => {
-            debug!("SOCKS5 connection request interrupted");
-            return Ok(true);
+        Ok(Step::Continue) | Ok(Step::Yield) => {}
+        Ok(Step::Close) => {
+            debug!("[#{}] SOCKS5 auth request interrupted", handler.id);
+            return Ok(Step::Close);
         }
         Err(err) => {
-            error!("During SOCKS5 connection request, error occured: {}", err);
-            return Err(err);
+            error!(
+                "[#{}] During SOCKS5 auth request, error occured: {}",
+                handler.id, err
+            );
+            return Err(err.into());
         }
     }
 
-    let buffer = handler.buffer.as_mut();
-    let buffer_len = handler.size;
-
-    let version = buffer[0];
-    let cmd = buffer[1];
-    let rsv = buffer[2];
-    let atyp = buffer[3];
+    let request = match parse_auth_request(&handler.buffer[..handler.size]) {
+        Ok(request) => request,
+        Err(err) => return parse_failure(handler, err),
+    };
+    let (username, password) = (&request.username, &request.password);
+    let accepted = match handler.config.auth.as_ref() {
+        Some((user, pass)) => username == user.as_bytes() && password == pass.as_bytes(),
+        None => false,
+    };
+    if !accepted {
+        error!(
+            "[#{}] SOCKS5 authentication failed for user {}",
+            handler.id,
+            String::from_utf8_lossy(username)
+        );
+    }
 
-    if version != 0x05 {
-        error!("Unsupported SOCKS version");
+    handler.reset_buffer();
+    handler.put_buffer(0x01);
+    handler.put_buffer(if accepted { 0x00 } else { 0x01 });
+    let result = handler.write_stream();
+    handler.clear_buffer();
+    if !accepted {
+        handler.close_as(CloseReason::PolicyDenied);
         handler.set_state(Socks5State::Closed);
-        return Ok(true);
+        return Ok(Step::Close);
     }
+    handler.set_state(Socks5State::ConnectionRequest);
 
-    if cmd != 0x01 {
-        error!("Unsupported SOCKS CMD: {}", cmd);
-        handler.set_state(Socks5State::Closed);
-        return Ok(true);
+    Ok(result?)
+}
+
+pub fn connection_request(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Connection Request", handler.id);
+
+    match handler.read_stream() {
+        Ok(Step::Continue) | Ok(Step::Yield) => {}
+        Ok(Step::Close) => {
+            debug!("[#{}] SOCKS5 connection request interrupted", handler.id);
+            return Ok(Step::Close);
+        }
+        Err(err) => {
+            error!(
+                "[#{}] During SOCKS5 connection request, error occured: {}",
+                handler.id, err
+            );
+            return Err(err.into());
+        }
     }
 
-    if rsv != 0x00 {
-        error!("Unexpected SOCKS RSV detected");
-        handler.set_state(Socks5State::Closed);
-        return Ok(true);
+    let buffer_len = handler.size;
+    let request = match parse_connection_request(
+        &handler.buffer[..buffer_len],
+        handler.config.max_domain_len,
+    ) {
+        Ok(request) => request,
+        Err(err) => return parse_failure(handler, err),
+    };
+
+    // RESOLVE only looks the name up, there is nothing to relay afterwards
+    let resolve = request.cmd == 0xF0 && handler.config.enable_resolve;
+    // Neither BIND nor UDP ASSOCIATE is implemented, they get the same
+    // answer as any other unknown command
+    if request.cmd != 0x01 && !resolve {
+        let name = match request.cmd {
+            0x02 => String::from("BIND"),
+            0x03 => String::from("UDP ASSOCIATE"),
+            cmd => format!("{:#04x}", cmd),
+        };
+        info!(
+            "[#{}] {} requested unsupported SOCKS CMD {}",
+            handler.id,
+            handler.peer(),
+            name
+        );
+        handler.close_as(CloseReason::ProtocolError);
+        return connection_failure(handler, 0x07);
     }
+    handler.resolve_only = resolve;
 
-    let addr: SocketAddr;
     let mut target: Target = Target::new();
-
-    match atyp {
-        1 => {
-            if buffer_len < 10 {
-                error!("Truncated request detected");
-                handler.set_state(Socks5State::Closed);
-                return Ok(true);
-            }
-            let mut ip = [0; 4];
-            handler.extract_buffer(&mut ip, 4);
-            let port = (handler.buffer[8] as u16) << 8 | handler.buffer[9] as u16;
-            addr = (ip, port).into();
-            let ip = Ipv4Addr::from_bytes(&ip).unwrap();
-            target.ip = ip.to_string();
-            target.port = port;
+    target.port = request.port;
+    match request.address {
+        Address::Ip(ip) => {
+            target.set_candidates(&[ip], request.port, None);
             target.domain = target.ip.clone();
+            request_target(handler, target)
         }
-        4 => {
-            if buffer_len < 22 {
-                error!("Truncated request detected");
-                handler.set_state(Socks5State::Closed);
-                return Ok(true);
-            }
-            let mut ip = [0; 16];
-            handler.extract_buffer(&mut ip, 4);
-            let port = (handler.buffer[20] as u16) << 8 | handler.buffer[21] as u16;
-            addr = (ip, port).into();
-            let ip = Ipv6Addr::from_bytes(&ip).unwrap();
-            target.ip = ip.to_string();
-            target.port = port;
-            target.domain = target.ip.clone();
+        Address::Domain(domain) => {
+            debug!("[#{}] Requested domain: {}", handler.id, domain);
+            target.domain = domain;
+            resolve_target(handler, target)
         }
-        3 => {
-            if buffer_len < 8 {
-                error!("Truncated request detected");
-                handler.set_state(Socks5State::Closed);
-                return Ok(true);
-            }
+    }
+}
 
-            let domain_len = buffer[4] as usize;
-            let mut domain = vec![0; domain_len];
-            handler.extract_buffer(&mut domain, 5);
-
-            match String::from_utf8(domain) {
-                Ok(s) => {
-                    debug!("Requested domain: {}", s);
-                    let resolver =
-                        Resolver::new(ResolverConfig::default(), ResolverOpts::default()).unwrap();
-                    let domain = s.clone();
-                    let response = match resolver.lookup_ip(s) {
-                        Ok(r) => r,
-                        Err(err) => {
-                            error!("Failed to resolve requested domain: {}", err);
-                            handler.set_state(Socks5State::Closed);
-                            return Ok(true);
-                        }
-                    };
-                    let port = (handler.buffer[buffer_len - 2] as u16) << 8
-                        | handler.buffer[buffer_len - 1] as u16;
-                    if let Some(ip) = response.iter().next() {
-                        addr = (ip, port).into();
-                        target.ip = ip.to_string();
-                        target.port = port;
-                        target.domain = domain;
-                    } else {
-                        error!("No DNS record to requested domain");
-                        handler.set_state(Socks5State::Closed);
-                        return Ok(true);
-                    }
-                }
-                Err(_) => {
-                    error!("Unexpected request domain detected");
-                    handler.set_state(Socks5State::Closed);
-                    return Ok(true);
-                }
-            }
+// Waits for the rest of an incomplete frame, anything else ends the
+// connection, with a reply when the error carries one.
+pub fn parse_failure(
+    handler: &mut Socks5Handler<Client>,
+    err: ParseError,
+) -> Result<Step, ProxyError> {
+    match err {
+        ParseError::Incomplete => Ok(Step::Yield),
+        ParseError::Protocol(reason) => {
+            handler.set_state(Socks5State::Closed);
+            Err(ProxyError::Protocol(reason))
         }
+        ParseError::Reply(rep, reason) => {
+            error!("[#{}] {}", handler.id, reason);
+            handler.close_as(CloseReason::ProtocolError);
+            connection_failure(handler, rep)
+        }
+    }
+}
+
+// Maps why the upstream could not be connected to a SOCKS5 REP code.
+pub fn reply_for_error(kind: Option<io::ErrorKind>) -> u8 {
+    match kind {
+        Some(io::ErrorKind::ConnectionRefused) => 0x05,
+        _ => 0x04,
+    }
+}
+
+// Parks the handler until the background resolver answers for the domain,
+// unless the upstream resolves it.
+pub fn resolve_target(
+    handler: &mut Socks5Handler<Client>,
+    target: Target,
+) -> Result<Step, ProxyError> {
+    if handler.resolves_remotely(&target) {
+        debug!(
+            "[#{}] Leaving {} to the upstream to resolve",
+            handler.id, target.domain
+        );
+        return request_target(handler, target);
+    }
+    handler
+        .resolver
+        .resolve(handler.id, handler.token, target.domain.clone());
+    handler.phases.resolve = Some(Instant::now());
+    handler.set_target(target);
+    handler.set_state(Socks5State::Resolving);
+    Ok(Step::Yield)
+}
+
+pub fn connection_resolved(
+    handler: &mut Socks5Handler<Client>,
+    ips: Option<Vec<IpAddr>>,
+) -> Result<Step, ProxyError> {
+    // The resolver already logged why, the client learns the host is
+    // unreachable rather than seeing a bare close
+    let ips = match ips {
+        Some(ips) if !ips.is_empty() => ips,
         _ => {
-            error!("Unexpected request ATYP detected");
-            handler.set_state(Socks5State::Closed);
-            return Ok(true);
+            connection_failure(handler, 0x04)?;
+            return Err(ProxyError::Dns(format!(
+                "no address for {}",
+                handler.target().domain
+            )));
         }
+    };
+    let mut target = handler.target().clone();
+    target.set_candidates(&ips, target.port, handler.config.prefer);
+
+    request_target(handler, target)
+}
+
+// Checks the ruleset against the final target and moves on to connecting
+// the upstream, shared by SOCKS5 and SOCKS4 requests.
+pub fn request_target(
+    handler: &mut Socks5Handler<Client>,
+    target: Target,
+) -> Result<Step, ProxyError> {
+    if !permitted(handler, &target) {
+        handler.set_target(target);
+        return connection_failure(handler, 0x02);
+    }
+
+    if handler.resolve_only {
+        return resolve_response(handler, target);
     }
 
-    target.addr = addr;
     info!(
-        "{} requested connection to {}:{}",
-        handler.stream_addr().unwrap(),
+        "[#{}] {} requested connection to {}:{}{}",
+        handler.id,
+        handler.peer(),
         target.domain,
-        target.port
+        target.port,
+        if handler.http() {
+            " over HTTP"
+        } else if handler.version == 0x04 {
+            " over SOCKS4"
+        } else {
+            ""
+        }
     );
     handler.set_target(target);
 
     handler.set_state(Socks5State::ClientConnectionRequest);
 
-    Ok(false)
+    Ok(Step::Continue)
 }
 
-pub fn connection_response(handler: &mut Socks5Handler<HttpClient>) -> io::Result<bool> {
-    debug!("SOCKS5 Server Connection Response");
+// Answers a RESOLVE with the first candidate in BND.ADDR and closes.
+fn resolve_response(
+    handler: &mut Socks5Handler<Client>,
+    target: Target,
+) -> Result<Step, ProxyError> {
+    info!(
+        "[#{}] {} resolved {} to {}",
+        handler.id,
+        handler.peer(),
+        target.domain,
+        target.addr.ip()
+    );
+    let bound = SocketAddr::new(target.addr.ip(), 0);
+    handler.set_target(target);
+    write_bound_reply(handler, 0x00, bound)?;
+    handler.set_state(Socks5State::Closed);
 
-    handler.reset_buffer();
-    handler.put_buffer(0x05);
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x01);
+    Ok(Step::Close)
+}
 
-    // BDN.ADDR & BND.PORT
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x00);
-    handler.put_buffer(0x00);
+pub fn connection_response(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Connection Response", handler.id);
 
-    let result = handler.write_stream();
+    let bound = bound_addr(handler);
+    let result = write_bound_reply(handler, 0x00, bound);
     handler.set_state(Socks5State::Relaying);
 
     result
 }
 
-pub fn relay_in(handler: &mut Socks5Handler<HttpClient>) -> io::Result<bool> {
-    debug!("SOCKS5 Server Relay IN");
+// Address reported in BND.ADDR/BND.PORT as --bnd-mode picks it, zeros by
+// default. Chained tunnels have no meaningful local address and report
+// zeros too.
+fn bound_addr(handler: &Socks5Handler<Client>) -> SocketAddr {
+    let zeros = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    match handler.config.bnd_mode {
+        BndMode::Zero => zeros,
+        BndMode::Advertise => handler.config.advertise_addr.unwrap_or(zeros),
+        BndMode::Local => handler
+            .upstream
+            .and_then(|key| handler.client.get(key))
+            .and_then(|client| client.local_addr())
+            .unwrap_or(zeros),
+    }
+}
 
-    handler.clear_buffer();
-    match handler.read_stream() {
-        Ok(false) => {}
-        Ok(true) => {
-            debug!("SOCKS5 Relay IN interrupted");
-            return Ok(true);
+// Tells the client why the tunnel could not be established before closing.
+pub fn connection_failure(
+    handler: &mut Socks5Handler<Client>,
+    rep: u8,
+) -> Result<Step, ProxyError> {
+    debug!(
+        "[#{}] SOCKS5 Server Connection Failure, REP: {:#04x}",
+        handler.id, rep
+    );
+
+    // A refusal by policy says nothing about the health of the upstream
+    handler.report_upstream(rep == 0x02);
+    handler.close_as(match rep {
+        0x02 => CloseReason::PolicyDenied,
+        _ => CloseReason::UpstreamError,
+    });
+    if handler.http() {
+        http_protocol::connection_failure(handler, rep)?;
+    } else if handler.version == 0x04 {
+        socks4_protocol::connection_failure(handler)?;
+    } else {
+        write_reply(handler, rep)?;
+    }
+    handler.set_state(Socks5State::Closed);
+
+    Ok(Step::Close)
+}
+
+// Maps the status of a failed upstream CONNECT to a SOCKS5 REP code.
+pub fn reply_for_status(status: u16) -> u8 {
+    match status {
+        403 => 0x02,
+        502 | 504 => 0x04,
+        _ => 0x01,
+    }
+}
+
+pub fn permitted(handler: &Socks5Handler<Client>, target: &Target) -> bool {
+    if handler.config.rules.blocks(target) {
+        info!(
+            "[#{}] {} denied connection to {}:{} by ruleset",
+            handler.id,
+            handler.peer(),
+            target.domain,
+            target.port
+        );
+        return false;
+    }
+    true
+}
+
+fn write_reply(handler: &mut Socks5Handler<Client>, rep: u8) -> Result<Step, ProxyError> {
+    write_bound_reply(handler, rep, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+}
+
+fn write_bound_reply(
+    handler: &mut Socks5Handler<Client>,
+    rep: u8,
+    bound: SocketAddr,
+) -> Result<Step, ProxyError> {
+    handler.reset_buffer();
+    handler.put_buffer(0x05);
+    handler.put_buffer(rep);
+    handler.put_buffer(0x00);
+
+    // BDN.ADDR & BND.PORT
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            handler.put_buffer(0x01);
+            ip.octets().iter().for_each(|b| handler.put_buffer(*b));
         }
-        Err(err) => {
-            error!("During SOCKS5 Relay IN, error occured: {}", err);
-            return Err(err);
+        IpAddr::V6(ip) => {
+            handler.put_buffer(0x04);
+            ip.octets().iter().for_each(|b| handler.put_buffer(*b));
         }
     }
-    let client = handler.client.get_mut(0).unwrap();
-    client.reset_buffer();
-    client.clone_buffer(&handler.buffer);
-    client.write_buffer()
+    bound
+        .port()
+        .to_be_bytes()
+        .iter()
+        .for_each(|b| handler.put_buffer(*b));
+
+    Ok(handler.write_stream()?)
 }
 
-pub fn relay_out(handler: &mut Socks5Handler<HttpClient>) -> io::Result<bool> {
-    debug!("SOCKS5 Server Relay OUT");
+pub fn relay_in(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Relay IN", handler.id);
+
+    let key = match handler.upstream {
+        Some(key) if handler.client.contains(key) => key,
+        _ => return Ok(Step::Close),
+    };
+    // Nothing new is read while the upstream still has a tail to take, the
+    // rest waits in the client's socket until it caught up.
+    if handler.client[key].pending() > 0 {
+        let flushed = handler.client[key].flush();
+        if blame(handler, flushed, CloseReason::UpstreamError)? == Step::Close {
+            return Ok(Step::Close);
+        }
+        if handler.client[key].pending() > 0 {
+            return Ok(Step::Yield);
+        }
+        if handler.client_eof {
+            shutdown_upstream(handler, key);
+            if finished(handler) {
+                return Ok(Step::Close);
+            }
+        }
+    }
+    if handler.client_eof {
+        return Ok(Step::Yield);
+    }
+    loop {
+        let quota = handler.quota();
+        let limit = cmp::min(quota, handler.max_buffer);
+        handler.clear_buffer();
+        let eof = match handler.read_stream_up_to(limit) {
+            Ok(step) => step == Step::Close,
+            Err(err) => {
+                handler.close_as(CloseReason::ClientError);
+                // Resets end the relay quietly, see Socks5Handler::handle
+                if !disconnected(&err) {
+                    error!(
+                        "[#{}] During SOCKS5 Relay IN, error occured: {}",
+                        handler.id, err
+                    );
+                }
+                return Err(err.into());
+            }
+        };
+        handler.consume(quota, handler.size);
+        let read = handler.size;
+        if !handler.screen_hello()? {
+            // The rest of the ClientHello is still on its way
+            if eof {
+                return Ok(Step::Close);
+            }
+            if read < limit || limit == quota {
+                return Ok(Step::Yield);
+            }
+            continue;
+        }
+        let size = handler.size;
+        if size > 0 {
+            let client = &mut handler.client[key];
+            client.reset_buffer();
+            client.swap_buffer(&mut handler.buffer);
+            handler.size = handler.buffer.len();
+            let written = client.write_buffer();
+            if blame(handler, written, CloseReason::UpstreamError)? == Step::Close {
+                return Ok(Step::Close);
+            }
+        }
+        // The client is done sending, pass that on once the upstream took
+        // everything and keep relaying the other direction until the
+        // upstream is done as well.
+        if eof {
+            debug!("[#{}] SOCKS5 Relay IN closed by the client", handler.id);
+            handler.client_eof = true;
+            handler.close_as(CloseReason::ClientEof);
+            if handler.client[key].pending() == 0 {
+                shutdown_upstream(handler, key);
+            }
+            if finished(handler) {
+                return Ok(Step::Close);
+            }
+            return Ok(Step::Yield);
+        }
+        // A short write resumes on the next writable edge of the upstream
+        if handler.client[key].pending() > 0 {
+            return Ok(Step::Yield);
+        }
+        // Only a full buffer leaves data behind that no new edge reports
+        if read < limit || limit == quota {
+            return Ok(Step::Yield);
+        }
+    }
+}
 
-    handler.reset_buffer();
-    let client = handler.client.get_mut(0).unwrap();
-    client.clear_buffer();
-    match client.read_buffer() {
-        Ok(false) => {}
-        Ok(true) => {
-            debug!("HTTP Client Relay IN interrupted");
-            return Ok(true);
+pub fn relay_out(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS5 Server Relay OUT", handler.id);
+
+    let key = match handler.upstream {
+        Some(key) if handler.client.contains(key) => key,
+        _ => return Ok(Step::Close),
+    };
+    if handler.pending() > 0 {
+        let flushed = handler.flush_stream();
+        if blame(handler, flushed, CloseReason::ClientError)? == Step::Close {
+            return Ok(Step::Close);
         }
-        Err(err) => {
-            error!("During HTTP Client Relay IN, error occured: {}", err);
-            return Err(err);
+        if handler.pending() > 0 {
+            return Ok(Step::Yield);
+        }
+        if handler.upstream_eof {
+            handler.shutdown_stream();
+            if finished(handler) {
+                return Ok(Step::Close);
+            }
         }
     }
-    if client.size == 0 {
-        return Ok(false);
+    if handler.upstream_eof {
+        return Ok(Step::Yield);
     }
-    handler.buffer.clone_from(&client.buffer);
-    handler.write_stream()
+    loop {
+        handler.reset_buffer();
+        let quota = handler.quota();
+        let limit = cmp::min(quota, handler.max_buffer);
+        let client = &mut handler.client[key];
+        client.clear_buffer();
+        let eof = match client.read_buffer_up_to(limit) {
+            Ok(step) => step == Step::Close,
+            Err(err) => {
+                handler.close_as(CloseReason::UpstreamError);
+                // Resets end the relay quietly, see Socks5Handler::handle
+                if !disconnected(&err) {
+                    error!(
+                        "[#{}] During SOCKS5 Relay OUT from the upstream, error occured: {}",
+                        handler.id, err
+                    );
+                }
+                return Err(err.into());
+            }
+        };
+        let size = client.size();
+        if size > 0 {
+            handler.phases.first_byte.get_or_insert_with(Instant::now);
+            // The handler's emptied buffer goes the other way
+            client.swap_buffer(&mut handler.buffer);
+            handler.size = size;
+            handler.consume(quota, size);
+            let written = handler.write_stream();
+            if blame(handler, written, CloseReason::ClientError)? == Step::Close {
+                return Ok(Step::Close);
+            }
+        }
+        if eof {
+            debug!("[#{}] SOCKS5 Relay OUT closed by the upstream", handler.id);
+            handler.upstream_eof = true;
+            handler.close_as(CloseReason::UpstreamEof);
+            if handler.pending() == 0 {
+                handler.shutdown_stream();
+            }
+            if finished(handler) {
+                return Ok(Step::Close);
+            }
+            return Ok(Step::Yield);
+        }
+        if handler.pending() > 0 {
+            return Ok(Step::Yield);
+        }
+        if size < limit || limit == quota {
+            return Ok(Step::Yield);
+        }
+    }
+}
+
+// A write failing or finding the peer gone ends the relay because of the
+// side it went to.
+fn blame(
+    handler: &mut Socks5Handler<Client>,
+    result: io::Result<Step>,
+    reason: CloseReason,
+) -> io::Result<Step> {
+    if !matches!(result, Ok(Step::Continue) | Ok(Step::Yield)) {
+        handler.close_as(reason);
+    }
+    result
+}
+
+fn shutdown_upstream(handler: &mut Socks5Handler<Client>, key: usize) {
+    if let Err(err) = handler.client[key].shutdown_write() {
+        debug!("[#{}] Upstream shutdown failed: {}", handler.id, err);
+    }
+}
+
+// Both sides are done sending and everything got delivered.
+fn finished(handler: &Socks5Handler<Client>) -> bool {
+    let upstream_pending = match handler.upstream {
+        Some(key) if handler.client.contains(key) => handler.client[key].pending(),
+        _ => 0,
+    };
+    handler.client_eof && handler.upstream_eof && handler.pending() == 0 && upstream_pending == 0
 }
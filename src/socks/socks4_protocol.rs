@@ -0,0 +1,80 @@
+use log::debug;
+
+use crate::datatype::Target;
+use crate::error::{CloseReason, ProxyError, Step};
+use crate::upstream::Client;
+
+use super::handler::Socks5Handler;
+use super::handler::Socks5State;
+use super::parse::{parse_socks4_request, Address};
+use super::server_protocol::{parse_failure, request_target, resolve_target};
+
+// SOCKS4 has no method negotiation, so the request has already been read by
+// method_request when the leading version byte turned out to be 0x04.
+pub fn connection_request(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS4 Server Connection Request", handler.id);
+
+    handler.set_version(0x04);
+
+    // No credentials can be sent over SOCKS4
+    if handler.config.require_auth {
+        handler.close_as(CloseReason::PolicyDenied);
+        connection_failure(handler)?;
+        handler.set_state(Socks5State::Closed);
+        return Err(ProxyError::Protocol(String::from(
+            "SOCKS4 request refused, authentication is required",
+        )));
+    }
+
+    let request = match parse_socks4_request(
+        &handler.buffer[..handler.size],
+        handler.config.max_domain_len,
+    ) {
+        Ok(request) => request,
+        Err(err) => return parse_failure(handler, err),
+    };
+
+    let mut target: Target = Target::new();
+    match request.address {
+        Address::Domain(domain) => {
+            debug!("[#{}] Requested domain: {}", handler.id, domain);
+            target.port = request.port;
+            target.domain = domain;
+            resolve_target(handler, target)
+        }
+        Address::Ip(ip) => {
+            target.set_candidates(&[ip], request.port, None);
+            target.domain = target.ip.clone();
+            request_target(handler, target)
+        }
+    }
+}
+
+pub fn connection_response(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    debug!("[#{}] SOCKS4 Server Connection Response", handler.id);
+
+    let result = write_reply(handler, 0x5A);
+    handler.set_state(Socks5State::Relaying);
+
+    result
+}
+
+pub fn connection_failure(handler: &mut Socks5Handler<Client>) -> Result<Step, ProxyError> {
+    write_reply(handler, 0x5B)
+}
+
+fn write_reply(handler: &mut Socks5Handler<Client>, cd: u8) -> Result<Step, ProxyError> {
+    handler.reset_buffer();
+    handler.put_buffer(0x00);
+    handler.put_buffer(cd);
+
+    // DSTPORT & DSTIP, ignored by clients
+    handler.put_buffer(0x00);
+    handler.put_buffer(0x00);
+    handler.put_buffer(0x00);
+    handler.put_buffer(0x00);
+    handler.put_buffer(0x00);
+    handler.put_buffer(0x00);
+
+    Ok(handler.write_stream()?)
+}
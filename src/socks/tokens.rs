@@ -0,0 +1,41 @@
+use mio::Token;
+
+// Hands out the tokens of connections and upstream clients. Released tokens
+// are held back until `recycle`, so events still queued in the current batch
+// for a closed socket can't reach whoever would get its token next.
+pub struct TokenPool {
+    next: Token,
+    free: Vec<Token>,
+    released: Vec<Token>,
+}
+
+impl TokenPool {
+    pub fn new(first: Token) -> Self {
+        Self {
+            next: first,
+            free: Vec::new(),
+            released: Vec::new(),
+        }
+    }
+
+    pub fn take(&mut self) -> Token {
+        match self.free.pop() {
+            Some(token) => token,
+            None => {
+                let token = self.next;
+                self.next.0 += 1;
+                token
+            }
+        }
+    }
+
+    // The token must no longer be routed anywhere.
+    pub fn release(&mut self, token: Token) {
+        self.released.push(token);
+    }
+
+    // Makes the released tokens available again, once their events are gone.
+    pub fn recycle(&mut self) {
+        self.free.append(&mut self.released);
+    }
+}
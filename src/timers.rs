@@ -0,0 +1,64 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+use fnv::FnvHashMap;
+use mio::Token;
+
+// One pending deadline per token. Rescheduling or cancelling leaves the old
+// heap entry behind, it is skipped once it surfaces.
+#[derive(Debug, Default)]
+pub struct Timers {
+    heap: BinaryHeap<Reverse<(Instant, Token)>>,
+    current: FnvHashMap<Token, Instant>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces any deadline already set for `token`.
+    pub fn schedule(&mut self, token: Token, at: Instant) {
+        if self.current.insert(token, at) != Some(at) {
+            self.heap.push(Reverse((at, token)));
+        }
+    }
+
+    pub fn cancel(&mut self, token: Token) {
+        self.current.remove(&token);
+    }
+
+    // Earliest deadline still pending.
+    pub fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(Reverse((at, token))) = self.heap.peek().copied() {
+            if self.current.get(&token) == Some(&at) {
+                return Some(at);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    // Takes the tokens whose deadline has passed by `now`, earliest first.
+    pub fn expired(&mut self, now: Instant) -> Vec<Token> {
+        let mut expired = Vec::new();
+        while let Some(at) = self.next_deadline() {
+            if at > now {
+                break;
+            }
+            let Reverse((_, token)) = self.heap.pop().unwrap();
+            self.current.remove(&token);
+            expired.push(token);
+        }
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current.is_empty()
+    }
+}
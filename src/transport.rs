@@ -0,0 +1,324 @@
+use log::{debug, error, info};
+use std::io;
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BytesMut};
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+
+use crate::buffer::{read_buf, write_some};
+use crate::error::Step;
+use crate::fd::fd_note;
+use crate::keepalive::Keepalive;
+use crate::outbound::{self, Outbound};
+use crate::proxy::Proxy;
+
+// Socket and buffers of an upstream client, whatever protocol it speaks on
+// top. A proxy is connected to by trying its addresses in order, all of
+// them again after a backoff while retries are left.
+pub struct Transport {
+    pub id: usize,
+    // Names the peer in log lines, e.g. `HTTP proxy`.
+    label: &'static str,
+    pub stream: Option<TcpStream>,
+    pub token: Option<Token>,
+    attempt: usize,
+    pub last_error: Option<io::ErrorKind>,
+    retries: u32,
+    retried: u32,
+    retry_delay: Duration,
+    retry_at: Option<Instant>,
+    connect_timeout: Option<Duration>,
+    pub connect_deadline: Option<Instant>,
+    pub buffer: BytesMut,
+    pub size: usize,
+    // Tail of a short write, sent before anything queued after it.
+    unsent: BytesMut,
+    buffer_size: usize,
+    outbound: Option<Outbound>,
+    keepalive: Option<Keepalive>,
+    nodelay: bool,
+}
+
+impl Transport {
+    pub fn new(id: usize, label: &'static str, buffer_size: usize) -> Self {
+        Self {
+            id,
+            label,
+            stream: None,
+            token: None,
+            attempt: 0,
+            last_error: None,
+            retries: 0,
+            retried: 0,
+            retry_delay: Duration::from_millis(0),
+            retry_at: None,
+            connect_timeout: None,
+            connect_deadline: None,
+            buffer: BytesMut::with_capacity(buffer_size),
+            size: 0,
+            unsent: BytesMut::new(),
+            buffer_size,
+            outbound: None,
+            keepalive: None,
+            nodelay: true,
+        }
+    }
+
+    pub fn set_outbound(&mut self, outbound: Option<Outbound>) {
+        self.outbound = outbound;
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    // Nagle's algorithm stays off unless this is false.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    // Bounds the time from connecting to the answer of the handshake.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    pub fn set_retry(&mut self, retries: u32, delay: Duration) {
+        self.retries = retries;
+        self.retry_delay = delay;
+    }
+
+    // Starts connecting a socket set up with the outbound and socket options.
+    pub fn open(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let stream = outbound::connect(addr, self.outbound.as_ref())?;
+        debug!(
+            "[#{}] Connect to {} {}{}",
+            self.id,
+            self.label,
+            addr,
+            fd_note(&stream)
+        );
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            keepalive.apply(&stream)?;
+        }
+        Ok(stream)
+    }
+
+    pub fn failed(&mut self, addr: SocketAddr, err: &io::Error) {
+        error!(
+            "[#{}] Failed to connect to {} {}, reason: {}",
+            self.id, self.label, addr, err
+        );
+        self.last_error = Some(err.kind());
+    }
+
+    // Every address of the proxy failed, try them all again after a backoff
+    // unless the retries are used up.
+    fn schedule_retry(&mut self, remote: &Proxy) -> bool {
+        if self.retried >= self.retries {
+            return false;
+        }
+        let delay = self.retry_delay * 2u32.saturating_pow(self.retried);
+        self.retried += 1;
+        self.attempt = 0;
+        self.retry_at = Some(Instant::now() + delay);
+        info!(
+            "[#{}] Retrying {} {} in {}ms ({}/{})",
+            self.id,
+            self.label,
+            remote.addr,
+            delay.as_millis(),
+            self.retried,
+            self.retries
+        );
+        true
+    }
+
+    // Closes when no address of the proxy could be connected and no retry
+    // is left.
+    pub fn connect(
+        &mut self,
+        remote: &Proxy,
+        token: Token,
+        registry: &Registry,
+    ) -> io::Result<Step> {
+        self.token = Some(token);
+        while self.stream.is_none() {
+            let addr = match remote.addrs.get(self.attempt).copied() {
+                Some(addr) => addr,
+                None if self.schedule_retry(remote) => return Ok(Step::Yield),
+                None => return Ok(Step::Close),
+            };
+            match self.open(addr) {
+                Ok(s) => {
+                    self.stream = Some(s);
+                    self.connect_deadline = self.connect_timeout.map(|t| Instant::now() + t);
+                }
+                Err(err) => {
+                    self.failed(addr, &err);
+                    self.attempt += 1;
+                }
+            }
+        }
+
+        let stream = self.stream.as_mut().unwrap();
+
+        registry.register(stream, token, Interest::READABLE.add(Interest::WRITABLE))?;
+
+        Ok(Step::Yield)
+    }
+
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    pub fn retry(&mut self, remote: &Proxy, registry: &Registry) -> io::Result<Step> {
+        match self.retry_at {
+            Some(at) if at <= Instant::now() => {
+                self.retry_at = None;
+                let token = self.token.unwrap();
+                self.connect(remote, token, registry)
+            }
+            _ => Ok(Step::Yield),
+        }
+    }
+
+    // Failing addresses are skipped, moving on to the next address of the
+    // proxy still counts as connecting.
+    pub fn check_connected(&mut self, remote: &Proxy, registry: &Registry) -> io::Result<bool> {
+        let stream = match self.stream.as_mut() {
+            Some(s) => s,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        let err = match stream.take_error()? {
+            Some(err) => err,
+            None => match stream.peer_addr() {
+                Ok(_) => return Ok(true),
+                Err(ref err) if err.kind() == io::ErrorKind::NotConnected => return Ok(false),
+                Err(err) => err,
+            },
+        };
+
+        registry.deregister(stream)?;
+        self.stream = None;
+        self.connect_deadline = None;
+        self.failed(remote.addrs[self.attempt], &err);
+        self.attempt += 1;
+
+        let token = self.token.unwrap();
+        match self.connect(remote, token, registry)? {
+            Step::Close => Err(err),
+            _ => Ok(false),
+        }
+    }
+
+    // Handshakes over a socket the previous hop of a chain tunneled.
+    pub fn adopt(&mut self, stream: TcpStream, token: Token) {
+        self.token = Some(token);
+        self.stream = Some(stream);
+        self.connect_deadline = self.connect_timeout.map(|t| Instant::now() + t);
+    }
+
+    // Appends to the buffer, closes once the peer is done sending.
+    pub fn read_up_to(&mut self, limit: usize) -> io::Result<Step> {
+        let stream = self.stream.as_mut().unwrap();
+        let mut remaining = limit;
+        while remaining > 0 {
+            match read_buf(stream, &mut self.buffer, remaining) {
+                Ok(0) => return Ok(Step::Close),
+                Ok(n) => {
+                    self.size += n;
+                    remaining -= n;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Step::Continue)
+    }
+
+    pub fn put(&mut self, value: &[u8]) {
+        self.buffer.extend_from_slice(value);
+        self.size += value.len();
+    }
+
+    // Drops the first `n` bytes of the buffer, e.g. a parsed message.
+    pub fn consume(&mut self, n: usize) {
+        self.buffer.advance(n);
+        self.size -= n;
+    }
+
+    // Queues bytes behind any unsent tail, leaving the buffer as it is.
+    pub fn send(&mut self, msg: &[u8]) -> io::Result<Step> {
+        self.unsent.extend_from_slice(msg);
+        self.flush()
+    }
+
+    pub fn write_buffer(&mut self) -> io::Result<Step> {
+        self.unsent.unsplit(self.buffer.split());
+        self.size = 0;
+        self.flush()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.unsent.len()
+    }
+
+    pub fn flush(&mut self) -> io::Result<Step> {
+        let stream = self.stream.as_mut().unwrap();
+        match write_some(stream, &self.unsent)? {
+            Some(n) => {
+                self.unsent.advance(n);
+                Ok(Step::Continue)
+            }
+            None => Ok(Step::Close),
+        }
+    }
+
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        match self.stream.as_ref() {
+            Some(stream) => stream.shutdown(Shutdown::Write),
+            None => Ok(()),
+        }
+    }
+
+    pub fn swap_buffer(&mut self, buffer: &mut BytesMut) {
+        mem::swap(&mut self.buffer, buffer);
+        self.size = self.buffer.len();
+    }
+
+    pub fn clear_buffer(&mut self) {
+        self.buffer.clear();
+        self.buffer.reserve(self.buffer_size);
+        self.size = 0;
+    }
+
+    pub fn reset_buffer(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn reregister(&mut self, registry: &Registry) -> io::Result<()> {
+        if let (Some(stream), Some(token)) = (self.stream.as_mut(), self.token) {
+            registry.reregister(stream, token, Interest::READABLE.add(Interest::WRITABLE))?;
+        }
+        Ok(())
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) {
+        if let Some(stream) = self.stream.as_mut() {
+            deregister(self.id, self.label, stream, registry);
+        }
+    }
+}
+
+// Sockets still being connected are let go of the same way.
+pub fn deregister(id: usize, label: &str, stream: &mut TcpStream, registry: &Registry) {
+    debug!("[#{}] Closing {} socket{}", id, label, fd_note(stream));
+    if let Err(err) = registry.deregister(stream) {
+        debug!("[#{}] Deregistering {} socket failed: {}", id, label, err);
+    }
+}
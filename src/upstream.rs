@@ -0,0 +1,107 @@
+use bytes::BytesMut;
+use mio::net::TcpStream;
+use mio::{event::Event, Registry, Token};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::error::Step;
+
+// Outbound side of a tunnel, a proxy of the chain or the target itself.
+pub trait UpstreamClient {
+    fn token(&self) -> Option<Token>;
+
+    // Closes when no address could be connected at all.
+    fn connect(&mut self, token: Token, registry: &Registry) -> io::Result<Step>;
+
+    // Returns Ok(true) once connected and Ok(false) while still connecting.
+    fn check_connected(&mut self, registry: &Registry) -> io::Result<bool>;
+
+    // Whether the tunnel is usable as soon as the socket is connected,
+    // without any handshake of its own.
+    fn established(&self) -> bool;
+
+    // `value` carries the bytes to relay out, swapped for the buffer of the
+    // client.
+    fn handle(&mut self, event: &Event, value: Option<&mut BytesMut>) -> io::Result<Step>;
+
+    // HTTP status the upstream refused the tunnel with.
+    fn status(&self) -> Option<u16> {
+        None
+    }
+
+    // Status line the status came with, e.g. `HTTP/1.1 502 Bad Gateway`.
+    fn status_line(&self) -> Option<&str> {
+        None
+    }
+
+    // SOCKS5 REP the upstream answered the tunnel request with.
+    fn reply(&self) -> Option<u8> {
+        None
+    }
+
+    // Local end of the connected socket.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    // Why the last address tried could not be connected.
+    fn last_error(&self) -> Option<io::ErrorKind>;
+
+    fn buffer(&self) -> &BytesMut;
+
+    fn size(&self) -> usize;
+
+    // Closes once the upstream is done sending.
+    fn read_buffer_up_to(&mut self, limit: usize) -> io::Result<Step>;
+
+    // Queues the buffer behind any unsent tail and writes what the socket
+    // takes, the rest waits for the next writable edge.
+    fn write_buffer(&mut self) -> io::Result<Step>;
+
+    // Bytes a short write left behind.
+    fn pending(&self) -> usize;
+
+    // Writes the unsent tail.
+    fn flush(&mut self) -> io::Result<Step>;
+
+    // Closes the sending half once the other side of the tunnel is done.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+
+    // Exchanges buffers instead of copying, the bytes of `buffer` become
+    // the ones to write and it gets the previous buffer back.
+    fn swap_buffer(&mut self, buffer: &mut BytesMut);
+
+    fn clear_buffer(&mut self);
+
+    fn reset_buffer(&mut self);
+
+    fn retry_at(&self) -> Option<Instant> {
+        None
+    }
+
+    // When the upstream has to have finished its handshake.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    // Runs a scheduled retry once due, closes when it finally failed.
+    fn retry(&mut self, _registry: &Registry) -> io::Result<Step> {
+        Ok(Step::Yield)
+    }
+
+    // Gives up the socket once tunneled, for the next hop of a chain.
+    fn take_stream(&mut self) -> Option<TcpStream> {
+        None
+    }
+
+    // Handshakes over a socket the previous hop of a chain already tunneled
+    // to this proxy instead of connecting, it stays registered as `token`.
+    fn adopt(&mut self, _stream: TcpStream, _token: Token) {}
+
+    fn reregister(&mut self, registry: &Registry) -> io::Result<()>;
+
+    fn deregister(&mut self, registry: &Registry);
+}
+
+pub type Client = Box<dyn UpstreamClient>;
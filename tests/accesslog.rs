@@ -0,0 +1,133 @@
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use common::{
+    abort, socks5_connect, spawn_echo_origin, spawn_http_proxy, spawn_recording_proxy, spawn_server,
+};
+use proxychain::accesslog::AccessLogFormat;
+use proxychain::acl::{DestinationRules, PortRange};
+
+// Waits for the JSON entry of the connection to the given port, the probe
+// made while waiting for the server to listen is logged too.
+fn entry_for(path: &Path, port: u16) -> serde_json::Value {
+    let mut entry = None;
+    for _ in 0..100 {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        entry = contents
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|entry| entry["port"] == port);
+        if entry.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = fs::remove_file(path);
+    entry.expect("no access log entry for the connection")
+}
+
+#[test]
+fn writes_a_json_entry_per_connection() {
+    let path = env::temp_dir().join(format!("proxychain-access-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let log = path.clone();
+    let server = spawn_server(proxy, move |server| {
+        server.access_log(Some(log), AccessLogFormat::Json)
+    });
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    stream.shutdown(Shutdown::Both).unwrap();
+
+    let entry = entry_for(&path, origin.port());
+    assert_eq!(entry["upstream"], proxy.to_string());
+    assert_eq!(entry["status"], "relayed");
+    assert_eq!(entry["reason"], "client_eof");
+    assert!(entry["bytes_out"].as_u64().unwrap() >= 4);
+    assert!(entry["timestamp"].is_string());
+    // An IPv4 target is never resolved
+    let timings = &entry["timings"];
+    assert!(timings["dns"].is_null());
+    for phase in ["connect", "handshake", "first_byte"].iter() {
+        assert!(
+            timings[*phase].as_f64().unwrap() >= 0.0,
+            "{} missing",
+            phase
+        );
+    }
+}
+
+#[test]
+fn records_the_status_line_of_a_refused_connect() {
+    let path = env::temp_dir().join(format!("proxychain-refused-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let (proxy, _) = spawn_recording_proxy(b"HTTP/1.1 502 Bad Gateway\r\n\r\n");
+    let log = path.clone();
+    let server = spawn_server(proxy, move |server| {
+        server.access_log(Some(log), AccessLogFormat::Json)
+    });
+
+    let origin = spawn_echo_origin();
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x04]);
+
+    let entry = entry_for(&path, origin.port());
+    assert_eq!(entry["status"], "failed");
+    assert_eq!(entry["upstream_status"], "HTTP/1.1 502 Bad Gateway");
+    assert_eq!(entry["reason"], "upstream_error");
+}
+
+#[test]
+fn records_why_connections_ended() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let denied = env::temp_dir().join(format!("proxychain-denied-{}.log", std::process::id()));
+    let idle = env::temp_dir().join(format!("proxychain-idle-{}.log", std::process::id()));
+    let _ = fs::remove_file(&denied);
+    let _ = fs::remove_file(&idle);
+
+    let log = denied.clone();
+    let server = spawn_server(proxy, move |server| {
+        let mut rules = DestinationRules::default();
+        rules.block_port(PortRange::parse(&origin.port().to_string()).unwrap());
+        server.rules(rules);
+        server.access_log(Some(log), AccessLogFormat::Json)
+    });
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x02]);
+    assert_eq!(entry_for(&denied, origin.port())["reason"], "policy_denied");
+
+    let log = idle.clone();
+    let server = spawn_server(proxy, move |server| {
+        server.idle_timeout(Duration::from_millis(200));
+        server.access_log(Some(log), AccessLogFormat::Json)
+    });
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    assert_eq!(entry_for(&idle, origin.port())["reason"], "idle_timeout");
+
+    let reset = env::temp_dir().join(format!("proxychain-rst-{}.log", std::process::id()));
+    let _ = fs::remove_file(&reset);
+    let log = reset.clone();
+    let server = spawn_server(proxy, move |server| {
+        server.access_log(Some(log), AccessLogFormat::Json)
+    });
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    stream.read_exact(&mut [0u8; 4]).unwrap();
+    abort(stream);
+    assert_eq!(entry_for(&reset, origin.port())["reason"], "client_error");
+}
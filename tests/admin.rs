@@ -0,0 +1,71 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use proxychain::proxy::Proxy;
+
+use common::{socks5_connect, spawn_built, spawn_echo_origin, spawn_http_proxy};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+// Sends one command and reads its answer until the server closes.
+fn command(path: &Path, line: &str) -> String {
+    let mut stream = UnixStream::connect(path).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(line.as_bytes()).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    answer
+}
+
+#[test]
+fn lists_and_kills_connections() {
+    let path = std::env::temp_dir().join(format!("proxychain-admin-{}.sock", process::id()));
+    let upstream = spawn_http_proxy(ESTABLISHED);
+    let socket = PathBuf::from(&path);
+    let server = spawn_built(move |builder| {
+        builder
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+            .admin_socket(socket)
+    });
+
+    let origin = spawn_echo_origin();
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    assert!(command(&path, "stats\n").starts_with("active 1\n"));
+
+    let list = command(&path, "list\n");
+    let line = list
+        .lines()
+        .find(|line| line.contains(&format!("127.0.0.1:{}", origin.port())))
+        .unwrap();
+    let id = line.split(' ').next().unwrap();
+
+    let mut session = BufReader::new(UnixStream::connect(&path).unwrap());
+    session.get_mut().write_all(b"kill 999999\n").unwrap();
+    let mut answer = String::new();
+    session.read_line(&mut answer).unwrap();
+    assert_eq!(answer, "error: no connection 999999\n");
+    session
+        .get_mut()
+        .write_all(format!("kill {}\n", id).as_bytes())
+        .unwrap();
+    answer.clear();
+    session.read_line(&mut answer).unwrap();
+    assert_eq!(answer, format!("killed {}\n", id));
+
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    // The killed connection and the listening probe have closed by now
+    assert!(command(&path, "stats\n").contains("\nduration_p99 "));
+    assert!(command(&path, "bogus\n").starts_with("error: unknown command bogus"));
+    let _ = std::fs::remove_file(&path);
+}
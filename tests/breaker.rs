@@ -0,0 +1,105 @@
+mod common;
+
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+
+use proxychain::breaker::{BreakerConfig, CircuitBreaker, CircuitBreaking, CircuitState};
+use proxychain::datatype::Target;
+use proxychain::proxy::Proxy;
+use proxychain::selector::{FirstAvailable, UpstreamSelector};
+
+use common::{socks5_connect, spawn_built, spawn_echo_origin, spawn_http_proxy};
+
+fn config() -> BreakerConfig {
+    BreakerConfig {
+        failures: 3,
+        window: Duration::from_secs(10),
+        cooldown: Duration::from_secs(30),
+    }
+}
+
+#[test]
+fn opens_after_repeated_failures_and_probes_after_the_cooldown() {
+    let now = Instant::now();
+    let mut circuit = CircuitBreaker::new("upstream", config());
+    circuit.failure(now);
+    circuit.failure(now);
+    assert!(circuit.allows(now));
+    circuit.failure(now);
+    assert_eq!(circuit.state(), CircuitState::Open(now + config().cooldown));
+    assert!(!circuit.allows(now + Duration::from_secs(29)));
+
+    // A single probe once the cooldown is over, a failed one reopens
+    let later = now + Duration::from_secs(30);
+    assert!(circuit.allows(later));
+    assert!(!circuit.allows(later));
+    circuit.failure(later);
+    assert!(!circuit.allows(later + Duration::from_secs(1)));
+
+    let probe = later + Duration::from_secs(30);
+    assert!(circuit.allows(probe));
+    circuit.success();
+    assert_eq!(circuit.state(), CircuitState::Closed);
+    assert!(circuit.allows(probe));
+}
+
+#[test]
+fn forgets_failures_outside_the_window() {
+    let now = Instant::now();
+    let mut circuit = CircuitBreaker::new("upstream", config());
+    circuit.failure(now);
+    circuit.failure(now);
+    circuit.failure(now + Duration::from_secs(11));
+    assert_eq!(circuit.state(), CircuitState::Closed);
+}
+
+#[test]
+fn selector_skips_open_upstreams() {
+    let first = Proxy::parse("http://127.0.0.1:8001");
+    let second = Proxy::parse("http://127.0.0.1:8002");
+    let inner = FirstAvailable::new(vec![first.clone(), second.clone()]);
+    let selector = CircuitBreaking::new(Box::new(inner), config());
+    let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let target = Target::new();
+
+    for _ in 0..3 {
+        selector.report(&first, false);
+    }
+    assert!(matches!(selector.state(&first), CircuitState::Open(_)));
+    assert_eq!(selector.select(&target, peer).unwrap().port, 8002);
+    let (proxy, reason) = selector.explain(&target, peer).unwrap();
+    assert_eq!(proxy.port, 8002);
+    assert_eq!(
+        reason,
+        "first upstream, but the circuit of 127.0.0.1:8001 is open"
+    );
+
+    for _ in 0..3 {
+        selector.report(&second, false);
+    }
+    assert!(selector.select(&target, peer).is_none());
+}
+
+#[test]
+fn fails_over_once_an_upstream_keeps_refusing() {
+    let dead = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let live = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let server = spawn_built(move |builder| {
+        builder
+            .upstream(Proxy::parse(&format!("http://{}", dead)))
+            .upstream(Proxy::parse(&format!("http://{}", live)))
+            .breaker(BreakerConfig {
+                failures: 1,
+                ..BreakerConfig::default()
+            })
+    });
+    let origin = spawn_echo_origin();
+
+    let (_, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x05]);
+    let (_, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
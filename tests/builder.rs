@@ -0,0 +1,224 @@
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use proxychain::proxy::Proxy;
+use proxychain::socks::server::Socks5Server;
+
+use common::{abort, socks5_connect, spawn_built, spawn_echo_origin, spawn_http_proxy};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+fn upstream() -> Proxy {
+    let addr = spawn_http_proxy(ESTABLISHED);
+    Proxy::parse(&format!("http://{}", addr))
+}
+
+// Greets offering username/password and sends the credentials, returns the
+// auth reply.
+fn authenticate(proxy: SocketAddr, username: &str, password: &str) -> (TcpStream, [u8; 2]) {
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(&[0x05, 0x02, 0x00, 0x02]).unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [0x05, 0x02]);
+
+    let mut request = vec![0x01, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).unwrap();
+    (stream, reply)
+}
+
+#[test]
+fn authenticated_client_relays() {
+    let origin = spawn_echo_origin();
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| builder.upstream(upstream).auth("alice", "secret"));
+
+    let (mut stream, reply) = authenticate(proxy, "alice", "secret");
+    assert_eq!(reply, [0x01, 0x00]);
+
+    let ip = match origin {
+        SocketAddr::V4(v4) => v4.ip().octets(),
+        SocketAddr::V6(_) => panic!("IPv4 origin expected"),
+    };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&ip);
+    request.extend_from_slice(&origin.port().to_be_bytes());
+    stream.write_all(&request).unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 0x00);
+
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn wrong_password_is_refused() {
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| builder.upstream(upstream).auth("alice", "secret"));
+
+    let (mut stream, reply) = authenticate(proxy, "alice", "guess");
+    assert_eq!(reply, [0x01, 0x01]);
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+}
+
+#[test]
+fn required_auth_refuses_clients_offering_no_auth() {
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| {
+        builder
+            .upstream(upstream)
+            .auth("alice", "secret")
+            .require_auth(true)
+    });
+
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).unwrap();
+    assert_eq!(answer, [0x05, 0xFF]);
+
+    // SOCKS4 can't authenticate at all
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(&[0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1, 0x00])
+        .unwrap();
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], 0x5B);
+
+    let (_, reply) = authenticate(proxy, "alice", "secret");
+    assert_eq!(reply, [0x01, 0x00]);
+}
+
+#[test]
+fn idle_connection_is_closed() {
+    let origin = spawn_echo_origin();
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| {
+        builder
+            .upstream(upstream)
+            .idle_timeout(Duration::from_millis(200))
+    });
+
+    let (mut stream, reply) = socks5_connect(proxy, origin);
+    assert_eq!(reply[1], 0x00);
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+}
+
+#[test]
+fn connections_over_the_limit_are_refused() {
+    let origin = spawn_echo_origin();
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| builder.upstream(upstream).max_connections(1));
+    // Let the readiness probe go away first.
+    thread::sleep(Duration::from_millis(100));
+
+    let (_held, reply) = socks5_connect(proxy, origin);
+    assert_eq!(reply[1], 0x00);
+
+    let mut refused = TcpStream::connect(proxy).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let _ = refused.write_all(&[0x05, 0x01, 0x00]);
+    assert!(!matches!(refused.read(&mut [0u8; 2]), Ok(n) if n > 0));
+}
+
+#[test]
+fn connections_over_the_per_ip_limit_are_refused() {
+    let origin = spawn_echo_origin();
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| builder.upstream(upstream).max_conns_per_ip(1));
+    thread::sleep(Duration::from_millis(100));
+
+    let (held, reply) = socks5_connect(proxy, origin);
+    assert_eq!(reply[1], 0x00);
+
+    let mut refused = TcpStream::connect(proxy).unwrap();
+    refused
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let _ = refused.write_all(&[0x05, 0x01, 0x00]);
+    assert!(!matches!(refused.read(&mut [0u8; 2]), Ok(n) if n > 0));
+
+    // The slot frees up once the held connection is gone
+    drop(held);
+    thread::sleep(Duration::from_millis(100));
+    let (_stream, reply) = socks5_connect(proxy, origin);
+    assert_eq!(reply[1], 0x00);
+}
+
+#[test]
+fn a_source_reconnects_after_resetting_its_connections() {
+    let origin = spawn_echo_origin();
+    let upstream = upstream();
+    let proxy = spawn_built(move |builder| builder.upstream(upstream).max_conns_per_ip(1));
+    thread::sleep(Duration::from_millis(100));
+
+    // Each reset frees the slot again, whether before or after the tunnel
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(proxy).unwrap();
+        stream.write_all(&[0x05, 0x01, 0x00]).unwrap();
+        abort(stream);
+        thread::sleep(Duration::from_millis(50));
+        let (stream, reply) = socks5_connect(proxy, origin);
+        assert_eq!(reply[1], 0x00);
+        abort(stream);
+        thread::sleep(Duration::from_millis(50));
+    }
+    let (_stream, reply) = socks5_connect(proxy, origin);
+    assert_eq!(reply[1], 0x00);
+}
+
+#[test]
+fn reports_an_address_in_use() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+
+    let err = Socks5Server::builder()
+        .listen(addr)
+        .upstream(upstream())
+        .build()
+        .serve()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    assert!(err
+        .to_string()
+        .starts_with(&format!("Failed to bind {}: ", addr)));
+}
+
+#[test]
+fn fails_to_start_as_an_unknown_user() {
+    let mut server = Socks5Server::builder()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .upstream(upstream())
+        .build();
+    server.user("no-such-proxychain-user");
+
+    let err = server.serve().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(err
+        .to_string()
+        .contains("unknown user no-such-proxychain-user"));
+}
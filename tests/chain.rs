@@ -0,0 +1,71 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use proxychain::acl::DirectRules;
+use proxychain::proxy::Proxy;
+
+use common::{
+    socks5_connect, spawn_built, spawn_echo_origin, spawn_http_proxy, spawn_recording_proxy,
+    spawn_server,
+};
+
+// A SOCKS5 hop: proxychain connecting to loopback targets itself.
+fn spawn_socks5_hop() -> SocketAddr {
+    let unused = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    spawn_server(unused, |server| {
+        let mut direct = DirectRules::default();
+        direct.add_loopback();
+        server.direct(direct);
+    })
+}
+
+fn spawn_chain(hops: Vec<String>) -> SocketAddr {
+    spawn_built(move |builder| {
+        hops.iter()
+            .fold(builder, |builder, url| builder.upstream(Proxy::parse(url)))
+            .chain(true)
+    })
+}
+
+#[test]
+fn tunnels_through_mixed_protocol_hops_in_order() {
+    let first = spawn_socks5_hop();
+    let second = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let third = spawn_socks5_hop();
+    let server = spawn_chain(vec![
+        format!("socks5://{}", first),
+        format!("http://{}", second),
+        format!("socks5://{}", third),
+    ]);
+    let origin = spawn_echo_origin();
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn answers_with_the_refusal_of_a_later_hop() {
+    let first = spawn_socks5_hop();
+    let (second, heads) = spawn_recording_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_chain(vec![
+        format!("socks5://{}", first),
+        format!("http://{}", second),
+    ]);
+    let origin = spawn_echo_origin();
+
+    let (_, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x02]);
+    // Reached through the first hop, asked for the destination itself
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with(&format!("CONNECT {} HTTP/1.1\r\n", origin)));
+}
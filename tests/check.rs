@@ -0,0 +1,64 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+use common::spawn_http_proxy;
+use proxychain::proxy::Proxy;
+use proxychain::socks::server::Socks5Server;
+
+fn server_for(upstream: &str) -> Socks5Server {
+    let mut server = Socks5Server::new(Proxy::parse("socks5://127.0.0.1:1080"));
+    server.subproxy(Proxy::parse(upstream));
+    server
+}
+
+#[test]
+fn reports_reachable_upstream() {
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let server = server_for(&format!("http://{}", proxy));
+    assert!(server.check(Duration::from_secs(5)));
+}
+
+#[test]
+fn reports_unreachable_upstream() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = server_for(&format!("http://{}", addr));
+    assert!(!server.check(Duration::from_secs(5)));
+}
+
+// SOCKS5 proxy that accepts no authentication and refuses every CONNECT
+// with REP 0x02, an HTTP request to it closes without an answer.
+fn spawn_socks5_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut greeting = [0u8; 3];
+            if stream.read_exact(&mut greeting).is_err() || greeting != [0x05, 0x01, 0x00] {
+                continue;
+            }
+            stream.write_all(&[0x05, 0x00]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x01]);
+            stream
+                .write_all(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn probes_socks5_upstream_with_a_socks5_handshake() {
+    let proxy = spawn_socks5_proxy();
+    let server = server_for(&format!("socks5://{}", proxy));
+    assert!(server.check(Duration::from_secs(5)));
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{socks5_connect, spawn_echo_origin, spawn_http_proxy};
+
+#[test]
+fn refuses_to_listen_for_https() {
+    let output = Command::new(env!("CARGO_BIN_EXE_proxychain"))
+        .args(["-i", "https://127.0.0.1:0", "-o", "http://127.0.0.1:8123"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(
+        "Unsupported IN proxy https://127.0.0.1:0: only socks5:// and http:// can be listened on"
+    ));
+}
+
+#[test]
+fn refuses_https_out_proxies() {
+    let output = Command::new(env!("CARGO_BIN_EXE_proxychain"))
+        .args(["-i", "socks5://127.0.0.1:0", "-o", "https://127.0.0.1:8443"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(
+        "Unsupported OUT proxy https://127.0.0.1:8443: https:// upstreams are not supported yet"
+    ));
+}
+
+#[test]
+fn refuses_dns_over_tls_and_https() {
+    for protocol in ["dot", "doh"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_proxychain"))
+            .args(["-i", "socks5://127.0.0.1:0", "-o", "http://127.0.0.1:8123"])
+            .args(["--dns", "1.1.1.1", "--dns-protocol", protocol])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+            "'{}' isn't a valid value for '--dns-protocol",
+            protocol
+        )));
+    }
+}
+
+fn explain(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_proxychain"))
+        .args(["-i", "socks5://127.0.0.1:0", "-o", "http://10.0.0.1:8123"])
+        .args(["-o", "socks5://10.0.0.2:1080"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn explains_the_routing_decision_without_serving() {
+    let rules = ["--direct", "corp.invalid", "--block-port", "25"];
+    assert_eq!(
+        explain(&[&rules[..], &["--explain", "git.corp.invalid:443"]].concat()),
+        "git.corp.invalid:443 (unresolved): direct, --direct matched domain corp.invalid\n"
+    );
+    assert_eq!(
+        explain(&[&rules[..], &["--explain", "10.1.2.3:25"]].concat()),
+        "10.1.2.3:25 (10.1.2.3): blocked by --block-port 25\n"
+    );
+    assert_eq!(
+        explain(&["--balance", "first", "--explain", "[2001:db8::1]:443"]),
+        "[2001:db8::1]:443 (2001:db8::1): upstream 10.0.0.1:8123, first upstream\n"
+    );
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn wait_listening(addr: SocketAddr) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(addr).is_err() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn takes_upstreams_from_the_environment_without_out() {
+    let addr = free_addr();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_proxychain"))
+        .args(["-i", &format!("socks5://{}", addr)])
+        .env("PROXYCHAIN_UPSTREAM", format!(" http://{}, ", proxy))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_listening(addr);
+
+    let (_, reply) = socks5_connect(addr, spawn_echo_origin());
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
+
+#[test]
+fn logs_relay_throughput_with_vv() {
+    let addr = free_addr();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_proxychain"))
+        .args([
+            "-i",
+            &format!("socks5://{}", addr),
+            "-o",
+            "http://127.0.0.1:9",
+        ])
+        .args(["--direct-loopback", "-vv", "--stats-interval", "1"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    wait_listening(addr);
+
+    let (mut stream, reply) = socks5_connect(addr, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    thread::sleep(Duration::from_millis(1500));
+    child.kill().unwrap();
+
+    let mut log = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut log)
+        .unwrap();
+    child.wait().unwrap();
+    assert!(log.contains("Throughput to 127.0.0.1:"), "{}", log);
+}
@@ -0,0 +1,237 @@
+#![allow(dead_code)]
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use proxychain::proxy::Proxy;
+use proxychain::socks::server::{Socks5Server, Socks5ServerBuilder};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+// Origin that sends back whatever it receives.
+pub fn spawn_echo_origin() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                let _ = io::copy(&mut reader, &mut stream);
+                let _ = stream.shutdown(Shutdown::Write);
+            });
+        }
+    });
+    addr
+}
+
+// HTTP proxy that answers CONNECT with `response` and then tunnels to the
+// requested authority.
+pub fn spawn_http_proxy(response: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || tunnel(stream, response));
+        }
+    });
+    addr
+}
+
+// HTTP proxy that answers every CONNECT with `response` and hands the
+// request head it got to the test.
+pub fn spawn_recording_proxy(response: &'static [u8]) -> (SocketAddr, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let _ = tx.send(read_head(&mut stream));
+            let _ = stream.write_all(response);
+        }
+    });
+    (addr, rx)
+}
+
+// Tunneling HTTP proxy like `spawn_http_proxy`, handing every request head
+// it got to the test.
+pub fn spawn_recording_tunnel() -> (SocketAddr, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let head = read_head(&mut stream);
+            let _ = tx.send(head.clone());
+            thread::spawn(move || relay(stream, &head, ESTABLISHED));
+        }
+    });
+    (addr, rx)
+}
+
+fn tunnel(mut client: TcpStream, response: &[u8]) {
+    let head = read_head(&mut client);
+    relay(client, &head, response);
+}
+
+fn relay(mut client: TcpStream, head: &str, response: &[u8]) {
+    let authority = head.split_whitespace().nth(1).unwrap().to_string();
+    client.write_all(response).unwrap();
+    if response.get(9..12) != Some(&b"200"[..]) {
+        return;
+    }
+    let mut origin = TcpStream::connect(authority.as_str()).unwrap();
+    let mut client_reader = client.try_clone().unwrap();
+    let mut origin_writer = origin.try_clone().unwrap();
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut origin_writer);
+        let _ = origin_writer.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut origin, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+}
+
+pub fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Runs proxychain chained to the given HTTP proxy and waits until it listens.
+pub fn spawn_proxychain(upstream: SocketAddr) -> SocketAddr {
+    spawn_server(upstream, |_| {})
+}
+
+pub fn spawn_server<F>(upstream: SocketAddr, setup: F) -> SocketAddr
+where
+    F: FnOnce(&mut Socks5Server) + Send + 'static,
+{
+    let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    thread::spawn(move || {
+        let mut server = Socks5Server::new(Proxy::parse(&format!("socks5://{}", addr)));
+        server.subproxy(Proxy::parse(&format!("http://{}", upstream)));
+        setup(&mut server);
+        server.serve().unwrap();
+    });
+    wait_listening(addr)
+}
+
+// Runs a server configured through the builder on a free port.
+pub fn spawn_built<F>(configure: F) -> SocketAddr
+where
+    F: FnOnce(Socks5ServerBuilder) -> Socks5ServerBuilder + Send + 'static,
+{
+    let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+    thread::spawn(move || {
+        let builder = Socks5Server::builder().listen(addr);
+        configure(builder).build().serve().unwrap();
+    });
+    wait_listening(addr)
+}
+
+fn wait_listening(addr: SocketAddr) -> SocketAddr {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("proxychain did not start listening on {}", addr);
+}
+
+// Performs the SOCKS5 handshake for an IPv4 target and returns the reply.
+pub fn socks5_connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, [u8; 10]) {
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+
+    let ip = match target {
+        SocketAddr::V4(v4) => v4.ip().octets(),
+        SocketAddr::V6(_) => panic!("IPv4 target expected"),
+    };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&ip);
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    (stream, reply)
+}
+
+// Minimal TLS 1.3 ClientHello record, with a server_name extension when
+// given one.
+pub fn client_hello(sni: Option<&str>) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(name) = sni {
+        let name = name.as_bytes();
+        let mut list = vec![0x00];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&list);
+    }
+    // supported_versions: TLS 1.3
+    extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend_from_slice(&[0x42; 32]);
+    hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+// Closes with SO_LINGER set to zero, which sends a RST instead of a FIN.
+pub fn abort(stream: TcpStream) {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    // SAFETY: the option value is a valid linger struct passed with its size.
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(ret, 0);
+}
@@ -0,0 +1,71 @@
+use std::net::{IpAddr, SocketAddr};
+
+use proxychain::datatype::{normalize_addr, Target};
+
+fn ip(value: &str) -> IpAddr {
+    value.parse().unwrap()
+}
+
+#[test]
+fn unmaps_ipv4_mapped_addresses() {
+    assert_eq!(normalize_addr(ip("::ffff:1.2.3.4")), ip("1.2.3.4"));
+    assert_eq!(normalize_addr(ip("::ffff:7f00:1")), ip("127.0.0.1"));
+    assert_eq!(normalize_addr(ip("1.2.3.4")), ip("1.2.3.4"));
+    // Neither IPv4-compatible nor other IPv6 addresses are touched
+    assert_eq!(normalize_addr(ip("::1.2.3.4")), ip("::1.2.3.4"));
+    assert_eq!(normalize_addr(ip("::1")), ip("::1"));
+    assert_eq!(normalize_addr(ip("2001:db8::1")), ip("2001:db8::1"));
+}
+
+#[test]
+fn connects_mapped_targets_over_ipv4() {
+    let mut target = Target::new();
+    target.domain = String::from("::ffff:192.0.2.1");
+    target.set_candidates(&[ip("::ffff:192.0.2.1"), ip("2001:db8::1")], 443, None);
+
+    let expected: SocketAddr = "192.0.2.1:443".parse().unwrap();
+    assert_eq!(target.addr, expected);
+    assert_eq!(target.candidates[0], expected);
+    assert_eq!(target.ip, "192.0.2.1");
+    assert_eq!(target.host(), "192.0.2.1");
+}
+
+fn target_at(value: &str) -> Target {
+    let mut target = Target::new();
+    target.set_candidates(&[ip(value)], 80, None);
+    target
+}
+
+#[test]
+fn brackets_ipv6_literal_hosts() {
+    let mut target = Target::new();
+    target.domain = String::from("::1");
+    assert_eq!(target.host(), "[::1]");
+    target.domain = String::from("example.com");
+    assert_eq!(target.host(), "example.com");
+}
+
+#[test]
+fn classifies_internal_targets() {
+    assert!(target_at("127.0.0.1").is_loopback());
+    assert!(target_at("::1").is_loopback());
+    assert!(target_at("169.254.169.254").is_link_local());
+    assert!(target_at("fe80::1").is_link_local());
+    assert!(target_at("10.1.2.3").is_private());
+    assert!(target_at("172.16.0.1").is_private());
+    assert!(target_at("192.168.1.1").is_private());
+    assert!(target_at("fd00::1").is_private());
+    assert!(target_at("0.0.0.0").is_internal());
+
+    for public in &["1.1.1.1", "172.32.0.1", "2001:db8::1", "fe00::1"] {
+        assert!(!target_at(public).is_internal(), "{}", public);
+    }
+}
+
+#[test]
+fn any_internal_candidate_makes_the_target_internal() {
+    let mut target = Target::new();
+    target.set_candidates(&[ip("1.1.1.1"), ip("::ffff:10.0.0.1")], 80, None);
+    assert!(target.is_private());
+    assert!(!target.is_loopback());
+}
@@ -0,0 +1,76 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use proxychain::dns::{order_srv, DnsCache, SrvRecord};
+
+fn ip(last: u8) -> Vec<IpAddr> {
+    vec![IpAddr::from([192, 0, 2, last])]
+}
+
+#[test]
+fn caches_answers_until_their_ttl_runs_out() {
+    let now = Instant::now();
+    let mut cache = DnsCache::new(4);
+    cache.insert("Example.com", ip(1), now + Duration::from_secs(30));
+
+    assert_eq!(cache.get("example.com", now), Some(ip(1)));
+    assert_eq!(
+        cache.get("example.com", now + Duration::from_secs(31)),
+        None
+    );
+    assert!(cache.is_empty());
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+}
+
+#[test]
+fn evicts_the_least_recently_used_domain() {
+    let now = Instant::now();
+    let expires = now + Duration::from_secs(60);
+    let mut cache = DnsCache::new(2);
+    cache.insert("a.example", ip(1), expires);
+    cache.insert("b.example", ip(2), expires);
+    assert!(cache.get("a.example", now).is_some());
+    cache.insert("c.example", ip(3), expires);
+
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get("b.example", now), None);
+    assert_eq!(cache.get("a.example", now), Some(ip(1)));
+    assert_eq!(cache.get("c.example", now), Some(ip(3)));
+}
+
+fn srv(priority: u16, weight: u16, target: &str) -> SrvRecord {
+    SrvRecord {
+        priority,
+        weight,
+        port: 3128,
+        target: String::from(target),
+    }
+}
+
+#[test]
+fn orders_srv_records_by_priority_then_weight() {
+    let records = vec![
+        srv(20, 0, "backup.example"),
+        srv(10, 10, "light.example"),
+        srv(10, 90, "heavy.example"),
+    ];
+    let targets = |records: Vec<SrvRecord>| {
+        records
+            .into_iter()
+            .map(|record| record.target)
+            .collect::<Vec<_>>()
+    };
+
+    // Draws up to the weight of the first record pick it, any higher the
+    // next one
+    let ordered = order_srv(records.clone(), |_| 5);
+    assert_eq!(
+        targets(ordered),
+        ["light.example", "heavy.example", "backup.example"]
+    );
+    let ordered = order_srv(records, |total| total);
+    assert_eq!(
+        targets(ordered),
+        ["heavy.example", "light.example", "backup.example"]
+    );
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use mio::{net::TcpListener as MioListener, Events, Interest, Poll, Token};
+use proxychain::proxy::Proxy;
+use proxychain::socks::server::Socks5Server;
+
+use common::{socks5_connect, spawn_echo_origin, spawn_http_proxy};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+const OWN: Token = Token(0);
+
+#[test]
+fn shares_a_poll_with_the_caller() {
+    let origin = spawn_echo_origin();
+    let upstream = spawn_http_proxy(ESTABLISHED);
+    let socks_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let own_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(64);
+        let mut own = MioListener::bind(own_addr).unwrap();
+        tx.send(own.local_addr().unwrap()).unwrap();
+        poll.registry()
+            .register(&mut own, OWN, Interest::READABLE)
+            .unwrap();
+
+        let mut server = Socks5Server::builder()
+            .listen(socks_addr)
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+            .build();
+        server.setup(poll.registry(), Token(10)).unwrap();
+        loop {
+            server.serve_with(&mut poll, &mut events).unwrap();
+            for event in events.iter() {
+                if event.token() == OWN {
+                    while own.accept().is_ok() {
+                        tx.send(own_addr).unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    // The caller's own listener keeps working, and once its connection is
+    // seen the server is set up too.
+    let own_addr = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let _own = TcpStream::connect(own_addr).unwrap();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let (mut stream, reply) = socks5_connect(socks_addr, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
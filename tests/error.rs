@@ -0,0 +1,15 @@
+use std::error::Error;
+use std::io;
+
+use proxychain::error::ProxyError;
+
+#[test]
+fn keeps_the_kind_of_io_errors() {
+    let err = ProxyError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+    assert!(err.source().is_some());
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::ConnectionReset);
+
+    let err = ProxyError::Protocol(String::from("unsupported SOCKS version"));
+    assert_eq!(err.to_string(), "protocol error: unsupported SOCKS version");
+    assert!(err.source().is_none());
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use common::{socks5_connect, spawn_echo_origin, spawn_http_proxy, spawn_server};
+
+fn wait_for(active: &AtomicUsize, expected: usize) {
+    for _ in 0..100 {
+        if active.load(Ordering::Relaxed) == expected {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(active.load(Ordering::Relaxed), expected);
+}
+
+#[test]
+fn counts_connections_until_every_kind_of_close() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200 Connection established\r\n\r\n");
+    let (tx, rx) = mpsc::channel();
+    let server = spawn_server(proxy, move |server| tx.send(server.active()).unwrap());
+    let active: Arc<AtomicUsize> = rx.recv().unwrap();
+    wait_for(&active, 0);
+
+    // A relayed tunnel, a client leaving mid-handshake and a refused version
+    let (tunnel, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let idle = TcpStream::connect(server).unwrap();
+    let mut refused = TcpStream::connect(server).unwrap();
+    refused.write_all(&[0x06, 0x01, 0x00]).unwrap();
+    let _ = refused.read(&mut [0u8; 1]);
+    wait_for(&active, 2);
+
+    idle.shutdown(Shutdown::Both).unwrap();
+    wait_for(&active, 1);
+    tunnel.shutdown(Shutdown::Both).unwrap();
+    wait_for(&active, 0);
+}
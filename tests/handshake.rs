@@ -0,0 +1,398 @@
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use proxychain::datatype::BndMode;
+use proxychain::dns::DnsProtocol;
+use proxychain::logger::{self, LogFormat, LogTarget};
+use proxychain::proxy::Proxy;
+
+use common::{
+    abort, socks5_connect, spawn_built, spawn_echo_origin, spawn_http_proxy, spawn_proxychain,
+    spawn_recording_proxy, spawn_server,
+};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+fn negotiate(server: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+    stream
+}
+
+#[test]
+fn survives_truncated_domain_requests() {
+    let origin = spawn_echo_origin();
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+
+    // A domain length claiming more bytes than are ever sent
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 0xff];
+    request.extend_from_slice(b"example.com");
+    request.extend_from_slice(&80u16.to_be_bytes());
+    for len in 4..=request.len() {
+        let mut stream = negotiate(server);
+        stream.write_all(&request[..len]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
+
+#[test]
+fn cuts_off_requests_larger_than_the_cap() {
+    let origin = spawn_echo_origin();
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), |server| {
+        server.max_request_size(4096)
+    });
+
+    // A SOCKS4 user id never ends, each frame would wait for more bytes
+    for greeting in [
+        &[0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1][..],
+        &[0x05, 0xFF][..],
+    ] {
+        let mut stream = TcpStream::connect(server).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(greeting).unwrap();
+        let flood = vec![0x41; 256 * 1024];
+        let _ = stream.write_all(&flood);
+        let mut rest = Vec::new();
+        match stream.read_to_end(&mut rest) {
+            Ok(_) => assert!(rest.is_empty()),
+            Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset),
+        }
+    }
+
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
+
+#[test]
+fn rejects_gssapi_only_clients_with_no_acceptable_method() {
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(&[0x05, 0x01, 0x01]).unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).unwrap();
+    assert_eq!(answer, [0x05, 0xFF]);
+}
+
+#[test]
+fn answers_bind_and_udp_associate_as_unsupported() {
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+
+    for cmd in [0x02, 0x03, 0x09] {
+        let mut stream = negotiate(server);
+        stream
+            .write_all(&[0x05, cmd, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+            .unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..2], [0x05, 0x07]);
+    }
+}
+
+#[test]
+fn survives_requests_shorter_than_the_header() {
+    let origin = spawn_echo_origin();
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+
+    for len in 1..4 {
+        let mut stream = negotiate(server);
+        stream.write_all(&[0x05, 0x01, 0x00][..len]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
+
+#[test]
+fn accepts_frames_split_across_segments() {
+    let origin = spawn_echo_origin();
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let send = |stream: &mut TcpStream, bytes: &[u8]| {
+        for byte in bytes {
+            stream.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+    };
+
+    send(&mut stream, &[0x05, 0x01, 0x00]);
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 0x09];
+    request.extend_from_slice(b"127.0.0.1");
+    request.extend_from_slice(&origin.port().to_be_bytes());
+    send(&mut stream, &request);
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn rejects_domains_that_would_smuggle_headers() {
+    let (proxy, heads) = spawn_recording_proxy(ESTABLISHED);
+    let server = spawn_proxychain(proxy);
+
+    let domain = b"example.com\r\nHost: evil";
+    let mut stream = negotiate(server);
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&80u16.to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x05, 0x08]);
+    assert!(heads.recv_timeout(Duration::from_millis(300)).is_err());
+}
+
+#[test]
+fn answers_resolve_with_the_address() {
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), |server| {
+        server.enable_resolve(true);
+    });
+
+    let mut stream = negotiate(server);
+    let mut request = vec![0x05, 0xF0, 0x00, 0x03, 0x09];
+    request.extend_from_slice(b"127.0.0.1");
+    request.extend_from_slice(&0u16.to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, [0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0]);
+}
+
+// DNS server answering every query with NXDOMAIN.
+fn spawn_nxdomain_dns() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut query = [0u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut query).unwrap();
+            // Header and question of the query, only the name ends in a 0
+            let end = 12 + query[12..len].iter().position(|b| *b == 0).unwrap() + 5;
+            let mut answer = query[..end].to_vec();
+            answer[2] = 0x81;
+            answer[3] = 0x83;
+            answer[6..12].copy_from_slice(&[0; 6]);
+            socket.send_to(&answer, peer).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn reports_unresolvable_domains_as_unreachable() {
+    let dns = spawn_nxdomain_dns();
+    let upstream = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_built(move |builder| {
+        builder
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+            .resolver(dns, DnsProtocol::Udp)
+    });
+
+    let domain = b"missing.example";
+    let mut stream = negotiate(server);
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&80u16.to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x05, 0x04]);
+}
+
+#[test]
+fn fails_fast_when_the_dns_server_never_answers() {
+    // Bound but never read, queries to it go unanswered
+    let blackhole = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dns = blackhole.local_addr().unwrap();
+    let upstream = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_built(move |builder| {
+        builder
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+            .resolver(dns, DnsProtocol::Udp)
+    });
+
+    let domain = b"unanswered.example";
+    let mut stream = negotiate(server);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&80u16.to_be_bytes());
+    let started = Instant::now();
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x05, 0x04]);
+    // The resolver gives up after its 2s timeout
+    assert!(
+        started.elapsed() < Duration::from_secs(5),
+        "took {:?}",
+        started.elapsed()
+    );
+    drop(blackhole);
+}
+
+// DNS server answering A queries with 127.0.0.1 after a delay, any other
+// query with no record.
+fn spawn_slow_dns(delay: Duration) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut query = [0u8; 512];
+        let (len, peer) = socket.recv_from(&mut query).unwrap();
+        let socket = socket.try_clone().unwrap();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let end = 12 + query[12..len].iter().position(|b| *b == 0).unwrap() + 5;
+            let mut answer = query[..end].to_vec();
+            answer[2] = 0x81;
+            answer[3] = 0x80;
+            answer[6..12].copy_from_slice(&[0; 6]);
+            if query[end - 4..end - 2] == [0x00, 0x01] {
+                answer[7] = 1;
+                answer
+                    .extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+            let _ = socket.send_to(&answer, peer);
+        });
+    });
+    addr
+}
+
+#[test]
+fn survives_a_client_resetting_while_its_target_resolves() {
+    // Arguments of log lines only get evaluated with a logger taking them
+    let log = env::temp_dir().join(format!("proxychain-reset-{}.log", process::id()));
+    logger::init(LogTarget::File(log.clone()), LogFormat::Text, "info").unwrap();
+    let dns = spawn_slow_dns(Duration::from_millis(300));
+    let upstream = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_built(move |builder| {
+        builder
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+            .resolver(dns, DnsProtocol::Udp)
+    });
+    let origin = spawn_echo_origin();
+
+    let domain = b"slow.example";
+    let mut stream = negotiate(server);
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&origin.port().to_be_bytes());
+    stream.write_all(&request).unwrap();
+    // Reset once the request was taken and the lookup is underway
+    thread::sleep(Duration::from_millis(100));
+    abort(stream);
+
+    // The answer for the gone client comes and goes
+    thread::sleep(Duration::from_millis(1000));
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let _ = fs::remove_file(log);
+}
+
+// Requests a domain from each stream at once, returns when all were answered.
+fn request_domains(streams: &mut [TcpStream], port: u16) -> Duration {
+    let started = Instant::now();
+    for (i, stream) in streams.iter_mut().enumerate() {
+        let domain = format!("slow{}.example", i);
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend_from_slice(domain.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).unwrap();
+    }
+    for stream in streams.iter_mut() {
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..2], [0x05, 0x00]);
+    }
+    started.elapsed()
+}
+
+#[test]
+fn resolves_concurrent_requests_in_parallel() {
+    let dns = spawn_slow_dns(Duration::from_millis(300));
+    let upstream = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_built(move |builder| {
+        builder
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+            .resolver(dns, DnsProtocol::Udp)
+    });
+    let origin = spawn_echo_origin();
+
+    let single = request_domains(&mut [negotiate(server)], origin.port());
+    let mut streams: Vec<TcpStream> = (0..8).map(|_| negotiate(server)).collect();
+    let concurrent = request_domains(&mut streams, origin.port());
+    // One after the other they would take 8 lookups
+    assert!(
+        concurrent < single * 2,
+        "8 lookups took {:?}, one took {:?}",
+        concurrent,
+        single
+    );
+}
+
+#[test]
+fn reports_the_advertised_address_as_bound() {
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), |server| {
+        server.advertise_addr("203.0.113.7:1080".parse().unwrap());
+        server.bnd_mode(BndMode::Advertise);
+    });
+
+    let (_stream, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply, [0x05, 0x00, 0x00, 0x01, 203, 0, 113, 7, 0x04, 0x38]);
+}
+
+#[test]
+fn reports_zeros_as_bound_by_default() {
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), |server| {
+        server.advertise_addr("203.0.113.7:1080".parse().unwrap());
+    });
+
+    let (_stream, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply, [0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+}
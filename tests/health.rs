@@ -0,0 +1,46 @@
+mod common;
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::{socks5_connect, spawn_echo_origin, spawn_http_proxy, spawn_server};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+fn read_status(addr: SocketAddr) -> String {
+    for _ in 0..100 {
+        if let Ok(mut stream) = TcpStream::connect(addr) {
+            let mut status = String::new();
+            stream.read_to_string(&mut status).unwrap();
+            return status;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("health check did not start listening on {}", addr);
+}
+
+#[test]
+fn answers_with_the_active_connections() {
+    let health = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), move |server| {
+        server.health_addr(health)
+    });
+
+    assert!(read_status(health).starts_with("OK\nactive "));
+
+    let (_stream, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    // The probe of spawn_server may still be counted for a moment
+    for _ in 0..100 {
+        if read_status(health) == "OK\nactive 1\n" {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("health check never reported the open tunnel");
+}
@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use proxychain::histogram::Histogram;
+
+fn ms(value: u64) -> Duration {
+    Duration::from_millis(value)
+}
+
+#[test]
+fn reports_percentiles_within_a_bucket() {
+    let mut histogram = Histogram::new();
+    assert_eq!(histogram.percentile(0.5), None);
+    assert_eq!(histogram.summary(), "-");
+
+    for _ in 0..90 {
+        histogram.record(ms(10));
+    }
+    for _ in 0..9 {
+        histogram.record(ms(200));
+    }
+    histogram.record(Duration::from_secs(3));
+    assert_eq!(histogram.count(), 100);
+
+    // Upper bounds of the buckets, never below the recorded value and
+    // less than a fifth above it
+    let within = |value: Duration, expected: Duration| {
+        value >= expected && value.as_secs_f64() < expected.as_secs_f64() * 1.2
+    };
+    assert!(within(histogram.percentile(0.5).unwrap(), ms(10)));
+    assert!(within(histogram.percentile(0.95).unwrap(), ms(200)));
+    assert!(within(histogram.percentile(1.0).unwrap(), ms(3000)));
+}
+
+#[test]
+fn clamps_extreme_durations_into_the_outer_buckets() {
+    let mut histogram = Histogram::new();
+    histogram.record(Duration::ZERO);
+    assert_eq!(histogram.percentile(0.5), Some(ms(1)));
+
+    histogram.record(Duration::from_secs(365 * 24 * 3600));
+    assert!(histogram.percentile(1.0).unwrap() > Duration::from_secs(3600));
+}
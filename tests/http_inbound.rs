@@ -0,0 +1,66 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use proxychain::proxy::{Proxy, ProxyProtocol};
+
+use common::{spawn_built, spawn_echo_origin, spawn_http_proxy};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+fn spawn_http_inbound(upstream: SocketAddr) -> SocketAddr {
+    spawn_built(move |builder| {
+        builder
+            .inbound(ProxyProtocol::HTTPProxy)
+            .upstream(Proxy::parse(&format!("http://{}", upstream)))
+    })
+}
+
+// Sends `request` and reads the response head.
+fn request(server: SocketAddr, request: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8(head).unwrap())
+}
+
+#[test]
+fn tunnels_connect_requests() {
+    let origin = spawn_echo_origin();
+    let server = spawn_http_inbound(spawn_http_proxy(ESTABLISHED));
+
+    let (mut stream, head) = request(
+        server,
+        &format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", origin),
+    );
+    assert_eq!(head, "HTTP/1.1 200 Connection established\r\n\r\n");
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn answers_refused_tunnels_with_a_status() {
+    let server = spawn_http_inbound(spawn_http_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n"));
+
+    let (_, head) = request(server, "CONNECT 127.0.0.1:9 HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+}
+
+#[test]
+fn refuses_methods_other_than_connect() {
+    let server = spawn_http_inbound(spawn_http_proxy(ESTABLISHED));
+
+    let (_, head) = request(server, "GET http://127.0.0.1/ HTTP/1.1\r\n\r\n");
+    assert!(head.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+}
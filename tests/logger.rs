@@ -0,0 +1,27 @@
+use std::fs;
+use std::process;
+
+use log::{debug, info};
+use proxychain::logger::{self, LogFormat, LogTarget};
+
+#[test]
+fn appends_filtered_json_lines_to_the_file() {
+    let path = std::env::temp_dir().join(format!("proxychain-log-{}.log", process::id()));
+    fs::write(&path, "existing\n").unwrap();
+
+    logger::init(LogTarget::File(path.clone()), LogFormat::Json, "info").unwrap();
+    info!("first");
+    debug!("hidden");
+    info!("second");
+    log::logger().flush();
+
+    let content = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "existing");
+    let entry: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(entry["level"], "INFO");
+    assert_eq!(entry["message"], "first");
+    assert!(lines[2].contains("\"message\":\"second\""));
+}
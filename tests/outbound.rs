@@ -0,0 +1,58 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::thread;
+
+use common::{socks5_connect, spawn_server};
+use proxychain::acl::DirectRules;
+use proxychain::datatype::BndMode;
+use proxychain::outbound::Outbound;
+
+const SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+// Origin that tells every peer the address it connected from.
+fn spawn_peer_origin() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let peer = stream.peer_addr().unwrap().ip().to_string();
+            let _ = stream.write_all(peer.as_bytes());
+            let _ = stream.read(&mut [0u8; 1]);
+        }
+    });
+    addr
+}
+
+#[test]
+fn direct_connections_leave_from_the_bound_address() {
+    let origin = spawn_peer_origin();
+    let server = spawn_server(origin, |server| {
+        let mut direct = DirectRules::default();
+        direct.add("127.0.0.0/8");
+        server.direct(direct);
+        server.outbound(Outbound::Addr(SOURCE));
+        server.bnd_mode(BndMode::Local);
+    });
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let mut peer = [0u8; 9];
+    stream.read_exact(&mut peer).unwrap();
+    assert_eq!(&peer, b"127.0.0.2");
+    // BND.ADDR/BND.PORT point at where the connection left from
+    assert_eq!(reply[3..8], [0x01, 127, 0, 0, 2]);
+    assert_ne!(reply[8..], [0, 0]);
+}
+
+#[test]
+fn parses_addresses_and_interfaces() {
+    assert_eq!(Outbound::parse("127.0.0.2"), Some(Outbound::Addr(SOURCE)));
+    assert_eq!(
+        Outbound::parse("eth0"),
+        Some(Outbound::Device(String::from("eth0")))
+    );
+    assert_eq!(Outbound::parse("an-interface-name-too-long"), None);
+}
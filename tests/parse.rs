@@ -0,0 +1,168 @@
+mod common;
+
+use std::net::IpAddr;
+
+use proxychain::socks::parse::{
+    parse_auth_request, parse_client_hello_sni, parse_connection_request, parse_http_connect,
+    parse_method_request, parse_socks4_request, Address, ParseError,
+};
+
+use common::client_hello;
+
+#[test]
+fn parses_the_offered_methods() {
+    let request = parse_method_request(&[0x05, 0x02, 0x00, 0x02]).unwrap();
+    assert_eq!(request.methods, vec![0x00, 0x02]);
+
+    assert_eq!(
+        parse_method_request(&[0x05, 0x02, 0x00]),
+        Err(ParseError::Incomplete)
+    );
+    assert!(matches!(
+        parse_method_request(&[0x06, 0x01, 0x00]),
+        Err(ParseError::Protocol(_))
+    ));
+}
+
+#[test]
+fn parses_connection_requests() {
+    let request = parse_connection_request(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80], 255);
+    let request = request.unwrap();
+    assert_eq!(request.cmd, 0x01);
+    assert_eq!(request.address, Address::Ip(IpAddr::from([127, 0, 0, 1])));
+    assert_eq!(request.port, 80);
+
+    let mut frame = vec![0x05, 0x01, 0x00, 0x03, 11];
+    frame.extend_from_slice(b"example.com");
+    frame.extend_from_slice(&443u16.to_be_bytes());
+    let request = parse_connection_request(&frame, 255).unwrap();
+    assert_eq!(
+        request.address,
+        Address::Domain(String::from("example.com"))
+    );
+    assert_eq!(request.port, 443);
+
+    // Every prefix of a valid frame waits for the rest
+    for len in 0..frame.len() {
+        assert_eq!(
+            parse_connection_request(&frame[..len], 255),
+            Err(ParseError::Incomplete)
+        );
+    }
+}
+
+#[test]
+fn answers_invalid_domains_and_drops_malformed_requests() {
+    let mut frame = vec![0x05, 0x01, 0x00, 0x03, 4];
+    frame.extend_from_slice(b"a b!");
+    frame.extend_from_slice(&[0, 80]);
+    assert!(matches!(
+        parse_connection_request(&frame, 255),
+        Err(ParseError::Reply(0x08, _))
+    ));
+
+    assert!(matches!(
+        parse_connection_request(&[0x05, 0x01, 0x01, 0x01, 127, 0, 0, 1, 0, 80], 255),
+        Err(ParseError::Protocol(_))
+    ));
+    assert!(matches!(
+        parse_connection_request(&[0x05, 0x01, 0x00, 0x09], 255),
+        Err(ParseError::Protocol(_))
+    ));
+}
+
+#[test]
+fn parses_username_and_password() {
+    let request = parse_auth_request(b"\x01\x04user\x06secret").unwrap();
+    assert_eq!(request.username, b"user");
+    assert_eq!(request.password, b"secret");
+
+    assert_eq!(
+        parse_auth_request(b"\x01\x04user\x06sec"),
+        Err(ParseError::Incomplete)
+    );
+    assert!(matches!(
+        parse_auth_request(b"\x05\x04user\x06secret"),
+        Err(ParseError::Protocol(_))
+    ));
+}
+
+#[test]
+fn parses_socks4_and_socks4a_requests() {
+    let request = parse_socks4_request(b"\x04\x01\x00\x50\x7f\x00\x00\x01bob\x00", 255).unwrap();
+    assert_eq!(request.address, Address::Ip(IpAddr::from([127, 0, 0, 1])));
+    assert_eq!(request.port, 80);
+    assert_eq!(request.userid, b"bob");
+
+    let frame = b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00";
+    let request = parse_socks4_request(frame, 255).unwrap();
+    assert_eq!(
+        request.address,
+        Address::Domain(String::from("example.com"))
+    );
+    assert_eq!(request.port, 443);
+    assert_eq!(
+        parse_socks4_request(&frame[..frame.len() - 1], 255),
+        Err(ParseError::Incomplete)
+    );
+
+    assert!(matches!(
+        parse_socks4_request(b"\x04\x02\x00\x50\x7f\x00\x00\x01\x00", 255),
+        Err(ParseError::Reply(0x5B, _))
+    ));
+}
+
+#[test]
+fn parses_http_connect_requests() {
+    let request = parse_http_connect(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n", 255).unwrap();
+    assert_eq!(
+        request.address,
+        Address::Domain(String::from("example.com"))
+    );
+    assert_eq!(request.port, 443);
+
+    let request = parse_http_connect(b"CONNECT [::1]:8443 HTTP/1.1\r\nHost: x\r\n\r\n", 255);
+    assert_eq!(
+        request.unwrap().address,
+        Address::Ip(IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1]))
+    );
+
+    assert_eq!(
+        parse_http_connect(b"CONNECT example.com:443 HTTP/1.1\r\n", 255),
+        Err(ParseError::Incomplete)
+    );
+    assert!(matches!(
+        parse_http_connect(b"CONNECT example.com HTTP/1.1\r\n\r\n", 255),
+        Err(ParseError::Reply(0x08, _))
+    ));
+    assert!(matches!(
+        parse_http_connect(b"GET / HTTP/1.1\r\n\r\n", 255),
+        Err(ParseError::Reply(0x07, _))
+    ));
+}
+
+#[test]
+fn extracts_the_sni_of_a_client_hello() {
+    let hello = client_hello(Some("example.com"));
+    assert_eq!(
+        parse_client_hello_sni(&hello),
+        Ok(Some(String::from("example.com")))
+    );
+    assert_eq!(parse_client_hello_sni(&client_hello(None)), Ok(None));
+
+    assert_eq!(
+        parse_client_hello_sni(&hello[..hello.len() - 1]),
+        Err(ParseError::Incomplete)
+    );
+    assert!(matches!(
+        parse_client_hello_sni(b"GET / HTTP/1.1\r\n\r\n"),
+        Err(ParseError::Protocol(_))
+    ));
+    // Cipher suites reaching past the end of the record
+    let mut broken = hello.clone();
+    broken[44] = 0xff;
+    assert!(matches!(
+        parse_client_hello_sni(&broken),
+        Err(ParseError::Protocol(_))
+    ));
+}
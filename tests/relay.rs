@@ -0,0 +1,780 @@
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use proxychain::acl::DestinationRules;
+use proxychain::http::{HostStyle, HttpVersion};
+use proxychain::proxy::Proxy;
+use proxychain::socks::server::Socks5Server;
+
+use common::{
+    abort, client_hello, read_head, socks5_connect, spawn_built, spawn_echo_origin,
+    spawn_http_proxy, spawn_proxychain, spawn_recording_proxy, spawn_recording_tunnel,
+    spawn_server,
+};
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+#[test]
+fn round_trips_through_http_proxy() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    let payload: Vec<u8> = (0..16 * 1024).map(|i| (i % 251) as u8).collect();
+    stream.write_all(&payload).unwrap();
+    let mut echoed = vec![0u8; payload.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(echoed, payload);
+}
+
+#[test]
+fn relays_large_transfers_byte_for_byte() {
+    let origin = spawn_echo_origin();
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    // Far more than a buffer, written while reading so both directions
+    // keep handing buffers over
+    let payload: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut writer = stream.try_clone().unwrap();
+    let sent = payload.clone();
+    let upload = thread::spawn(move || writer.write_all(&sent).unwrap());
+    let mut echoed = vec![0u8; payload.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    upload.join().unwrap();
+    assert!(echoed == payload);
+}
+
+#[test]
+fn relays_concurrent_tunnels_with_one_event_per_poll() {
+    let origin = spawn_echo_origin();
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), |server| {
+        server.event_capacity(1)
+    });
+
+    let mut streams: Vec<TcpStream> = (0..4)
+        .map(|_| {
+            let (stream, reply) = socks5_connect(server, origin);
+            assert_eq!(reply[..2], [0x05, 0x00]);
+            stream
+        })
+        .collect();
+    for (i, stream) in streams.iter_mut().enumerate() {
+        stream.write_all(&[i as u8; 512]).unwrap();
+    }
+    for (i, stream) in streams.iter_mut().enumerate() {
+        let mut echoed = [0u8; 512];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, [i as u8; 512]);
+    }
+}
+
+#[test]
+fn round_trips_over_socks4() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_proxychain(proxy);
+
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&origin.port().to_be_bytes());
+    request.extend_from_slice(&[127, 0, 0, 1]);
+    request.push(0x00);
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x00, 0x5A]);
+
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn keeps_tunnel_bytes_sent_with_the_response() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200\r\n\r\nHELLO");
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    let mut early = [0u8; 5];
+    stream.read_exact(&mut early).unwrap();
+    assert_eq!(&early, b"HELLO");
+}
+
+// HTTP proxy that sends its CONNECT response in two writes, the blank line
+// coming late, and then echoes the tunnel itself.
+fn spawn_split_response_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                read_head(&mut stream);
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\nVia: 1.1 split")
+                    .unwrap();
+                thread::sleep(Duration::from_millis(100));
+                stream.write_all(b"\r\n\r\n").unwrap();
+                let mut reader = stream.try_clone().unwrap();
+                let _ = io::copy(&mut reader, &mut stream);
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn waits_for_the_whole_response_head_before_tunneling() {
+    let server = spawn_proxychain(spawn_split_response_proxy());
+
+    let (mut stream, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn refused_tunnel_is_reported() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_proxychain(proxy);
+
+    let (_, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x02]);
+}
+
+#[test]
+fn rejects_a_status_that_is_not_three_digits() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.1 2!0 OK\r\n\r\n");
+    let server = spawn_proxychain(proxy);
+
+    let (_, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x01]);
+}
+
+#[test]
+fn blocks_internal_targets_before_reaching_the_upstream() {
+    let (proxy, heads) = spawn_recording_proxy(ESTABLISHED);
+    let server = spawn_server(proxy, |server| {
+        let mut rules = DestinationRules::default();
+        rules.block_internal();
+        server.rules(rules);
+    });
+
+    let (_, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x02]);
+    assert!(heads.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+// HTTP proxy that hangs up on every connection right away.
+fn spawn_closing_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            drop(stream);
+        }
+    });
+    addr
+}
+
+#[test]
+fn upstream_closing_before_answering_is_a_general_failure() {
+    let server = spawn_proxychain(spawn_closing_proxy());
+
+    let (_, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x01]);
+}
+
+#[test]
+fn sends_extra_connect_headers_in_order() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_server(proxy, |server| {
+        server.connect_header(String::from("User-Agent"), String::from("proxychain"));
+        server.connect_header(String::from("X-Token"), String::from("secret"));
+    });
+
+    let (_stream, reply) = socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    assert_eq!(reply[..2], [0x05, 0x02]);
+
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with("CONNECT 127.0.0.1:9 HTTP/1.1\r\n"));
+    assert!(head.ends_with("User-Agent: proxychain\r\nX-Token: secret\r\n\r\n"));
+    assert_eq!(head.matches("\r\n\r\n").count(), 1);
+}
+
+#[test]
+fn authorizes_with_the_proxy_url_credentials() {
+    let (proxy, heads) =
+        spawn_recording_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+    let server = spawn_built(move |builder| {
+        builder.upstream(Proxy::parse(&format!("http://user:secret@{}", proxy)))
+    });
+
+    socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.ends_with("\r\nProxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"));
+}
+
+#[test]
+fn keeps_a_configured_proxy_authorization() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_server(proxy, |server| {
+        server.connect_header(
+            String::from("Proxy-Authorization"),
+            String::from("Bearer t"),
+        );
+    });
+
+    socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(head.matches("Proxy-Authorization").count(), 1);
+    assert!(head.ends_with("\r\nProxy-Authorization: Bearer t\r\n\r\n"));
+}
+
+#[test]
+fn omits_default_ports_from_the_host_header() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_server(proxy, |server| {
+        server.connect_host_style(HostStyle::OmitDefault)
+    });
+
+    socks5_connect(server, "127.0.0.1:443".parse().unwrap());
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with("CONNECT 127.0.0.1:443 HTTP/1.1\r\n"));
+    assert!(head.contains("\r\nHost: 127.0.0.1\r\n"));
+
+    socks5_connect(server, "127.0.0.1:8443".parse().unwrap());
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.contains("\r\nHost: 127.0.0.1:8443\r\n"));
+}
+
+#[test]
+fn forwards_port_80_requests_in_absolute_form() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+    let server = spawn_server(proxy, |server| server.http_absolute_form(true));
+
+    let (mut stream, reply) = socks5_connect(server, "127.0.0.1:80".parse().unwrap());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream
+        .write_all(b"GET /index.html HTTP/1.1\r\nHost: 127.0.0.1\r\n")
+        .unwrap();
+    stream
+        .write_all(b"Connection: keep-alive\r\nAccept: */*\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("\r\n\r\nok"));
+
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(
+        head,
+        "GET http://127.0.0.1/index.html HTTP/1.1\r\nHost: 127.0.0.1\r\n\
+         Accept: */*\r\nConnection: close\r\n\r\n"
+    );
+}
+
+#[test]
+fn tunnels_other_ports_despite_absolute_form() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_server(proxy, |server| server.http_absolute_form(true));
+
+    socks5_connect(server, "127.0.0.1:443".parse().unwrap());
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with("CONNECT 127.0.0.1:443 HTTP/1.1\r\n"));
+}
+
+#[test]
+fn speaks_http_1_0_without_keep_alive_hints() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.0 403 Forbidden\r\n\r\n");
+    let server = spawn_server(proxy, |server| {
+        server.upstream_http_version(HttpVersion::Http10)
+    });
+    socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with("CONNECT 127.0.0.1:9 HTTP/1.0\r\nHost: 127.0.0.1:9\r\n"));
+    assert!(!head.contains("keep-alive"));
+
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(b"HTTP/1.0 200 Connection established\r\n\r\n");
+    let server = spawn_server(proxy, |server| {
+        server.upstream_http_version(HttpVersion::Http10)
+    });
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn keeps_relaying_after_the_client_half_closes() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    let payload: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    stream.write_all(&payload).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, payload);
+}
+
+// Origin that greets first, the way SMTP servers do, then echoes.
+fn spawn_banner_origin() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                stream.write_all(b"220 origin ESMTP\r\n").unwrap();
+                let mut reader = stream.try_clone().unwrap();
+                let _ = io::copy(&mut reader, &mut stream);
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn delivers_the_banner_of_a_server_speaks_first_origin() {
+    let origin = spawn_banner_origin();
+    let proxy = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let mut banner = [0u8; 18];
+    stream.read_exact(&mut banner).unwrap();
+    assert_eq!(&banner, b"220 origin ESMTP\r\n");
+
+    stream.write_all(b"QUIT\r\n").unwrap();
+    let mut echoed = [0u8; 6];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"QUIT\r\n");
+}
+
+#[test]
+fn prepends_proxy_protocol_header_once() {
+    let (proxy, heads) = spawn_recording_proxy(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    let server = spawn_server(proxy, |server| server.send_proxy_protocol(true));
+
+    let (stream, reply) = socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    assert_eq!(reply[..2], [0x05, 0x02]);
+
+    let source = stream.local_addr().unwrap();
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} 9\r\nCONNECT 127.0.0.1:9 HTTP/1.1\r\n",
+        source.port()
+    );
+    assert!(head.starts_with(&expected));
+    assert_eq!(head.matches("PROXY ").count(), 1);
+}
+
+// Origin that answers a request with one last chunk and closes right away.
+fn spawn_closing_origin(chunk: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let chunk = chunk.clone();
+            thread::spawn(move || {
+                let mut request = [0u8; 4];
+                stream.read_exact(&mut request).unwrap();
+                stream.write_all(&chunk).unwrap();
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+fn delivers_the_final_chunk_before_closing() {
+    let chunk: Vec<u8> = (0..64 * 1024).map(|i| (i % 249) as u8).collect();
+    let origin = spawn_closing_origin(chunk.clone());
+    let proxy = spawn_http_proxy(ESTABLISHED);
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"last").unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, chunk);
+}
+
+#[test]
+fn ends_non_persistent_tunnels_cleanly() {
+    let origin = spawn_closing_origin(b"done".to_vec());
+    let proxy = spawn_http_proxy(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n");
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"last").unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"done");
+
+    let (_stream, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
+
+// HTTP proxy taking its time with every CONNECT, it reports when each one
+// arrived.
+fn spawn_slow_answering_proxy(delay: Duration) -> (SocketAddr, Receiver<Instant>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    head.push(byte[0]);
+                }
+                let _ = tx.send(Instant::now());
+                thread::sleep(delay);
+                let _ = stream.write_all(ESTABLISHED);
+                thread::sleep(Duration::from_secs(1));
+            });
+        }
+    });
+    (addr, rx)
+}
+
+#[test]
+fn queues_connects_beyond_the_pending_limit() {
+    let (proxy, arrivals) = spawn_slow_answering_proxy(Duration::from_millis(300));
+    let server = spawn_server(proxy, |server| server.max_pending_upstream(1));
+
+    let clients: Vec<_> = (0..2)
+        .map(|_| thread::spawn(move || socks5_connect(server, "127.0.0.1:9".parse().unwrap()).1))
+        .collect();
+    let first = arrivals.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = arrivals.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(second - first >= Duration::from_millis(250));
+    for client in clients {
+        assert_eq!(client.join().unwrap()[..2], [0x05, 0x00]);
+    }
+}
+
+// HTTP proxy that accepts connections and never answers.
+fn spawn_hung_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let held: Vec<TcpStream> = listener.incoming().map(Result::unwrap).collect();
+        drop(held);
+    });
+    addr
+}
+
+#[test]
+fn hung_connect_is_reported_as_unreachable() {
+    let proxy = spawn_hung_proxy();
+    let server = spawn_server(proxy, |server| {
+        server.connect_timeout(Duration::from_millis(200))
+    });
+
+    let start = Instant::now();
+    let (_, reply) = socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    assert_eq!(reply[..2], [0x05, 0x04]);
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+// HTTP proxy with a tiny receive buffer that only starts reading the tunnel
+// after a while, so writes towards it come up short. Hands everything sent
+// after the CONNECT to the test.
+fn spawn_slow_proxy() -> (SocketAddr, Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let size: libc::c_int = 4096;
+    // SAFETY: the option value is a plain int passed with its size, set on
+    // the listener so accepted sockets start out with it.
+    unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        stream.write_all(ESTABLISHED).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        let _ = tx.send(received);
+    });
+    (addr, rx)
+}
+
+#[test]
+fn retains_the_tail_of_short_upstream_writes() {
+    let (proxy, received) = spawn_slow_proxy();
+    let server = spawn_proxychain(proxy);
+
+    let (mut stream, reply) = socks5_connect(server, "127.0.0.1:9".parse().unwrap());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    let payload: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let sent = payload.clone();
+    thread::spawn(move || {
+        stream.write_all(&sent).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+    });
+    let received = received.recv_timeout(Duration::from_secs(20)).unwrap();
+    assert_eq!(received.len(), payload.len());
+    assert!(received == payload);
+}
+
+#[test]
+fn survives_a_client_resetting_its_tunnel() {
+    let origin = spawn_echo_origin();
+    let server = spawn_proxychain(spawn_http_proxy(ESTABLISHED));
+    let (mut bystander, _) = socks5_connect(server, origin);
+
+    // Closing with the echo still unread makes the kernel send a RST
+    let (mut reset, _) = socks5_connect(server, origin);
+    reset.write_all(b"unread").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    drop(reset);
+    thread::sleep(Duration::from_millis(100));
+    let (mut next, _) = socks5_connect(server, origin);
+
+    for stream in [&mut bystander, &mut next] {
+        stream.write_all(b"ping").unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+}
+
+#[test]
+fn keeps_accepting_after_failing_to_answer_a_reset_client() {
+    // Takes the CONNECT and never answers it
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream = silent.local_addr().unwrap();
+    thread::spawn(move || {
+        let held: Vec<_> = silent.incoming().collect();
+        drop(held);
+    });
+    let server = spawn_server(upstream, |server| {
+        server.connect_timeout(Duration::from_millis(200))
+    });
+    let origin = spawn_echo_origin();
+
+    // Gone with a RST while the CONNECT is pending, answering it then fails
+    let mut reset = TcpStream::connect(server).unwrap();
+    reset.write_all(&[0x05, 0x01, 0x00]).unwrap();
+    reset.read_exact(&mut [0u8; 2]).unwrap();
+    reset
+        .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+        .unwrap();
+    thread::sleep(Duration::from_millis(50));
+    abort(reset);
+    thread::sleep(Duration::from_millis(400));
+
+    let (_, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x04]);
+}
+
+#[test]
+fn enforces_the_sni_of_the_first_relayed_bytes() {
+    let origin = spawn_echo_origin();
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), |server| {
+        server.enforce_sni(true)
+    });
+
+    // Held back until complete, then passed on as a whole
+    let hello = client_hello(Some(&origin.ip().to_string()));
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[1], 0x00);
+    stream.write_all(&hello[..20]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(&hello[20..]).unwrap();
+    let mut echoed = vec![0u8; hello.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(echoed, hello);
+
+    for first in [
+        client_hello(Some("example.com")),
+        b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+    ] {
+        let (mut stream, reply) = socks5_connect(server, origin);
+        assert_eq!(reply[1], 0x00);
+        stream.write_all(&first).unwrap();
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+        assert!(rest.is_empty());
+    }
+}
+
+// Writes `len` bytes to every connection, then closes it.
+fn spawn_sending_origin(len: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let _ = stream.write_all(&vec![0x5a; len]);
+            });
+        }
+    });
+    addr
+}
+
+fn timed_download<F>(len: usize, setup: F) -> Duration
+where
+    F: FnOnce(&mut Socks5Server) + Send + 'static,
+{
+    let origin = spawn_sending_origin(len);
+    let server = spawn_server(spawn_http_proxy(ESTABLISHED), setup);
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let started = Instant::now();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), len);
+    started.elapsed()
+}
+
+#[test]
+fn paces_relay_to_the_rate_limit() {
+    // 1 MB at 100 KB/s scaled down, the first second's worth is the burst
+    let elapsed = timed_download(300_000, |server| server.rate_limit(100_000));
+    assert!(
+        elapsed >= Duration::from_millis(1500) && elapsed <= Duration::from_millis(3500),
+        "took {:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn zero_rate_limit_relays_unthrottled() {
+    let elapsed = timed_download(300_000, |server| server.rate_limit(0));
+    assert!(elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+}
+
+// Echo origin on [::1], None without IPv6 loopback.
+fn spawn_ipv6_echo_origin() -> Option<u16> {
+    let listener = TcpListener::bind("[::1]:0").ok()?;
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            thread::spawn(move || io::copy(&mut reader, &mut stream));
+        }
+    });
+    Some(port)
+}
+
+#[test]
+fn tunnels_to_ipv6_targets_through_http() {
+    let port = match spawn_ipv6_echo_origin() {
+        Some(port) => port,
+        None => return eprintln!("IPv6 loopback unavailable, skipped"),
+    };
+    let (proxy, heads) = spawn_recording_tunnel();
+    let server = spawn_proxychain(proxy);
+
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x04];
+    request.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with(&format!("CONNECT [::1]:{} HTTP/1.1\r\n", port)));
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn tunnels_socks4a_ipv6_literals_in_brackets() {
+    let port = match spawn_ipv6_echo_origin() {
+        Some(port) => port,
+        None => return eprintln!("IPv6 loopback unavailable, skipped"),
+    };
+    let (proxy, heads) = spawn_recording_tunnel();
+    let server = spawn_proxychain(proxy);
+
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = vec![0x04, 0x01];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&[0, 0, 0, 1, 0x00]);
+    request.extend_from_slice(b"::1\0");
+    stream.write_all(&request).unwrap();
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [0x00, 0x5A]);
+
+    let head = heads.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(head.starts_with(&format!("CONNECT [::1]:{} HTTP/1.1\r\n", port)));
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use common::{socks5_connect, spawn_built, spawn_echo_origin, spawn_recording_tunnel};
+
+fn write_upstream(path: &Path, upstream: SocketAddr) {
+    let contents = format!("{{\"upstreams\": [\"http://{}\"]}}", upstream);
+    fs::write(path, contents).unwrap();
+}
+
+fn hangup() {
+    // SAFETY: sending a signal to our own process has no memory effects.
+    assert_eq!(unsafe { libc::kill(libc::getpid(), libc::SIGHUP) }, 0);
+}
+
+// Connects through the server once and returns whether `upstream` got it.
+fn went_through(server: SocketAddr, origin: SocketAddr, upstream: &Receiver<String>) -> bool {
+    let (_stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[1], 0x00);
+    upstream.recv_timeout(Duration::from_millis(500)).is_ok()
+}
+
+#[test]
+fn reloads_upstreams_on_sighup_for_new_connections() {
+    let path = env::temp_dir().join(format!("proxychain-reload-{}.json", process::id()));
+    let origin = spawn_echo_origin();
+    let (first, first_heads) = spawn_recording_tunnel();
+    let (second, second_heads) = spawn_recording_tunnel();
+    write_upstream(&path, first);
+
+    let config = path.clone();
+    let server = spawn_built(move |builder| builder.config_file(config));
+    assert!(went_through(server, origin, &first_heads));
+    let (mut held, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[1], 0x00);
+    first_heads.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    write_upstream(&path, second);
+    hangup();
+    let mut reloaded = false;
+    for _ in 0..20 {
+        thread::sleep(Duration::from_millis(50));
+        if went_through(server, origin, &second_heads) {
+            reloaded = true;
+            break;
+        }
+    }
+    assert!(reloaded, "new connections still use the old upstream");
+
+    // Opened before the reload, still relayed through the first upstream.
+    held.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    held.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    fs::write(&path, "{\"upstreams\": [").unwrap();
+    hangup();
+    thread::sleep(Duration::from_millis(200));
+    assert!(went_through(server, origin, &second_heads));
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+
+use proxychain::acl::DirectRules;
+use proxychain::datatype::Target;
+use proxychain::proxy::Proxy;
+use proxychain::selector::{FirstAvailable, RoundRobin, RuleBased, UpstreamSelector};
+
+fn proxies() -> Vec<Proxy> {
+    vec![
+        Proxy::parse("http://127.0.0.1:8001"),
+        Proxy::parse("http://127.0.0.1:8002"),
+    ]
+}
+
+fn target(domain: &str) -> Target {
+    let mut target = Target::new();
+    target.domain = String::from(domain);
+    target
+}
+
+fn peer() -> SocketAddr {
+    "127.0.0.1:40000".parse().unwrap()
+}
+
+#[test]
+fn round_robin_cycles_through_proxies() {
+    let selector = RoundRobin::new(proxies());
+    let ports: Vec<u16> = (0..4)
+        .map(|_| selector.select(&target("a.com"), peer()).unwrap().port)
+        .collect();
+    assert_eq!(ports, [8001, 8002, 8001, 8002]);
+    assert!(RoundRobin::new(Vec::new())
+        .select(&target("a.com"), peer())
+        .is_none());
+}
+
+#[test]
+fn rule_based_falls_back_when_nothing_matches() {
+    let mut rules = DirectRules::default();
+    rules.add("internal.example");
+    let mut selector = RuleBased::new(Box::new(FirstAvailable::new(proxies())));
+    selector.route(rules, Proxy::parse("http://127.0.0.1:8003"));
+
+    let routed = selector.select(&target("git.internal.example"), peer());
+    assert_eq!(routed.unwrap().port, 8003);
+    let fallback = selector.select(&target("example.com"), peer());
+    assert_eq!(fallback.unwrap().port, 8001);
+    assert_eq!(selector.proxies().len(), 3);
+}
@@ -0,0 +1,232 @@
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use proxychain::acl::{Cidr, DestinationRules};
+use proxychain::config::Config;
+use proxychain::proxy::Proxy;
+
+use common::{socks5_connect, spawn_built, spawn_echo_origin};
+
+const SUCCEEDED: [u8; 10] = [0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+
+// SOCKS5 proxy insisting on username/password, it tunnels IPv4 targets
+// once given `user:secret`.
+fn spawn_authenticating_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            thread::spawn(move || serve(stream.unwrap()));
+        }
+    });
+    addr
+}
+
+fn serve(mut client: TcpStream) {
+    let mut head = [0u8; 2];
+    client.read_exact(&mut head).unwrap();
+    let mut methods = vec![0u8; head[1] as usize];
+    client.read_exact(&mut methods).unwrap();
+    if !methods.contains(&0x02) {
+        client.write_all(&[0x05, 0xFF]).unwrap();
+        return;
+    }
+    client.write_all(&[0x05, 0x02]).unwrap();
+
+    let mut auth = [0u8; 2];
+    client.read_exact(&mut auth).unwrap();
+    let mut username = vec![0u8; auth[1] as usize];
+    client.read_exact(&mut username).unwrap();
+    let mut len = [0u8; 1];
+    client.read_exact(&mut len).unwrap();
+    let mut password = vec![0u8; len[0] as usize];
+    client.read_exact(&mut password).unwrap();
+    if auth[0] != 0x01 || username != b"user" || password != b"secret" {
+        client.write_all(&[0x01, 0x01]).unwrap();
+        return;
+    }
+    client.write_all(&[0x01, 0x00]).unwrap();
+
+    let mut request = [0u8; 10];
+    client.read_exact(&mut request).unwrap();
+    assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x01]);
+    let target = SocketAddr::from((
+        [request[4], request[5], request[6], request[7]],
+        u16::from_be_bytes([request[8], request[9]]),
+    ));
+    let mut origin = TcpStream::connect(target).unwrap();
+    client.write_all(&SUCCEEDED).unwrap();
+
+    let mut client_reader = client.try_clone().unwrap();
+    let mut origin_writer = origin.try_clone().unwrap();
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut origin_writer);
+        let _ = origin_writer.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut origin, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+}
+
+fn spawn_chained(url: String) -> SocketAddr {
+    spawn_built(move |builder| builder.upstream(Proxy::parse(&url)))
+}
+
+#[test]
+fn authenticates_with_the_upstream_socks5_proxy() {
+    let origin = spawn_echo_origin();
+    let proxy = spawn_authenticating_proxy();
+    let server = spawn_chained(format!("socks5://user:secret@{}", proxy));
+
+    let (mut stream, reply) = socks5_connect(server, origin);
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    stream.write_all(b"ping").unwrap();
+    let mut echoed = [0u8; 4];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[test]
+fn fails_the_request_without_credentials() {
+    let proxy = spawn_authenticating_proxy();
+    let server = spawn_chained(format!("socks5://{}", proxy));
+
+    let (_, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x01]);
+}
+
+#[test]
+fn fails_the_request_with_rejected_credentials() {
+    let proxy = spawn_authenticating_proxy();
+    let server = spawn_chained(format!("socks5://user:wrong@{}", proxy));
+
+    let (_, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x01]);
+}
+
+// SOCKS5 proxy without authentication that hands every CONNECT request it
+// got to the test and accepts it.
+fn spawn_recording_proxy() -> (SocketAddr, Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[0x05, 0x00]).unwrap();
+            let mut request = vec![0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            let rest = match request[3] {
+                0x01 => 5,
+                0x03 => request[4] as usize + 2,
+                _ => 17,
+            };
+            let mut tail = vec![0u8; rest];
+            stream.read_exact(&mut tail).unwrap();
+            request.extend_from_slice(&tail);
+            let _ = tx.send(request);
+            stream.write_all(&SUCCEEDED).unwrap();
+        }
+    });
+    (addr, rx)
+}
+
+// Asks for `domain` through proxychain, returning the request and whether
+// it got a tunnel.
+fn request_domain(server: SocketAddr, domain: &[u8]) -> (Vec<u8>, bool) {
+    let mut stream = TcpStream::connect(server).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&443u16.to_be_bytes());
+    stream.write_all(&request).unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    (request, reply[..2] == [0x05, 0x00])
+}
+
+#[test]
+fn leaves_domains_to_socks5h_upstreams() {
+    let (proxy, requests) = spawn_recording_proxy();
+    let server = spawn_chained(format!("socks5h://{}", proxy));
+
+    // The name can't be resolved here, only the upstream could
+    let (request, tunneled) = request_domain(server, b"only-upstream-knows.invalid");
+    assert!(tunneled);
+
+    let sent = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(sent, request);
+}
+
+#[test]
+fn sends_the_domain_to_socks5h_upstreams_even_once_resolved() {
+    let (proxy, requests) = spawn_recording_proxy();
+    let server = spawn_built(move |builder| {
+        // Blocked CIDRs need the address, so the domain is resolved here
+        let mut rules = DestinationRules::default();
+        rules.block_cidr(Cidr::parse("192.0.2.0/24").unwrap());
+        builder.config(Config {
+            subproxy: vec![Proxy::parse(&format!("socks5h://{}", proxy))],
+            rules,
+            ..Config::default()
+        })
+    });
+
+    let (request, tunneled) = request_domain(server, b"localhost");
+    assert!(tunneled);
+
+    // The final hop sees the name for SNI and virtual hosts, not 127.0.0.1
+    let sent = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(sent, request);
+    assert_eq!(&sent[5..14], b"localhost");
+}
+
+// SOCKS5 proxy without authentication that only starts listening after
+// `delay`, accepting every CONNECT request.
+fn spawn_late_proxy(delay: Duration) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    thread::spawn(move || {
+        thread::sleep(delay);
+        let listener = TcpListener::bind(addr).unwrap();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[0x05, 0x00]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&SUCCEEDED).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn retries_an_unreachable_socks5_upstream() {
+    let proxy = spawn_late_proxy(Duration::from_millis(200));
+    let server = spawn_built(move |builder| {
+        builder.config(Config {
+            subproxy: vec![Proxy::parse(&format!("socks5://{}", proxy))],
+            upstream_retries: 3,
+            upstream_retry_delay: Duration::from_millis(100),
+            ..Config::default()
+        })
+    });
+
+    let (_, reply) = socks5_connect(server, spawn_echo_origin());
+    assert_eq!(reply[..2], [0x05, 0x00]);
+}
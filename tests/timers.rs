@@ -0,0 +1,38 @@
+use std::time::{Duration, Instant};
+
+use mio::Token;
+
+use proxychain::timers::Timers;
+
+#[test]
+fn expires_deadlines_in_order() {
+    let now = Instant::now();
+    let mut timers = Timers::new();
+    timers.schedule(Token(2), now + Duration::from_secs(2));
+    timers.schedule(Token(1), now + Duration::from_secs(1));
+    timers.schedule(Token(3), now + Duration::from_secs(3));
+
+    assert_eq!(timers.next_deadline(), Some(now + Duration::from_secs(1)));
+    assert_eq!(
+        timers.expired(now + Duration::from_secs(2)),
+        vec![Token(1), Token(2)]
+    );
+    assert_eq!(timers.len(), 1);
+    assert_eq!(timers.next_deadline(), Some(now + Duration::from_secs(3)));
+}
+
+#[test]
+fn rescheduling_replaces_the_deadline() {
+    let now = Instant::now();
+    let mut timers = Timers::new();
+    timers.schedule(Token(1), now + Duration::from_secs(1));
+    timers.schedule(Token(1), now + Duration::from_secs(5));
+    timers.schedule(Token(2), now + Duration::from_secs(2));
+    timers.cancel(Token(2));
+
+    assert_eq!(timers.next_deadline(), Some(now + Duration::from_secs(5)));
+    assert!(timers.expired(now + Duration::from_secs(4)).is_empty());
+    assert_eq!(timers.expired(now + Duration::from_secs(5)), vec![Token(1)]);
+    assert!(timers.is_empty());
+    assert_eq!(timers.next_deadline(), None);
+}
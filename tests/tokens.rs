@@ -0,0 +1,15 @@
+use mio::Token;
+use proxychain::socks::tokens::TokenPool;
+
+#[test]
+fn reuses_released_tokens_after_recycle() {
+    let mut tokens = TokenPool::new(Token(2));
+    assert_eq!(tokens.take(), Token(2));
+    assert_eq!(tokens.take(), Token(3));
+
+    tokens.release(Token(2));
+    assert_eq!(tokens.take(), Token(4));
+    tokens.recycle();
+    assert_eq!(tokens.take(), Token(2));
+    assert_eq!(tokens.take(), Token(5));
+}